@@ -0,0 +1,56 @@
+//! Traversal time by `--order`. The tree is created under the temporary directory,
+//! or set `PARA_DT_BENCH_DIR` to traverse an existing directory.
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use libpara_dt::builder::TraverserBuilder;
+use libpara_dt::options::Order;
+use libpara_dt::traverse::traverse_with_visitor;
+use libpara_dt::visitor::NullVisitor;
+use std::path::PathBuf;
+
+fn bench_tree() -> PathBuf {
+    if let Some(dir) = std::env::var_os("PARA_DT_BENCH_DIR") {
+        return dir.into();
+    }
+
+    let root = std::env::temp_dir().join("libpara-dt-bench-order");
+    if !root.exists() {
+        for i in 0..100 {
+            for j in 0..10 {
+                let d = root.join(format!("{}/{}", i, j));
+                std::fs::create_dir_all(&d).unwrap();
+                for k in 0..50 {
+                    std::fs::write(d.join(k.to_string()), b"").unwrap();
+                }
+            }
+        }
+    }
+    root
+}
+
+fn order(c: &mut Criterion) {
+    let root = bench_tree();
+    let mut g = c.benchmark_group("order");
+    g.sample_size(10);
+
+    for (name, order) in [
+        ("alphabetical", Order::Alphabetical),
+        ("readdir", Order::Readdir),
+        ("unordered", Order::Unordered),
+    ] {
+        g.bench_function(name, |b| {
+            b.iter(|| {
+                let mut t = TraverserBuilder::new(&root)
+                    .order(order.clone())
+                    .num_threads(8)
+                    .build()
+                    .unwrap();
+                traverse_with_visitor(&mut t, Box::new(NullVisitor)).unwrap();
+            })
+        });
+    }
+    g.finish();
+}
+
+criterion_group!(benches, order);
+criterion_main!(benches);
@@ -0,0 +1,194 @@
+use crate::error;
+use crate::options::{Method, Options, Order};
+use crate::quote::QuotingStyle;
+use crate::record::Format;
+use crate::sort::SortKey;
+use crate::traverse::Traverser;
+use std::path::PathBuf;
+
+/// Build a `Traverser` without going through the command line parser.
+///
+/// ```no_run
+/// use libpara_dt::builder::TraverserBuilder;
+/// use libpara_dt::options::Order;
+///
+/// let mut t = TraverserBuilder::new("/usr")
+///     .order(Order::Readdir)
+///     .num_threads(8)
+///     .build()
+///     .unwrap();
+/// libpara_dt::traverse::traverse(&mut t).unwrap();
+/// ```
+pub struct TraverserBuilder {
+    opts: Options,
+}
+
+impl TraverserBuilder {
+    /// start from the command line defaults with `Method::List`
+    pub fn new<P: Into<PathBuf>>(src_path: P) -> TraverserBuilder {
+        TraverserBuilder {
+            opts: Options::new(vec![src_path.into()], Method::List),
+        }
+    }
+
+    /// add one more root directory. roots are output in the order added
+    pub fn add_src_path<P: Into<PathBuf>>(mut self, src_path: P) -> Self {
+        self.opts.src_paths.push(src_path.into());
+        self
+    }
+
+    pub fn method(mut self, method: Method) -> Self {
+        self.opts.method = method;
+        self
+    }
+
+    pub fn order(mut self, order: Order) -> Self {
+        self.opts.order = order;
+        self
+    }
+
+    pub fn sort(mut self, key: SortKey) -> Self {
+        self.opts.sort = Some(key);
+        self
+    }
+
+    pub fn dirs_first(mut self, b: bool) -> Self {
+        self.opts.dirs_first = b;
+        self
+    }
+
+    pub fn post_order(mut self, b: bool) -> Self {
+        self.opts.post_order = b;
+        self
+    }
+
+    pub fn reverse(mut self, b: bool) -> Self {
+        self.opts.reverse = b;
+        self
+    }
+
+    pub fn num_threads(mut self, n: usize) -> Self {
+        self.opts.num_threads = n;
+        self
+    }
+
+    pub fn readdir_dirent_buffer_size(mut self, n: usize) -> Self {
+        self.opts.readdir_dirent_buffer_size = n;
+        self
+    }
+
+    pub fn max_ioreq_depth(mut self, n: usize) -> Self {
+        self.opts.max_ioreq_depth = n;
+        self
+    }
+
+    pub fn follow_symlink(mut self, b: bool) -> Self {
+        self.opts.follow_symlink = b;
+        self
+    }
+
+    pub fn ignore_eaccess(mut self, b: bool) -> Self {
+        self.opts.ignore_eaccess = b;
+        self
+    }
+
+    /// find(1) style filter expression. see `filter`
+    pub fn filter(mut self, expr: &str) -> Self {
+        self.opts.filter = Some(expr.to_owned());
+        self
+    }
+
+    /// skip entries matching the glob, and do not descend into them
+    pub fn exclude(mut self, glob: &str) -> Self {
+        self.opts.exclude.push(glob.to_owned());
+        self
+    }
+
+    /// do not descend into directories matching the find(1) style expression
+    pub fn prune(mut self, expr: &str) -> Self {
+        self.opts.prune = Some(expr.to_owned());
+        self
+    }
+
+    pub fn ignore_files(mut self, b: bool) -> Self {
+        self.opts.ignore_files = b;
+        self
+    }
+
+    pub fn max_depth(mut self, depth: usize) -> Self {
+        self.opts.max_depth = Some(depth);
+        self
+    }
+
+    pub fn min_depth(mut self, depth: usize) -> Self {
+        self.opts.min_depth = Some(depth);
+        self
+    }
+
+    pub fn one_file_system(mut self, b: bool) -> Self {
+        self.opts.one_file_system = b;
+        self
+    }
+
+    pub fn include_fstype(mut self, fstype: &str) -> Self {
+        self.opts.include_fstype.push(fstype.to_owned());
+        self
+    }
+
+    pub fn exclude_fstype(mut self, fstype: &str) -> Self {
+        self.opts.exclude_fstype.push(fstype.to_owned());
+        self
+    }
+
+    pub fn output<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.opts.output = Some(path.into());
+        self
+    }
+
+    pub fn format(mut self, format: Format) -> Self {
+        self.opts.format = format;
+        self
+    }
+
+    pub fn print0(mut self, b: bool) -> Self {
+        self.opts.print0 = b;
+        self
+    }
+
+    pub fn quoting_style(mut self, style: QuotingStyle) -> Self {
+        self.opts.quoting_style = style;
+        self
+    }
+
+    pub fn relative(mut self, b: bool) -> Self {
+        self.opts.relative = b;
+        self
+    }
+
+    pub fn prefix(mut self, prefix: &str) -> Self {
+        self.opts.prefix = Some(prefix.into());
+        self
+    }
+
+    pub fn build(self) -> Result<Traverser, error::E> {
+        self.opts.validate()?;
+        Ok(Traverser { opt: self.opts })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validate() {
+        assert!(TraverserBuilder::new(".").build().is_ok());
+        assert!(matches!(
+            TraverserBuilder::new(".").num_threads(0).build(),
+            Err(error::E::InvalidOptionError {
+                name: "num_threads",
+                ..
+            })
+        ));
+    }
+}
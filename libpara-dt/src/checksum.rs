@@ -0,0 +1,370 @@
+//! `Method::Checksum`. prints the hash of each regular file, in the format of `sha256sum`.
+//!
+//! Files are read and hashed by the traverse threads in parallel, and printed in the traversal order.
+//! With `--check`, the files listed in a manifest are hashed in parallel instead of a traversal.
+//! Files are read through io_uring of stat-async in those threads, up to `--max-ioreq-depth` blocks
+//! ahead of the hashing, and with blocking `read(2)` where io_uring is not available.
+
+use crate::error;
+use crate::hash::{self, ContentHashes, HashAlgorithm};
+use crate::options::Options;
+use crate::pathstr::PathFormat;
+use crate::record::{self, Format, Output, Record, CHECKSUM_COLUMNS};
+use crate::visitor::{Action, Entry, Visitor};
+use std::ffi::OsStr;
+use std::io::{self, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// the path of a manifest line. `\` and newline are escaped like `sha256sum`,
+/// and the line starts with `\` then
+fn escape(path: &[u8], out: &mut Vec<u8>) -> bool {
+    if !path.iter().any(|c| matches!(c, b'\\' | b'\n' | b'\r')) {
+        out.extend_from_slice(path);
+        return false;
+    }
+    for c in path {
+        match c {
+            b'\\' => out.extend_from_slice(b"\\\\"),
+            b'\n' => out.extend_from_slice(b"\\n"),
+            b'\r' => out.extend_from_slice(b"\\r"),
+            c => out.push(*c),
+        }
+    }
+    true
+}
+
+fn unescape(path: &[u8]) -> Option<Vec<u8>> {
+    let mut ret = Vec::with_capacity(path.len());
+    let mut it = path.iter();
+    while let Some(c) = it.next() {
+        if *c != b'\\' {
+            ret.push(*c);
+            continue;
+        }
+        ret.push(match it.next()? {
+            b'\\' => b'\\',
+            b'n' => b'\n',
+            b'r' => b'\r',
+            _ => return None,
+        });
+    }
+    Some(ret)
+}
+
+/// `HASH  PATH` of a manifest line
+pub fn manifest_line(hash: &[u8], path: &[u8], out: &mut Vec<u8>) {
+    let mut p = Vec::with_capacity(path.len());
+    if escape(path, &mut p) {
+        out.push(b'\\');
+    }
+    out.extend_from_slice(record::hex(hash).as_bytes());
+    out.extend_from_slice(b"  ");
+    out.extend_from_slice(&p);
+    out.push(b'\n');
+}
+
+pub struct Checksummer {
+    out: Output,
+    path_format: PathFormat,
+    hashes: Arc<ContentHashes>,
+    errors: usize,
+    path: Vec<u8>,
+    buf: Vec<u8>,
+}
+
+impl Checksummer {
+    pub fn new(out: Box<dyn Write + Send>, algorithm: HashAlgorithm) -> Checksummer {
+        Checksummer {
+            out: Output::Text(out),
+            path_format: PathFormat::default(),
+            hashes: Arc::new(ContentHashes::new(algorithm, false)),
+            errors: 0,
+            path: Vec::new(),
+            buf: Vec::new(),
+        }
+    }
+
+    pub fn format(mut self, format: Format) -> Self {
+        self.out = self.out.with_format(format, CHECKSUM_COLUMNS);
+        self
+    }
+
+    pub fn path_format(mut self, f: PathFormat) -> Self {
+        self.path_format = f;
+        self
+    }
+
+    /// blocks of a file read ahead of the hashing
+    pub fn ioreq_depth(mut self, n: usize) -> Self {
+        Arc::get_mut(&mut self.hashes).unwrap().ioreq_depth = n;
+        self
+    }
+}
+
+impl Visitor for Checksummer {
+    fn action(&self) -> Option<Arc<dyn Action>> {
+        Some(self.hashes.clone())
+    }
+
+    /// regular files hashed by the action. directories are skipped
+    fn visit(&mut self, entry: &Entry) -> Result<(), error::E> {
+        let r = match self.hashes.take(entry) {
+            Some(r) => r,
+            None => return Ok(()),
+        };
+        let hash = match r {
+            Ok(h) => h,
+            Err(eno) => {
+                self.errors += 1;
+                return self.out.error(&error::E::ReadFileError {
+                    path: entry.path.clone(),
+                    eno,
+                });
+            }
+        };
+
+        self.path.clear();
+        self.path_format.push(entry, &mut self.path);
+        match &mut self.out {
+            Output::Text(out) => {
+                self.buf.clear();
+                manifest_line(&hash, &self.path, &mut self.buf);
+                error::maybe_generic_io_error(out.write_all(&self.buf))
+            }
+            Output::Records(w) => w.write(&Record::checksum(
+                &self.path,
+                self.hashes.algorithm.name(),
+                &hash,
+            )),
+        }
+    }
+
+    fn error(&mut self, e: &error::E) -> Result<(), error::E> {
+        self.errors += 1;
+        self.out.error(e)
+    }
+
+    fn finish(&mut self) -> Result<(), error::E> {
+        self.out.flush()?;
+        if self.errors > 0 {
+            return Err(error::E::Incomplete {
+                errors: self.errors,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// one line of a manifest
+struct Listed {
+    hash: Vec<u8>,
+    path: Vec<u8>,
+}
+
+/// `None` for a line not in the format
+fn parse_line(line: &[u8], algorithm: HashAlgorithm) -> Option<Listed> {
+    let (escaped, line) = match line.strip_prefix(b"\\") {
+        Some(l) => (true, l),
+        None => (false, line),
+    };
+    let hex_len = algorithm.hash_len() * 2;
+    let (h, rest) = (line.get(..hex_len)?, line.get(hex_len..)?);
+    // `*` is the binary mode of sha256sum, the same on Linux
+    let path = rest
+        .strip_prefix(b"  ")
+        .or_else(|| rest.strip_prefix(b" *"))?;
+    let hash = (0..h.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(std::str::from_utf8(&h[i..i + 2]).ok()?, 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    let path = if escaped {
+        unescape(path)?
+    } else {
+        path.to_vec()
+    };
+    if path.is_empty() {
+        return None;
+    }
+    Some(Listed { hash, path })
+}
+
+#[derive(Debug, Eq, PartialEq)]
+enum Status {
+    Ok,
+    Failed,
+    Unreadable,
+}
+
+/// verify the hashes in `manifest`, like `sha256sum --check`, and print the results to `out`.
+/// relative paths are from the root directory
+pub fn check(
+    opts: &Options,
+    manifest: &Path,
+    algorithm: HashAlgorithm,
+    mut out: Box<dyn Write + Send>,
+) -> Result<(), error::E> {
+    let roots = opts.root_paths()?;
+    let base = match roots.as_slice() {
+        [r] => r.clone(),
+        _ => {
+            return Err(error::invalid_option(
+                "check",
+                "needs one root directory to resolve the paths",
+            ))
+        }
+    };
+    let text = if manifest.as_os_str() == "-" {
+        let mut buf = Vec::new();
+        error::maybe_generic_io_error(io::Read::read_to_end(&mut io::stdin().lock(), &mut buf))?;
+        buf
+    } else {
+        error::maybe_generic_io_error(std::fs::read(manifest))?
+    };
+
+    let mut malformed = 0;
+    let mut listed = Vec::new();
+    for line in text.split(|c| *c == b'\n') {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if line.is_empty() {
+            continue;
+        }
+        match parse_line(line, algorithm) {
+            Some(l) => listed.push(l),
+            None => malformed += 1,
+        }
+    }
+
+    // hashed in parallel, and printed in the order of the manifest
+    let next = AtomicUsize::new(0);
+    let status: Vec<Mutex<Option<Status>>> = listed.iter().map(|_| Mutex::new(None)).collect();
+    std::thread::scope(|s| {
+        for _ in 0..opts.num_threads {
+            s.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                let l = match listed.get(i) {
+                    Some(l) => l,
+                    None => break,
+                };
+                let path = base.join(OsStr::from_bytes(&l.path));
+                let st = match std::fs::File::open(&path)
+                    .and_then(|mut f| hash::hash_file(algorithm, &mut f, opts.max_ioreq_depth))
+                {
+                    Ok(h) if h == l.hash => Status::Ok,
+                    Ok(_) => Status::Failed,
+                    Err(e) => {
+                        eprintln!("{:?}: {}", path, e);
+                        Status::Unreadable
+                    }
+                };
+                *status[i].lock().unwrap() = Some(st);
+            });
+        }
+    });
+
+    let mut buf = Vec::new();
+    let (mut failed, mut unreadable) = (0, 0);
+    for (l, st) in listed.iter().zip(status) {
+        let st = st.into_inner().unwrap();
+        buf.clear();
+        if escape(&l.path, &mut buf) {
+            buf.insert(0, b'\\');
+        }
+        buf.extend_from_slice(match st {
+            Some(Status::Ok) => b": OK\n".as_slice(),
+            Some(Status::Failed) => {
+                failed += 1;
+                b": FAILED\n"
+            }
+            _ => {
+                unreadable += 1;
+                b": FAILED open or read\n"
+            }
+        });
+        error::maybe_generic_io_error(out.write_all(&buf))?;
+    }
+    error::maybe_generic_io_error(out.flush())?;
+
+    if malformed > 0 {
+        eprintln!("WARNING: {} lines are improperly formatted", malformed);
+    }
+    if unreadable > 0 {
+        eprintln!("WARNING: {} listed files could not be read", unreadable);
+    }
+    if failed > 0 {
+        eprintln!("WARNING: {} computed checksums did NOT match", failed);
+    }
+    let errors = malformed + unreadable + failed;
+    if errors > 0 {
+        return Err(error::E::Incomplete { errors });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::options::{Method, Order};
+    use crate::printer::{test_run, SharedBuf};
+
+    #[test]
+    fn manifest() {
+        let root = crate::options::test_tree("checksum", &["a/b", "c", "d\ne", "f/"]);
+        let mut opts = crate::options::test_option(root.to_str().unwrap());
+        opts.order = Order::Alphabetical;
+        opts.num_threads = 4;
+        opts.relative = true;
+
+        let path_format = PathFormat::new(&opts);
+        let (s, r) = test_run(opts.clone(), |out| {
+            Checksummer::new(out, HashAlgorithm::Sha256).path_format(path_format)
+        });
+        r.unwrap();
+        let lines: Vec<_> = s.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].ends_with("  a/b"));
+        assert!(lines[1].ends_with("  c"));
+        assert!(lines[2].starts_with('\\') && lines[2].ends_with("  d\\ne"));
+        let h = hash::hash_reader(HashAlgorithm::Sha256, &mut &b"c"[..]).unwrap();
+        assert_eq!(&lines[1][..64], record::hex(&h));
+
+        let dir = crate::options::test_tree("checksum_manifest", &[]);
+        let manifest = dir.join("sha256");
+        std::fs::write(&manifest, &s).unwrap();
+        opts.method = Method::Checksum {
+            algorithm: HashAlgorithm::Sha256,
+            check: Some(manifest.clone()),
+        };
+        opts.validate().unwrap();
+        let mut o = opts.clone();
+        o.files_from = Some(manifest.clone());
+        assert!(matches!(
+            o.validate(),
+            Err(error::E::InvalidOptionError { .. })
+        ));
+        let buf = SharedBuf::default();
+        check(
+            &opts,
+            &manifest,
+            HashAlgorithm::Sha256,
+            Box::new(buf.clone()),
+        )
+        .unwrap();
+        assert_eq!(buf.string(), "a/b: OK\nc: OK\n\\d\\ne: OK\n");
+
+        std::fs::write(root.join("c"), "x").unwrap();
+        let buf = SharedBuf::default();
+        assert!(matches!(
+            check(
+                &opts,
+                &manifest,
+                HashAlgorithm::Sha256,
+                Box::new(buf.clone())
+            ),
+            Err(error::E::Incomplete { errors: 1 })
+        ));
+        assert_eq!(buf.string(), "a/b: OK\nc: FAILED\n\\d\\ne: OK\n");
+    }
+}
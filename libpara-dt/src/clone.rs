@@ -0,0 +1,1034 @@
+//! `Method::CloneDirectory`. copies the root directory to `dst`, like `cp -r` or `rsync -r`.
+//!
+//! Files, symlinks and special files are copied by the traverse threads as they are read, and
+//! directories are made by the visitor in pre-order. The parent of a file may be read before it is
+//! visited, so the copy makes missing parents too. Each is written to a temporary name in its
+//! directory and renamed over the old one when complete, like `rsync`.
+//!
+//! Metadata in `Preserve` is applied to an entry after its contents, and to a directory when the visitor
+//! leaves it. So the entries written in a directory do not change its times, and a read-only mode does not
+//! block them. A read-only directory in the destination is given write permission while entries are
+//! written in it, and its mode is restored when the visitor leaves it.
+
+use crate::dedup::{is_temp_path, read_full, temp_path};
+use crate::dir::Dir;
+use crate::error;
+use crate::hash;
+use crate::quote::{self, QuotingStyle};
+use crate::record::{Format, Output, Record, CLONE_COLUMNS};
+use crate::visitor::{Action, Entry, FileType, Visitor};
+use nix::errno::Errno;
+use nix::sys::stat::{FileStat, Mode, SFlag};
+use nix::sys::time::TimeSpec;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::ffi::CStr;
+use std::fs::File;
+use std::io::{self, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, Eq, PartialEq)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+pub enum Preserve {
+    /// permission bits, including setuid, setgid and sticky
+    Mode,
+    /// owner and group. only as root, and skipped otherwise
+    Ownership,
+    /// access and modification times in nanoseconds
+    Timestamps,
+    /// extended attributes, including `security.*` and POSIX ACLs in `system.posix_acl_*`
+    Xattr,
+    /// all of the above
+    All,
+}
+
+/// metadata applied to the copies
+#[derive(Clone, Copy, Debug, Default)]
+struct Preserved {
+    mode: bool,
+    ownership: bool,
+    timestamps: bool,
+    xattr: bool,
+}
+
+impl Preserved {
+    fn new(p: &[Preserve]) -> Preserved {
+        let has = |x| p.contains(&x) || p.contains(&Preserve::All);
+        Preserved {
+            mode: has(Preserve::Mode),
+            ownership: has(Preserve::Ownership) && nix::unistd::geteuid().is_root(),
+            timestamps: has(Preserve::Timestamps),
+            xattr: has(Preserve::Xattr),
+        }
+    }
+
+    /// apply the metadata of `src` to `dst`, without following symlinks
+    fn apply(&self, src: &Path, dst: &Path, t: FileType, st: &FileStat) -> io::Result<()> {
+        if self.ownership {
+            nix::unistd::fchownat(
+                None,
+                dst,
+                Some(nix::unistd::Uid::from_raw(st.st_uid)),
+                Some(nix::unistd::Gid::from_raw(st.st_gid)),
+                nix::unistd::FchownatFlags::NoFollowSymlink,
+            )?;
+        }
+        // before chmod, since `user.*` attributes are set only with write permission
+        if self.xattr {
+            match crate::xattr::copy(src, dst) {
+                // read-only from an earlier copy. the mode is set again below
+                Err(e)
+                    if e.raw_os_error() == Some(libc::EACCES)
+                        && self.mode
+                        && t != FileType::Symlink =>
+                {
+                    let mode = std::fs::symlink_metadata(dst)?.mode() & 0o7777;
+                    std::fs::set_permissions(dst, std::fs::Permissions::from_mode(mode | 0o200))?;
+                    crate::xattr::copy(src, dst)?;
+                }
+                r => r?,
+            }
+        }
+        // after chown, which clears setuid. symlinks have no mode of their own on Linux
+        if self.mode && t != FileType::Symlink {
+            nix::sys::stat::fchmodat(
+                None,
+                dst,
+                Mode::from_bits_truncate(st.st_mode & 0o7777),
+                nix::sys::stat::FchmodatFlags::FollowSymlink,
+            )?;
+        }
+        if self.timestamps {
+            let time = |sec, nsec| {
+                TimeSpec::from(libc::timespec {
+                    tv_sec: sec,
+                    tv_nsec: nsec,
+                })
+            };
+            nix::sys::stat::utimensat(
+                None,
+                dst,
+                &time(st.st_atime, st.st_atime_nsec),
+                &time(st.st_mtime, st.st_mtime_nsec),
+                nix::sys::stat::UtimensatFlags::NoFollowSymlink,
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// alignment of the buffers for `O_DIRECT`
+const ALIGN: usize = 4096;
+
+/// zeroed buffer aligned to `ALIGN`
+struct AlignedBuf {
+    ptr: std::ptr::NonNull<u8>,
+    len: usize,
+}
+
+unsafe impl Send for AlignedBuf {}
+
+impl AlignedBuf {
+    fn new(len: usize) -> AlignedBuf {
+        let layout = std::alloc::Layout::from_size_align(len, ALIGN).unwrap();
+        let ptr = unsafe { std::alloc::alloc_zeroed(layout) };
+        AlignedBuf {
+            ptr: std::ptr::NonNull::new(ptr)
+                .unwrap_or_else(|| std::alloc::handle_alloc_error(layout)),
+            len,
+        }
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl Drop for AlignedBuf {
+    fn drop(&mut self) {
+        let layout = std::alloc::Layout::from_size_align(self.len, ALIGN).unwrap();
+        unsafe { std::alloc::dealloc(self.ptr.as_ptr(), layout) };
+    }
+}
+
+/// `YXcstpoguax` of `rsync --itemize-changes` for an entry replacing `old`. the mode and owner
+/// are compared only when they are preserved
+fn itemize(
+    t: FileType,
+    old: Option<&std::fs::Metadata>,
+    st: &FileStat,
+    preserve: &Preserved,
+) -> String {
+    let (y, x) = match t {
+        FileType::File => ('>', 'f'),
+        FileType::Directory => ('c', 'd'),
+        FileType::Symlink => ('c', 'L'),
+        FileType::CharacterDevice | FileType::BlockDevice => ('c', 'D'),
+        FileType::Fifo | FileType::Socket => ('c', 'S'),
+    };
+    match old {
+        None => format!("{}{}+++++++++", y, x),
+        Some(m) => {
+            let flag = |differs: bool, c: char| if differs { c } else { '.' };
+            let c = flag(x == 'L', 'c');
+            let s = flag(m.size() != st.st_size as u64, 's');
+            let t = flag(
+                (m.mtime(), m.mtime_nsec()) != (st.st_mtime, st.st_mtime_nsec),
+                't',
+            );
+            let p = flag(
+                preserve.mode && x != 'L' && m.mode() & 0o7777 != st.st_mode & 0o7777,
+                'p',
+            );
+            let o = flag(preserve.ownership && m.uid() != st.st_uid, 'o');
+            let g = flag(preserve.ownership && m.gid() != st.st_gid, 'g');
+            format!("{}{}{}{}{}{}{}{}...", y, x, c, s, t, p, o, g)
+        }
+    }
+}
+
+fn same_type(t: FileType, m: &std::fs::Metadata) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+    let ft = m.file_type();
+    match t {
+        FileType::File => ft.is_file(),
+        FileType::Directory => ft.is_dir(),
+        FileType::Symlink => ft.is_symlink(),
+        FileType::Fifo => ft.is_fifo(),
+        FileType::Socket => ft.is_socket(),
+        FileType::CharacterDevice => ft.is_char_device(),
+        FileType::BlockDevice => ft.is_block_device(),
+    }
+}
+
+/// the mode of a directory before `Copier::make_writable`
+fn restore_mode(path: &Path, mode: u32) -> io::Result<()> {
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+}
+
+fn remove_any(path: &Path, m: &std::fs::Metadata) -> io::Result<()> {
+    if m.is_dir() {
+        std::fs::remove_dir_all(path)
+    } else {
+        std::fs::remove_file(path)
+    }
+}
+
+/// a change made by `Copier`. `flags` is of `itemize`
+struct Item {
+    flags: String,
+    /// target of a symlink
+    link: Option<PathBuf>,
+}
+
+/// copies entries in the traverse threads
+struct Copier {
+    dst: PathBuf,
+    use_o_direct: bool,
+    use_fallocate: bool,
+    buffer_size: usize,
+    incremental: bool,
+    preserve: Preserved,
+    umask: u32,
+    buffers: Mutex<Vec<AlignedBuf>>,
+    /// directories made by `make_dir`, until the visitor takes them
+    created: Mutex<HashSet<PathBuf>>,
+    /// directories passed to the action, which are not descended
+    not_descended: Mutex<HashSet<PathBuf>>,
+    /// directories given write permission by `make_writable`, with their modes before
+    writable: Mutex<HashMap<PathBuf, u32>>,
+    done: Mutex<HashMap<PathBuf, Result<Option<Item>, error::E>>>,
+}
+
+impl Copier {
+    fn dst_path(&self, rel: &Path) -> PathBuf {
+        if rel.as_os_str().is_empty() {
+            self.dst.clone()
+        } else {
+            self.dst.join(rel)
+        }
+    }
+
+    /// add `u+wx` to the directory `path` in the destination, so that entries can be written in
+    /// it. the visitor restores the mode when it leaves the directory
+    fn make_writable(&self, path: &Path) -> io::Result<()> {
+        let m = std::fs::symlink_metadata(path)?;
+        let mode = m.mode() & 0o7777;
+        if !path.starts_with(&self.dst) || !m.is_dir() || mode & 0o300 == 0o300 {
+            return Ok(());
+        }
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode | 0o300))?;
+        self.writable
+            .lock()
+            .unwrap()
+            .entry(path.to_path_buf())
+            .or_insert(mode);
+        Ok(())
+    }
+
+    /// run `f`, and again after giving the parent of `path` write permission when it is denied
+    fn with_writable_parent<R>(&self, path: &Path, f: impl Fn() -> io::Result<R>) -> io::Result<R> {
+        match f() {
+            Err(e) if e.raw_os_error() == Some(libc::EACCES) => {
+                match path.parent() {
+                    // not the owner. the retry fails with `EACCES` again
+                    Some(p) => {
+                        let _ = self.make_writable(p);
+                    }
+                    None => return Err(e),
+                }
+                f()
+            }
+            r => r,
+        }
+    }
+
+    /// make `path` and missing parents. an entry other than a directory in the way is removed
+    fn make_dir(&self, path: &Path) -> io::Result<()> {
+        match self.with_writable_parent(path, || std::fs::create_dir(path)) {
+            Ok(()) => {
+                self.created.lock().unwrap().insert(path.to_path_buf());
+                Ok(())
+            }
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                let m = std::fs::symlink_metadata(path)?;
+                if m.is_dir() {
+                    return Ok(());
+                }
+                self.with_writable_parent(path, || remove_any(path, &m))?;
+                self.make_dir(path)
+            }
+            Err(e) if matches!(e.raw_os_error(), Some(libc::ENOENT) | Some(libc::ENOTDIR)) => {
+                match path.parent() {
+                    Some(p) if path != self.dst => self.make_dir(p)?,
+                    _ => return Err(e),
+                }
+                self.make_dir(path)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// run `f` making the parent of `path` when it is missing, or giving it write permission
+    fn with_parent<R>(&self, path: &Path, f: impl Fn() -> io::Result<R>) -> io::Result<R> {
+        let f = || self.with_writable_parent(path, &f);
+        match f() {
+            Err(e) if matches!(e.raw_os_error(), Some(libc::ENOENT) | Some(libc::ENOTDIR)) => {
+                if let Some(p) = path.parent() {
+                    self.make_dir(p)?;
+                }
+                f()
+            }
+            r => r,
+        }
+    }
+
+    fn copy_contents(&self, src: &mut File, dst: &mut File, st: &FileStat) -> io::Result<()> {
+        if self.use_fallocate && st.st_size > 0 {
+            // only a hint. it fails on some filesystems
+            unsafe { libc::fallocate(dst.as_raw_fd(), 0, 0, st.st_size) };
+        }
+        let mut buf = self
+            .buffers
+            .lock()
+            .unwrap()
+            .pop()
+            .unwrap_or_else(|| AlignedBuf::new(self.buffer_size));
+        let r = (|| {
+            let b = buf.as_mut_slice();
+            loop {
+                let n = read_full(src, b)?;
+                if n == 0 {
+                    return Ok(());
+                }
+                if n < b.len() && self.use_o_direct {
+                    // the last block is not aligned
+                    let fl = nix::fcntl::fcntl(dst.as_raw_fd(), nix::fcntl::F_GETFL)?;
+                    nix::fcntl::fcntl(
+                        dst.as_raw_fd(),
+                        nix::fcntl::F_SETFL(
+                            nix::fcntl::OFlag::from_bits_truncate(fl)
+                                & !nix::fcntl::OFlag::O_DIRECT,
+                        ),
+                    )?;
+                }
+                dst.write_all(&b[..n])?;
+                if n < b.len() {
+                    return Ok(());
+                }
+            }
+        })();
+        self.buffers.lock().unwrap().push(buf);
+        r
+    }
+
+    fn copy_file(&self, dir: &Dir, name: &CStr, st: &FileStat, path: &Path) -> io::Result<()> {
+        let mut src = dir.with_fd(|fd| hash::open_at(fd, name))?;
+        let open = |direct: bool| {
+            self.with_parent(path, || {
+                std::fs::OpenOptions::new()
+                    .write(true)
+                    .create_new(true)
+                    // writable until its metadata is applied
+                    .mode(0o600)
+                    .custom_flags(if direct { libc::O_DIRECT } else { 0 })
+                    .open(path)
+            })
+        };
+        let mut dst = match open(self.use_o_direct) {
+            // not supported by the filesystem
+            Err(e) if self.use_o_direct && e.raw_os_error() == Some(libc::EINVAL) => open(false)?,
+            r => r?,
+        };
+        self.copy_contents(&mut src, &mut dst, st)
+    }
+
+    /// apply the metadata again to `path`, whose contents are the same as the entry. `None` when
+    /// the preserved metadata is the same too
+    fn update_metadata(
+        &self,
+        entry: &Entry,
+        path: &Path,
+        st: &FileStat,
+        flags: &str,
+    ) -> io::Result<Option<Item>> {
+        let mut flags: Vec<char> = flags.chars().collect();
+        // only the attributes change, like `rsync`
+        flags[0] = '.';
+        flags[2] = '.';
+        if !self.preserve.timestamps {
+            flags[4] = '.';
+        }
+        if self.preserve.xattr
+            && crate::xattr::get_all(&entry.path)? != crate::xattr::get_all(path)?
+        {
+            flags[10] = 'x';
+        }
+        if flags[2..].iter().all(|c| *c == '.') {
+            return Ok(None);
+        }
+        self.preserve
+            .apply(&entry.path, path, entry.file_type, st)?;
+        Ok(Some(Item {
+            flags: flags.into_iter().collect(),
+            link: None,
+        }))
+    }
+
+    /// copy one entry. `None` when the destination is the same already
+    fn copy(
+        &self,
+        dir: &Dir,
+        name: &CStr,
+        entry: &Entry,
+        st: &FileStat,
+    ) -> io::Result<Option<Item>> {
+        let path = self.dst_path(entry.relative_path());
+        let mut old = match std::fs::symlink_metadata(&path) {
+            Ok(m) => Some(m),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) if e.raw_os_error() == Some(libc::ENOTDIR) => None,
+            Err(e) => return Err(e),
+        };
+        if old.as_ref().is_some_and(|m| !same_type(entry.file_type, m)) {
+            self.with_writable_parent(&path, || remove_any(&path, old.as_ref().unwrap()))?;
+            old = None;
+        }
+        let flags = itemize(entry.file_type, old.as_ref(), st, &self.preserve);
+
+        let mut link = None;
+        match (entry.file_type, &old) {
+            (FileType::File, Some(m))
+                if self.incremental
+                    && m.size() == st.st_size as u64
+                    && (m.mtime(), m.mtime_nsec()) == (st.st_mtime, st.st_mtime_nsec) =>
+            {
+                return self.update_metadata(entry, &path, st, &flags);
+            }
+            (FileType::Symlink, _) => {
+                let target = PathBuf::from(dir.with_fd(|fd| nix::fcntl::readlinkat(fd, name))?);
+                if old.is_some() && std::fs::read_link(&path)? == target {
+                    return self.update_metadata(entry, &path, st, &flags);
+                }
+                link = Some(target);
+            }
+            (
+                FileType::Fifo
+                | FileType::Socket
+                | FileType::CharacterDevice
+                | FileType::BlockDevice,
+                Some(m),
+            ) if m.rdev() == st.st_rdev => return self.update_metadata(entry, &path, st, &flags),
+            // made by the visitor
+            (FileType::Directory, _) => return Ok(None),
+            _ => {}
+        }
+
+        let tmp = temp_path(&path);
+        let r = (|| {
+            // left by an interrupted copy
+            let _ = std::fs::remove_file(&tmp);
+            match entry.file_type {
+                FileType::File => self.copy_file(dir, name, st, &tmp)?,
+                FileType::Symlink => self.with_parent(&tmp, || {
+                    std::os::unix::fs::symlink(link.as_ref().unwrap(), &tmp)
+                })?,
+                _ => self.with_parent(&tmp, || {
+                    nix::sys::stat::mknod(
+                        &tmp,
+                        SFlag::from_bits_truncate(st.st_mode) & SFlag::S_IFMT,
+                        Mode::from_bits_truncate(st.st_mode & 0o777),
+                        st.st_rdev,
+                    )
+                    .map_err(io::Error::from)
+                })?,
+            }
+            self.preserve
+                .apply(&entry.path, &tmp, entry.file_type, st)?;
+            if entry.file_type == FileType::File && !self.preserve.mode {
+                // the mode it would be created with
+                std::fs::set_permissions(
+                    &tmp,
+                    std::fs::Permissions::from_mode(st.st_mode & 0o777 & !self.umask),
+                )?;
+            }
+            self.with_writable_parent(&path, || std::fs::rename(&tmp, &path))
+        })();
+        if r.is_err() {
+            let _ = std::fs::remove_file(&tmp);
+        }
+        r?;
+        Ok(Some(Item { flags, link }))
+    }
+}
+
+impl Action for Copier {
+    fn entry(
+        &self,
+        dir: &Dir,
+        name: &CStr,
+        entry: &Entry,
+        metadata: Option<&FileStat>,
+    ) -> Result<bool, error::E> {
+        if entry.file_type == FileType::Directory {
+            self.not_descended
+                .lock()
+                .unwrap()
+                .insert(entry.path.clone());
+            return Ok(true);
+        }
+        let st = match metadata {
+            Some(st) => st,
+            None => return Ok(true),
+        };
+        let r = self
+            .copy(dir, name, entry, st)
+            .map_err(|eno| error::E::CopyError {
+                path: entry.path.clone(),
+                eno,
+            });
+        self.done.lock().unwrap().insert(entry.path.clone(), r);
+        Ok(true)
+    }
+}
+
+struct DirState {
+    depth: usize,
+    src: PathBuf,
+    dst: PathBuf,
+    metadata: FileStat,
+}
+
+pub struct Cloner {
+    out: Output,
+    quoting_style: QuotingStyle,
+    dst: PathBuf,
+    use_o_direct: bool,
+    use_fallocate: bool,
+    buffer_size: usize,
+    incremental: bool,
+    delete: bool,
+    itemize: bool,
+    preserve: Preserved,
+    copier: Option<Arc<Copier>>,
+    /// directories to apply the metadata to, when the visitor leaves them
+    dirs: Vec<DirState>,
+    errors: usize,
+    buf: Vec<u8>,
+}
+
+impl Cloner {
+    pub fn new(out: Box<dyn Write + Send>, dst: &Path) -> Cloner {
+        Cloner {
+            out: Output::Text(out),
+            quoting_style: QuotingStyle::Literal,
+            dst: dst.to_path_buf(),
+            use_o_direct: false,
+            use_fallocate: true,
+            buffer_size: 1024 * 1024,
+            incremental: false,
+            delete: false,
+            itemize: false,
+            preserve: Preserved::new(&[Preserve::Timestamps]),
+            copier: None,
+            dirs: Vec::new(),
+            errors: 0,
+            buf: Vec::new(),
+        }
+    }
+
+    /// write with `O_DIRECT` where the filesystem supports it
+    pub fn use_o_direct(mut self, b: bool) -> Self {
+        self.use_o_direct = b;
+        self
+    }
+
+    pub fn use_fallocate(mut self, b: bool) -> Self {
+        self.use_fallocate = b;
+        self
+    }
+
+    /// bytes copied at once for each file. rounded up to 4096
+    pub fn buffer_size(mut self, size: u64) -> Self {
+        self.buffer_size = (size.max(1) as usize).div_ceil(ALIGN) * ALIGN;
+        self
+    }
+
+    /// skip regular files of the same size and modification time. the preserved metadata is
+    /// applied to them again when it differs
+    pub fn incremental(mut self, b: bool) -> Self {
+        self.incremental = b;
+        self
+    }
+
+    /// delete entries in the destination which are not in the root directory
+    pub fn delete(mut self, b: bool) -> Self {
+        self.delete = b;
+        self
+    }
+
+    /// print the changes like `rsync --itemize-changes`
+    pub fn itemize(mut self, b: bool) -> Self {
+        self.itemize = b;
+        self
+    }
+
+    /// metadata to copy, like `cp --preserve`. only timestamps by default
+    pub fn preserve(mut self, p: &[Preserve]) -> Self {
+        self.preserve = Preserved::new(p);
+        self
+    }
+
+    pub fn quoting_style(mut self, style: QuotingStyle) -> Self {
+        self.quoting_style = style;
+        self
+    }
+
+    pub fn format(mut self, format: Format) -> Self {
+        self.out = self.out.with_format(format, CLONE_COLUMNS);
+        self
+    }
+
+    fn print(
+        &mut self,
+        flags: &str,
+        rel: &Path,
+        is_dir: bool,
+        link: Option<&Path>,
+    ) -> Result<(), error::E> {
+        if !self.itemize {
+            return Ok(());
+        }
+        let mut path = rel.as_os_str().as_bytes().to_vec();
+        if path.is_empty() {
+            path.push(b'.');
+        }
+        if is_dir {
+            path.push(b'/');
+        }
+        match &mut self.out {
+            Output::Text(out) => {
+                self.buf.clear();
+                self.buf.extend_from_slice(flags.as_bytes());
+                self.buf.push(b' ');
+                quote::quote(&path, self.quoting_style, &mut self.buf);
+                if let Some(l) = link {
+                    self.buf.extend_from_slice(b" -> ");
+                    quote::quote(l.as_os_str().as_bytes(), self.quoting_style, &mut self.buf);
+                }
+                self.buf.push(b'\n');
+                error::maybe_generic_io_error(out.write_all(&self.buf))
+            }
+            Output::Records(w) => w.write(&Record::cloned(&path, flags)),
+        }
+    }
+
+    /// apply the metadata to the directories not containing the entry at `depth`
+    fn leave_dirs(&mut self, depth: usize) -> Result<(), error::E> {
+        while self.dirs.last().is_some_and(|d| d.depth >= depth) {
+            let d = self.dirs.pop().unwrap();
+            let copier = self.copier.clone().unwrap();
+            let mode = copier.writable.lock().unwrap().remove(&d.dst);
+            let r = self
+                .preserve
+                .apply(&d.src, &d.dst, FileType::Directory, &d.metadata)
+                .and_then(|()| match mode {
+                    Some(m) if !self.preserve.mode => restore_mode(&d.dst, m),
+                    _ => Ok(()),
+                });
+            if let Err(eno) = r {
+                self.copy_error(error::E::CopyError { path: d.dst, eno })?;
+            }
+        }
+        Ok(())
+    }
+
+    fn copy_error(&mut self, e: error::E) -> Result<(), error::E> {
+        self.errors += 1;
+        self.out.error(&e)
+    }
+
+    /// delete the entries of `dst` not in `src`
+    fn delete_extraneous(&mut self, src: &Path, dst: &Path, rel: &Path) -> Result<(), error::E> {
+        let mut names: Vec<_> = match std::fs::read_dir(dst) {
+            Ok(d) => d.filter_map(|e| e.ok()).map(|e| e.file_name()).collect(),
+            Err(eno) => {
+                return self.copy_error(error::E::CopyError {
+                    path: dst.to_path_buf(),
+                    eno,
+                })
+            }
+        };
+        names.sort();
+        // being written by the traverse threads
+        names.retain(|n| !is_temp_path(n));
+        for name in names {
+            match std::fs::symlink_metadata(src.join(&name)) {
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                _ => continue,
+            }
+            let path = dst.join(&name);
+            let copier = self.copier.clone().unwrap();
+            let r = std::fs::symlink_metadata(&path).and_then(|m| {
+                copier.with_writable_parent(&path, || remove_any(&path, &m))?;
+                Ok(m.is_dir())
+            });
+            match r {
+                Ok(is_dir) => self.print("*deleting  ", &rel.join(&name), is_dir, None)?,
+                Err(e) => {
+                    self.copy_error(error::E::RemoveError {
+                        path,
+                        eno: Errno::from_i32(e.raw_os_error().unwrap_or(0)),
+                    })?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Visitor for Cloner {
+    fn wants_metadata(&self) -> bool {
+        true
+    }
+
+    fn requires_order(&self) -> bool {
+        true
+    }
+
+    fn action(&self) -> Option<Arc<dyn Action>> {
+        self.copier.clone().map(|c| c as Arc<dyn Action>)
+    }
+
+    fn start(&mut self, roots: &[PathBuf]) -> Result<(), error::E> {
+        let root = match roots {
+            [r] => r,
+            _ => {
+                return Err(error::invalid_option(
+                    "dst",
+                    "copies exactly one root directory",
+                ))
+            }
+        };
+        let copier = Copier {
+            dst: self.dst.clone(),
+            use_o_direct: self.use_o_direct,
+            use_fallocate: self.use_fallocate,
+            buffer_size: self.buffer_size,
+            incremental: self.incremental,
+            preserve: self.preserve,
+            umask: crate::modify::current_umask(),
+            buffers: Mutex::new(Vec::new()),
+            created: Mutex::new(HashSet::new()),
+            not_descended: Mutex::new(HashSet::new()),
+            writable: Mutex::new(HashMap::new()),
+            done: Mutex::new(HashMap::new()),
+        };
+        // the nearest existing ancestor, as `dst` may not exist yet
+        let existing = self
+            .dst
+            .ancestors()
+            .find(|p| p.exists())
+            .unwrap_or(Path::new("."));
+        let canonical = |p: &Path| error::maybe_generic_io_error(p.canonicalize());
+        let (existing_c, root_c) = (canonical(existing)?, canonical(root)?);
+        if existing_c.starts_with(&root_c) {
+            return Err(error::invalid_option(
+                "dst",
+                "must not be in the root directory",
+            ));
+        }
+        // --delete would remove the root directory from the destination
+        if existing == self.dst && root_c.starts_with(&existing_c) {
+            return Err(error::invalid_option(
+                "dst",
+                "must not contain the root directory",
+            ));
+        }
+        let parent = self.dst.parent().filter(|p| !p.as_os_str().is_empty());
+        parent
+            .map_or(Ok(()), std::fs::create_dir_all)
+            .and_then(|()| copier.make_dir(&self.dst))
+            .map_err(|eno| error::E::CopyError {
+                path: self.dst.clone(),
+                eno,
+            })?;
+        self.copier = Some(Arc::new(copier));
+        Ok(())
+    }
+
+    fn visit(&mut self, entry: &Entry) -> Result<(), error::E> {
+        let copier = self.copier.clone().unwrap();
+        let rel = entry.relative_path();
+        self.leave_dirs(entry.depth)?;
+        if entry.file_type != FileType::Directory {
+            return match copier.done.lock().unwrap().remove(&entry.path) {
+                Some(Ok(Some(item))) => self.print(&item.flags, rel, false, item.link.as_deref()),
+                Some(Err(e)) => self.copy_error(e),
+                _ => Ok(()),
+            };
+        }
+
+        let dst = copier.dst_path(rel);
+        if let Err(eno) = copier.make_dir(&dst) {
+            return self.copy_error(error::E::CopyError { path: dst, eno });
+        }
+        if copier.created.lock().unwrap().remove(&dst) {
+            self.print("cd+++++++++", rel, true, None)?;
+        }
+        let descended = !copier.not_descended.lock().unwrap().remove(&entry.path);
+        if self.delete && descended {
+            self.delete_extraneous(&entry.path, &dst, rel)?;
+        }
+        if let Some(st) = entry.metadata {
+            self.dirs.push(DirState {
+                depth: entry.depth,
+                src: entry.path.clone(),
+                dst,
+                metadata: st,
+            });
+        }
+        Ok(())
+    }
+
+    fn error(&mut self, e: &error::E) -> Result<(), error::E> {
+        self.errors += 1;
+        self.out.error(e)
+    }
+
+    fn finish(&mut self) -> Result<(), error::E> {
+        self.leave_dirs(0)?;
+        // not visited, like the parents of entries below `--min-depth`
+        if let Some(c) = self.copier.clone() {
+            let writable: Vec<_> = c.writable.lock().unwrap().drain().collect();
+            for (path, mode) in writable {
+                if let Err(eno) = restore_mode(&path, mode) {
+                    self.copy_error(error::E::CopyError { path, eno })?;
+                }
+            }
+        }
+        self.out.flush()?;
+        if self.errors > 0 {
+            return Err(error::E::Incomplete {
+                errors: self.errors,
+            });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::options::Order;
+    use crate::options::TestDir;
+    use crate::printer::test_run;
+
+    fn clone(src: &Path, dst: &Path, incremental: bool, delete: bool) -> String {
+        let mut opts = crate::options::test_option(src.to_str().unwrap());
+        opts.order = Order::Alphabetical;
+        opts.num_threads = 4;
+        opts.min_depth = Some(0);
+        let (out, r) = test_run(opts, |out| {
+            Cloner::new(out, dst)
+                .buffer_size(4096)
+                .incremental(incremental)
+                .delete(delete)
+                .itemize(true)
+        });
+        r.unwrap();
+        out
+    }
+
+    #[test]
+    fn incremental() {
+        let src = crate::options::test_tree("clone_src", &["a/b", "a/c", "d/", "e"]);
+        let dst = TestDir::new("clone_dst");
+        let big: Vec<u8> = (0..10000u32).map(|i| i as u8).collect();
+        std::fs::write(src.join("a/c"), &big).unwrap();
+        std::os::unix::fs::symlink("a/b", src.join("l")).unwrap();
+
+        assert_eq!(
+            clone(&src, &dst, true, false),
+            "cd+++++++++ ./\n\
+             cd+++++++++ a/\n\
+             >f+++++++++ a/b\n\
+             >f+++++++++ a/c\n\
+             cd+++++++++ d/\n\
+             >f+++++++++ e\n\
+             cL+++++++++ l -> a/b\n"
+        );
+        assert_eq!(std::fs::read(dst.join("a/c")).unwrap(), big);
+        assert_eq!(clone(&src, &dst, true, false), "");
+
+        std::fs::write(src.join("e"), "changed").unwrap();
+        std::fs::write(dst.join("d/x"), "").unwrap();
+        std::fs::create_dir_all(dst.join("y/z")).unwrap();
+        assert_eq!(
+            clone(&src, &dst, true, true),
+            "*deleting   y/\n\
+             *deleting   d/x\n\
+             >f.st...... e\n"
+        );
+        assert_eq!(std::fs::read(dst.join("e")).unwrap(), b"changed");
+        assert!(!dst.join("y").exists());
+    }
+
+    #[test]
+    fn update_metadata() {
+        let src = crate::options::test_tree("clone_update", &["a", "b"]);
+        let dst = TestDir::new("clone_update_dst");
+        let run = || {
+            let mut opts = crate::options::test_option(src.to_str().unwrap());
+            opts.order = Order::Alphabetical;
+            let (out, r) = test_run(opts, |out| {
+                Cloner::new(out, &dst)
+                    .incremental(true)
+                    .itemize(true)
+                    .preserve(&[Preserve::Mode, Preserve::Timestamps])
+            });
+            r.unwrap();
+            out
+        };
+        run();
+        std::fs::set_permissions(src.join("a"), std::fs::Permissions::from_mode(0o640)).unwrap();
+        assert_eq!(run(), ".f...p..... a\n");
+        let m = std::fs::symlink_metadata(dst.join("a")).unwrap();
+        assert_eq!(m.mode() & 0o7777, 0o640);
+        assert_eq!(run(), "");
+    }
+
+    #[test]
+    fn read_only_dir() {
+        let src = crate::options::test_tree("clone_read_only", &["r/f"]);
+        let dst = TestDir::new("clone_read_only_dst");
+        let set_mode =
+            |p: &Path, m| std::fs::set_permissions(p, std::fs::Permissions::from_mode(m)).unwrap();
+        set_mode(&src.join("r"), 0o555);
+        for preserve in [&[Preserve::Mode][..], &[]] {
+            for content in ["a", "bb"] {
+                set_mode(&src.join("r"), 0o755);
+                std::fs::write(src.join("r/f"), content).unwrap();
+                set_mode(&src.join("r"), 0o555);
+                let opts = crate::options::test_option(src.to_str().unwrap());
+                let (_, r) = test_run(opts, |out| {
+                    Cloner::new(out, &dst).incremental(true).preserve(preserve)
+                });
+                r.unwrap();
+                assert_eq!(std::fs::read(dst.join("r/f")).unwrap(), content.as_bytes());
+                let m = std::fs::symlink_metadata(dst.join("r")).unwrap();
+                assert_eq!(m.mode() & 0o7777, 0o555);
+            }
+        }
+    }
+
+    #[test]
+    fn nested() {
+        let root = crate::options::test_tree("clone_nested", &["a/b/c"]);
+        for (src, dst) in [("a", "a/b/d"), ("a/b", "a")] {
+            let opts = crate::options::test_option(root.join(src).to_str().unwrap());
+            let (_, r) = test_run(opts, |out| Cloner::new(out, &root.join(dst)).delete(true));
+            assert!(matches!(r, Err(error::E::InvalidOptionError { .. })));
+        }
+        assert!(root.join("a/b/c").exists());
+        assert!(!root.join("a/b/d").exists());
+    }
+
+    #[test]
+    fn preserve() {
+        let src = crate::options::test_tree("clone_preserve", &["a/b", "a/c/", "a/r"]);
+        let dst = TestDir::new("clone_preserve_dst");
+        let set_mode = |p: &str, m| {
+            std::fs::set_permissions(src.join(p), std::fs::Permissions::from_mode(m)).unwrap()
+        };
+        let xattr = ["a/b", "a/r"]
+            .iter()
+            .all(|p| crate::xattr::set(&src.join(p), b"user.test", b"v").is_ok());
+        set_mode("a/b", 0o604);
+        set_mode("a/r", 0o444);
+        set_mode("a", 0o750);
+        let t = TimeSpec::from(libc::timespec {
+            tv_sec: 1_000_000_000,
+            tv_nsec: 123_456_789,
+        });
+        for p in ["a/b", "a/c", "a/r", "a"] {
+            nix::sys::stat::utimensat(
+                None,
+                &src.join(p),
+                &t,
+                &t,
+                nix::sys::stat::UtimensatFlags::NoFollowSymlink,
+            )
+            .unwrap();
+        }
+
+        let mut opts = crate::options::test_option(src.to_str().unwrap());
+        opts.order = Order::Alphabetical;
+        opts.num_threads = 4;
+        opts.min_depth = Some(0);
+        let (_, r) = test_run(opts, |out| {
+            Cloner::new(out, &dst).preserve(&[
+                Preserve::Mode,
+                Preserve::Timestamps,
+                Preserve::Xattr,
+            ])
+        });
+        r.unwrap();
+
+        for (p, mode) in [("a/b", 0o604), ("a/r", 0o444), ("a", 0o750)] {
+            let m = std::fs::symlink_metadata(dst.join(p)).unwrap();
+            assert_eq!(m.mode() & 0o7777, mode);
+            // "a" is written after its times are read, so they are applied after the entries in it
+            assert_eq!((m.mtime(), m.mtime_nsec()), (1_000_000_000, 123_456_789));
+        }
+        if xattr {
+            for p in ["a/b", "a/r"] {
+                assert_eq!(crate::xattr::get(&dst.join(p), b"user.test").unwrap(), b"v");
+            }
+        }
+    }
+}
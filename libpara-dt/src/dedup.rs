@@ -0,0 +1,291 @@
+//! `--dedup` of `Method::Dupes`. replaces duplicates with hard links, or shares their extents.
+//!
+//! In each group of `dupes::find_groups`, the first file on each filesystem is kept,
+//! and the others are compared with it byte by byte right before they are replaced.
+//! Hard links are made only between files of the same owner, group and mode, like `hardlink(1)`,
+//! so that no file changes its permissions.
+
+use crate::dupes::File;
+use crate::error;
+use serde::{Deserialize, Serialize};
+use std::ffi::{OsStr, OsString};
+use std::io::{self, Read};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, Eq, PartialEq)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+pub enum DedupMode {
+    /// replace duplicates with hard links to the kept file. only files with the same owner,
+    /// group and mode are linked. they get the times of the kept file
+    Hardlink,
+    /// share the extents with `FIDEDUPERANGE`, on filesystems supporting it like btrfs and xfs.
+    /// the files stay separate
+    Reflink,
+}
+
+#[derive(Debug, Default)]
+pub struct Summary {
+    pub files: AtomicUsize,
+    /// of the replaced files. a file with other hard links is not counted, as its data stays
+    pub bytes: AtomicU64,
+}
+
+const COMPARE_BUFFER_SIZE: usize = 256 * 1024;
+
+/// read until `buf` is full or the end of the file
+pub fn read_full(f: &mut std::fs::File, buf: &mut [u8]) -> io::Result<usize> {
+    let mut n = 0;
+    while n < buf.len() {
+        match f.read(&mut buf[n..]) {
+            Ok(0) => break,
+            Ok(m) => n += m,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(n)
+}
+
+/// compare the rest of two files byte by byte
+pub fn same_contents(fa: &mut std::fs::File, fb: &mut std::fs::File) -> io::Result<bool> {
+    let mut ba = vec![0u8; COMPARE_BUFFER_SIZE];
+    let mut bb = vec![0u8; COMPARE_BUFFER_SIZE];
+    loop {
+        let na = read_full(fa, &mut ba)?;
+        let nb = read_full(fb, &mut bb)?;
+        if na != nb || ba[..na] != bb[..nb] {
+            return Ok(false);
+        }
+        if na == 0 {
+            return Ok(true);
+        }
+    }
+}
+
+fn temp_suffix() -> String {
+    format!(".para-dt-{}", std::process::id())
+}
+
+/// a temporary name next to `path`, to be renamed over it
+pub fn temp_path(path: &Path) -> PathBuf {
+    let mut tmp = path.as_os_str().to_owned().into_vec();
+    tmp.extend_from_slice(temp_suffix().as_bytes());
+    PathBuf::from(OsString::from_vec(tmp))
+}
+
+/// made by `temp_path` in this process
+pub fn is_temp_path(name: &OsStr) -> bool {
+    name.as_bytes().ends_with(temp_suffix().as_bytes())
+}
+
+/// link `keep` to a temporary name next to `dup`, and rename it over `dup`
+fn replace_with_link(keep: &Path, dup: &Path) -> io::Result<()> {
+    let tmp = temp_path(dup);
+    std::fs::hard_link(keep, &tmp)?;
+    std::fs::rename(&tmp, dup).inspect_err(|_| {
+        let _ = std::fs::remove_file(&tmp);
+    })
+}
+
+/// `struct file_dedupe_range` with one `struct file_dedupe_range_info`
+#[repr(C)]
+struct DedupeRange {
+    src_offset: u64,
+    src_length: u64,
+    dest_count: u16,
+    reserved1: u16,
+    reserved2: u32,
+    dest_fd: i64,
+    dest_offset: u64,
+    bytes_deduped: u64,
+    status: i32,
+    reserved: u32,
+}
+
+/// `_IOWR(0x94, 54, struct file_dedupe_range)`
+const FIDEDUPERANGE: libc::c_ulong = 0xc0189436;
+const FILE_DEDUPE_RANGE_DIFFERS: i32 = 1;
+
+fn dedupe_range(keep: &Path, dup: &Path, size: u64) -> io::Result<()> {
+    let src = std::fs::File::open(keep)?;
+    let dst = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(dup)?;
+    let mut off = 0;
+    while off < size {
+        let mut r = DedupeRange {
+            src_offset: off,
+            src_length: size - off,
+            dest_count: 1,
+            reserved1: 0,
+            reserved2: 0,
+            dest_fd: dst.as_raw_fd() as i64,
+            dest_offset: off,
+            bytes_deduped: 0,
+            status: 0,
+            reserved: 0,
+        };
+        if unsafe { libc::ioctl(src.as_raw_fd(), FIDEDUPERANGE, &mut r) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if r.status < 0 {
+            return Err(io::Error::from_raw_os_error(-r.status));
+        }
+        if r.status == FILE_DEDUPE_RANGE_DIFFERS {
+            return Err(io::Error::other("contents changed"));
+        }
+        if r.bytes_deduped == 0 {
+            return Err(io::Error::other("no progress"));
+        }
+        off += r.bytes_deduped;
+    }
+    Ok(())
+}
+
+/// (kept, duplicates) in each group, per filesystem, and also per owner and mode for hard links
+fn pairs(files: &[File], groups: &[Vec<usize>], mode: DedupMode) -> Vec<(usize, Vec<usize>)> {
+    let key = |f: &File| match mode {
+        DedupMode::Hardlink => (f.dev, f.uid, f.gid, f.mode),
+        DedupMode::Reflink => (f.dev, 0, 0, 0),
+    };
+    let mut ret = Vec::new();
+    for g in groups {
+        let mut by_key: Vec<(usize, Vec<usize>)> = Vec::new();
+        for &i in g {
+            match by_key
+                .iter_mut()
+                .find(|(k, _)| key(&files[*k]) == key(&files[i]))
+            {
+                Some((_, d)) => d.push(i),
+                None => by_key.push((i, Vec::new())),
+            }
+        }
+        ret.extend(by_key.into_iter().filter(|(_, d)| !d.is_empty()));
+    }
+    ret
+}
+
+/// Deduplicate the groups in `num_threads` threads, or only count with `dry_run`.
+/// errors are passed to `error`, and the files are left as they are.
+pub fn dedup(
+    files: &[File],
+    groups: &[Vec<usize>],
+    mode: DedupMode,
+    dry_run: bool,
+    num_threads: usize,
+    error: impl FnMut(error::E) + Send,
+) -> Summary {
+    let summary = Summary::default();
+    let pairs = pairs(files, groups, mode);
+    let next = AtomicUsize::new(0);
+    let error = Mutex::new(error);
+    let done = |i: usize| {
+        let f = &files[i];
+        summary.files.fetch_add(1, Ordering::Relaxed);
+        if mode == DedupMode::Reflink || f.nlink == 1 {
+            summary.bytes.fetch_add(f.size, Ordering::Relaxed);
+        }
+    };
+
+    std::thread::scope(|s| {
+        for _ in 0..num_threads {
+            s.spawn(|| loop {
+                let n = next.fetch_add(1, Ordering::Relaxed);
+                let (keep, dups) = match pairs.get(n) {
+                    Some(p) => p,
+                    None => break,
+                };
+                let keep = &files[*keep];
+                for &i in dups {
+                    if dry_run {
+                        done(i);
+                        continue;
+                    }
+                    let dup = &files[i];
+                    let r = std::fs::File::open(&keep.path)
+                        .and_then(|mut a| {
+                            same_contents(&mut a, &mut std::fs::File::open(&dup.path)?)
+                        })
+                        .and_then(|same| {
+                            if !same {
+                                return Err(io::Error::other(format!(
+                                    "differs from {:?}",
+                                    keep.path
+                                )));
+                            }
+                            match mode {
+                                DedupMode::Hardlink => replace_with_link(&keep.path, &dup.path),
+                                DedupMode::Reflink => dedupe_range(&keep.path, &dup.path, dup.size),
+                            }
+                        });
+                    match r {
+                        Ok(()) => done(i),
+                        Err(eno) => (error.lock().unwrap())(error::E::DedupError {
+                            path: dup.path.clone(),
+                            eno,
+                        }),
+                    }
+                }
+            });
+        }
+    });
+    summary
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+    #[test]
+    fn hardlink() {
+        let root = crate::options::test_tree("dedup", &["a", "b", "c", "d", "e"]);
+        for p in ["a", "b", "c", "e"] {
+            std::fs::write(root.join(p), "same").unwrap();
+        }
+        // a different mode is kept
+        std::fs::set_permissions(root.join("e"), std::fs::Permissions::from_mode(0o600)).unwrap();
+        std::fs::set_permissions(root.join("a"), std::fs::Permissions::from_mode(0o644)).unwrap();
+        let files: Vec<File> = ["a", "b", "c", "d", "e"]
+            .iter()
+            .map(|p| {
+                let m = std::fs::metadata(root.join(p)).unwrap();
+                File {
+                    path: root.join(p),
+                    size: m.size(),
+                    output_path: Vec::new(),
+                    dev: m.dev(),
+                    nlink: m.nlink(),
+                    uid: m.uid(),
+                    gid: m.gid(),
+                    mode: m.mode() & 0o7777,
+                }
+            })
+            .collect();
+        // "d" is in the group by mistake, and is left by the byte comparison
+        let groups = vec![vec![0, 1, 2, 3, 4]];
+
+        let s = dedup(&files, &groups, DedupMode::Hardlink, true, 2, |_| {});
+        assert_eq!(s.files.load(Ordering::Relaxed), 3);
+        assert_eq!(s.bytes.load(Ordering::Relaxed), 4 + 4 + 1);
+        assert_eq!(std::fs::metadata(root.join("a")).unwrap().nlink(), 1);
+
+        let mut errors = Vec::new();
+        let s = dedup(&files, &groups, DedupMode::Hardlink, false, 2, |e| {
+            errors.push(e)
+        });
+        assert_eq!(s.files.load(Ordering::Relaxed), 2);
+        assert_eq!(errors.len(), 1);
+        let ino = |p: &str| std::fs::metadata(root.join(p)).unwrap().ino();
+        assert_eq!(ino("a"), ino("b"));
+        assert_eq!(ino("a"), ino("c"));
+        assert_ne!(ino("a"), ino("d"));
+        assert_ne!(ino("a"), ino("e"));
+        assert_eq!(std::fs::read(root.join("d")).unwrap(), b"d");
+    }
+}
@@ -0,0 +1,621 @@
+//! `Method::Diff`. compares two trees like `diff -rq`.
+//!
+//! The other tree is traversed by another traversal with the same options, and its entries are sent
+//! to the visitor of the root directory through a bounded channel. Both come in pre-order with the names
+//! sorted byte-wise, so they are paired by the relative path like a merge of two sorted lists.
+//! The contents of regular files are compared by the traverse threads of the root directory.
+//! Modes and owners are compared only with `Differ::metadata`, and times only by `Strictness::Mtime`.
+
+use crate::dedup::same_contents;
+use crate::dir::Dir;
+use crate::error;
+use crate::hash::{self, HashAlgorithm};
+use crate::options::Options;
+use crate::pathstr::PathFormat;
+use crate::quote::{self, QuotingStyle};
+use crate::record::{Format, Output, Record, DIFF_COLUMNS};
+use crate::traverse::{traverse_with_visitor, Traverser};
+use crate::visitor::{Action, Entry, FileType, Visitor};
+use crossbeam::channel::{Receiver, Sender};
+use nix::sys::stat::FileStat;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{self, AtomicUsize};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, Eq, PartialEq)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+pub enum Strictness {
+    /// regular files differ in size or modification time. nothing is read
+    Mtime,
+    /// regular files differ in the BLAKE3 hash of the contents. times are not compared
+    Hash,
+    /// regular files differ byte by byte, like `diff -rq`. times are not compared
+    Bytes,
+}
+
+/// entries of the other tree buffered ahead of the root directory
+const CHANNEL_SIZE: usize = 4096;
+
+enum Msg {
+    Entry(Entry),
+    Done,
+}
+
+/// Visitor of the other tree
+struct OtherSide {
+    tx: Sender<Msg>,
+    errors: Arc<AtomicUsize>,
+}
+
+impl Visitor for OtherSide {
+    fn wants_metadata(&self) -> bool {
+        true
+    }
+
+    fn requires_order(&self) -> bool {
+        true
+    }
+
+    fn visit(&mut self, entry: &Entry) -> Result<(), error::E> {
+        self.tx
+            .send(Msg::Entry(entry.clone()))
+            .map_err(|_| error::E::Cancelled)
+    }
+
+    fn error(&mut self, e: &error::E) -> Result<(), error::E> {
+        self.errors.fetch_add(1, atomic::Ordering::Relaxed);
+        eprintln!("ignored error: {}", e);
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), error::E> {
+        self.tx.send(Msg::Done).map_err(|_| error::E::Cancelled)
+    }
+}
+
+/// Compares each regular file with the file at the same relative path in the other tree.
+/// Nothing is stored when the other is not a regular file.
+struct ContentCompare {
+    other: PathBuf,
+    strictness: Strictness,
+    done: Mutex<HashMap<PathBuf, Result<bool, error::E>>>,
+}
+
+impl ContentCompare {
+    fn take(&self, path: &Path) -> Option<Result<bool, error::E>> {
+        self.done.lock().unwrap().remove(path)
+    }
+
+    fn compare(&self, dir: &Dir, name: &CStr, path: &Path, other: &Path) -> Result<bool, error::E> {
+        let read_error = |path: &Path| {
+            let path = path.to_path_buf();
+            move |eno| error::E::ReadFileError { path, eno }
+        };
+        let mut l = dir
+            .with_fd(|fd| hash::open_at(fd, name))
+            .map_err(read_error(path))?;
+        let mut r = std::fs::OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_NOFOLLOW)
+            .open(other)
+            .map_err(read_error(other))?;
+        match self.strictness {
+            Strictness::Bytes => same_contents(&mut l, &mut r).map_err(read_error(path)),
+            _ => {
+                let hl =
+                    hash::hash_reader(HashAlgorithm::Blake3, &mut l).map_err(read_error(path))?;
+                let hr =
+                    hash::hash_reader(HashAlgorithm::Blake3, &mut r).map_err(read_error(other))?;
+                Ok(hl == hr)
+            }
+        }
+    }
+}
+
+impl Action for ContentCompare {
+    fn entry(
+        &self,
+        dir: &Dir,
+        name: &CStr,
+        entry: &Entry,
+        metadata: Option<&FileStat>,
+    ) -> Result<bool, error::E> {
+        if entry.file_type != FileType::File {
+            return Ok(true);
+        }
+        let other = self.other.join(entry.relative_path());
+        let r = match std::fs::symlink_metadata(&other) {
+            Ok(m) if m.file_type().is_file() => {
+                if metadata.is_some_and(|st| st.st_size as u64 != m.size()) {
+                    Ok(false)
+                } else {
+                    self.compare(dir, name, &entry.path, &other)
+                }
+            }
+            _ => return Ok(true),
+        };
+        self.done.lock().unwrap().insert(entry.path.clone(), r);
+        Ok(true)
+    }
+}
+
+/// how a pair of entries differs
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Change {
+    OnlyLeft,
+    OnlyRight,
+    Type,
+    Differ,
+    Metadata,
+}
+
+impl Change {
+    fn name(self) -> &'static str {
+        match self {
+            Change::OnlyLeft => "only_left",
+            Change::OnlyRight => "only_right",
+            Change::Type => "type",
+            Change::Differ => "differ",
+            Change::Metadata => "metadata",
+        }
+    }
+}
+
+/// the words of `diff`
+fn type_description(t: FileType) -> &'static str {
+    match t {
+        FileType::File => "regular file",
+        FileType::Directory => "directory",
+        FileType::Symlink => "symbolic link",
+        FileType::Fifo => "fifo",
+        FileType::Socket => "socket",
+        FileType::CharacterDevice => "character special file",
+        FileType::BlockDevice => "block special file",
+    }
+}
+
+pub struct Differ {
+    out: Output,
+    quoting_style: QuotingStyle,
+    path_format: PathFormat,
+    /// options of the other traversal
+    other_opts: Options,
+    strictness: Strictness,
+    metadata: bool,
+    compare: Option<Arc<ContentCompare>>,
+    rx: Option<Receiver<Msg>>,
+    thread: Option<JoinHandle<Result<(), error::E>>>,
+    /// the next entry of the other tree
+    pending: Option<Entry>,
+    other_done: bool,
+    /// entries below these directories are already reported with them
+    skip_left: Option<PathBuf>,
+    skip_right: Option<PathBuf>,
+    differences: usize,
+    errors: usize,
+    other_errors: Arc<AtomicUsize>,
+    buf: Vec<u8>,
+}
+
+impl Differ {
+    /// `opts` are of the root directory, and used for `other` too
+    pub fn new(
+        out: Box<dyn Write + Send>,
+        opts: &Options,
+        other: &Path,
+        strictness: Strictness,
+    ) -> Differ {
+        let mut other_opts = opts.clone();
+        other_opts.src_paths = vec![other.to_path_buf()];
+        other_opts.files_from = None;
+        let compare = (strictness != Strictness::Mtime).then(|| {
+            Arc::new(ContentCompare {
+                other: other.to_path_buf(),
+                strictness,
+                done: Mutex::new(HashMap::new()),
+            })
+        });
+        Differ {
+            out: Output::Text(out),
+            quoting_style: QuotingStyle::Literal,
+            path_format: PathFormat::default(),
+            other_opts,
+            strictness,
+            metadata: false,
+            compare,
+            rx: None,
+            thread: None,
+            pending: None,
+            other_done: false,
+            skip_left: None,
+            skip_right: None,
+            differences: 0,
+            errors: 0,
+            other_errors: Arc::new(AtomicUsize::new(0)),
+            buf: Vec::new(),
+        }
+    }
+
+    /// also compare the mode, owner and group of all entries
+    pub fn metadata(mut self, metadata: bool) -> Self {
+        self.metadata = metadata;
+        self
+    }
+
+    pub fn quoting_style(mut self, style: QuotingStyle) -> Self {
+        self.quoting_style = style;
+        self
+    }
+
+    pub fn format(mut self, format: Format) -> Self {
+        self.out = self.out.with_format(format, DIFF_COLUMNS);
+        self
+    }
+
+    pub fn path_format(mut self, f: PathFormat) -> Self {
+        self.path_format = f;
+        self
+    }
+
+    /// the next entry of the other tree not below a skipped directory
+    fn peek_right(&mut self) -> Option<&Entry> {
+        while self.pending.is_none() && !self.other_done {
+            match self.rx.as_ref().map(|rx| rx.recv()) {
+                Some(Ok(Msg::Entry(e))) => {
+                    if !self
+                        .skip_right
+                        .as_ref()
+                        .is_some_and(|s| e.relative_path().starts_with(s))
+                    {
+                        self.pending = Some(e);
+                    }
+                }
+                _ => self.other_done = true,
+            }
+        }
+        self.pending.as_ref()
+    }
+
+    fn quoted(&mut self, path: &Path) {
+        quote::quote(
+            path.as_os_str().as_bytes(),
+            self.quoting_style,
+            &mut self.buf,
+        );
+    }
+
+    fn report(
+        &mut self,
+        change: Change,
+        left: Option<&Entry>,
+        right: Option<&Entry>,
+    ) -> Result<(), error::E> {
+        self.differences += 1;
+        // the entries below are compared only when both are directories
+        if matches!(change, Change::OnlyLeft | Change::OnlyRight | Change::Type) {
+            for (e, skip) in [(left, &mut self.skip_left), (right, &mut self.skip_right)] {
+                if let Some(e) = e.filter(|e| e.file_type == FileType::Directory) {
+                    *skip = Some(e.relative_path().to_path_buf());
+                }
+            }
+        }
+
+        if let Output::Records(w) = &mut self.out {
+            let e = left.or(right).unwrap();
+            let mut path = Vec::new();
+            self.path_format.push(e, &mut path);
+            return w.write(&Record::diff(&path, change.name()));
+        }
+
+        self.buf.clear();
+        match (change, left, right) {
+            (Change::OnlyLeft, Some(e), _) | (Change::OnlyRight, _, Some(e)) => {
+                self.buf.extend_from_slice(b"Only in ");
+                self.quoted(e.path.parent().unwrap_or(Path::new("")));
+                self.buf.extend_from_slice(b": ");
+                self.quoted(Path::new(e.path.file_name().unwrap_or_default()));
+            }
+            (Change::Type, Some(l), Some(r)) => {
+                self.buf.extend_from_slice(b"File ");
+                self.quoted(&l.path);
+                self.buf.extend_from_slice(
+                    format!(" is a {} while file ", type_description(l.file_type)).as_bytes(),
+                );
+                self.quoted(&r.path);
+                self.buf.extend_from_slice(
+                    format!(" is a {}", type_description(r.file_type)).as_bytes(),
+                );
+            }
+            (Change::Metadata, Some(l), Some(r)) => {
+                let meta = |e: &Entry| {
+                    e.metadata.as_ref().map_or(String::new(), |st| {
+                        format!("{:04o} {}:{}", st.st_mode & 0o7777, st.st_uid, st.st_gid)
+                    })
+                };
+                self.buf.extend_from_slice(b"Metadata of ");
+                self.quoted(&l.path);
+                self.buf
+                    .extend_from_slice(format!(" ({}) and ", meta(l)).as_bytes());
+                self.quoted(&r.path);
+                self.buf
+                    .extend_from_slice(format!(" ({}) differ", meta(r)).as_bytes());
+            }
+            (_, Some(l), Some(r)) => {
+                self.buf
+                    .extend_from_slice(if l.file_type == FileType::Symlink {
+                        b"Symbolic links ".as_slice()
+                    } else {
+                        b"Files "
+                    });
+                self.quoted(&l.path);
+                self.buf.extend_from_slice(b" and ");
+                self.quoted(&r.path);
+                self.buf.extend_from_slice(b" differ");
+            }
+            _ => return Ok(()),
+        }
+        self.buf.push(b'\n');
+        match &mut self.out {
+            Output::Text(out) => error::maybe_generic_io_error(out.write_all(&self.buf)),
+            Output::Records(_) => Ok(()),
+        }
+    }
+
+    fn read_error(&mut self, e: error::E) -> Result<(), error::E> {
+        self.errors += 1;
+        self.out.error(&e)
+    }
+
+    /// compare entries of the same relative path
+    fn compare(&mut self, l: &Entry, r: &Entry) -> Result<(), error::E> {
+        if l.file_type != r.file_type {
+            return self.report(Change::Type, Some(l), Some(r));
+        }
+        let (lst, rst) = match (&l.metadata, &r.metadata) {
+            (Some(lst), Some(rst)) => (lst, rst),
+            _ => return Ok(()),
+        };
+        let differ = match l.file_type {
+            FileType::File => match (self.strictness, &self.compare) {
+                (Strictness::Mtime, _) | (_, None) => {
+                    lst.st_size != rst.st_size
+                        || (lst.st_mtime, lst.st_mtime_nsec) != (rst.st_mtime, rst.st_mtime_nsec)
+                }
+                (_, Some(c)) => match c.take(&l.path) {
+                    Some(Ok(same)) => !same,
+                    Some(Err(e)) => return self.read_error(e),
+                    // replaced after the other tree was read
+                    None => true,
+                },
+            },
+            FileType::Symlink => {
+                let target = |p: &Path| {
+                    std::fs::read_link(p).map_err(|eno| error::E::ReadFileError {
+                        path: p.to_path_buf(),
+                        eno,
+                    })
+                };
+                match (target(&l.path), target(&r.path)) {
+                    (Ok(lt), Ok(rt)) => lt != rt,
+                    (Err(e), _) | (_, Err(e)) => return self.read_error(e),
+                }
+            }
+            FileType::CharacterDevice | FileType::BlockDevice => lst.st_rdev != rst.st_rdev,
+            _ => false,
+        };
+        if differ {
+            self.report(Change::Differ, Some(l), Some(r))?;
+        }
+        // symlinks have no mode of their own on Linux
+        let mode = |st: &FileStat| match l.file_type {
+            FileType::Symlink => 0,
+            _ => st.st_mode & 0o7777,
+        };
+        if self.metadata
+            && (mode(lst), lst.st_uid, lst.st_gid) != (mode(rst), rst.st_uid, rst.st_gid)
+        {
+            self.report(Change::Metadata, Some(l), Some(r))?;
+        }
+        Ok(())
+    }
+}
+
+impl Visitor for Differ {
+    fn wants_metadata(&self) -> bool {
+        true
+    }
+
+    fn requires_order(&self) -> bool {
+        true
+    }
+
+    fn action(&self) -> Option<Arc<dyn Action>> {
+        self.compare.clone().map(|c| c as Arc<dyn Action>)
+    }
+
+    /// start the traversal of the other tree
+    fn start(&mut self, roots: &[PathBuf]) -> Result<(), error::E> {
+        if roots.len() != 1 {
+            return Err(error::invalid_option(
+                "other",
+                "compares exactly one root directory",
+            ));
+        }
+        let other = self.other_opts.src_paths[0].clone();
+        nix::sys::stat::stat(&other).map_err(|eno| error::E::OpenDirError { path: other, eno })?;
+
+        let (tx, rx) = crossbeam::channel::bounded(CHANNEL_SIZE);
+        let v = OtherSide {
+            tx,
+            errors: self.other_errors.clone(),
+        };
+        let opt = self.other_opts.clone();
+        self.rx = Some(rx);
+        self.thread = Some(std::thread::spawn(move || {
+            traverse_with_visitor(&mut Traverser { opt }, Box::new(v))
+        }));
+        Ok(())
+    }
+
+    fn visit(&mut self, entry: &Entry) -> Result<(), error::E> {
+        let rel = entry.relative_path();
+        if self.skip_left.as_ref().is_some_and(|s| rel.starts_with(s)) {
+            return Ok(());
+        }
+        loop {
+            match self.peek_right().map(|r| r.relative_path().cmp(rel)) {
+                Some(Ordering::Less) => {
+                    let r = self.pending.take();
+                    self.report(Change::OnlyRight, None, r.as_ref())?;
+                }
+                Some(Ordering::Equal) => {
+                    let r = self.pending.take().unwrap();
+                    return self.compare(entry, &r);
+                }
+                _ => return self.report(Change::OnlyLeft, Some(entry), None),
+            }
+        }
+    }
+
+    fn error(&mut self, e: &error::E) -> Result<(), error::E> {
+        self.errors += 1;
+        self.out.error(e)
+    }
+
+    fn finish(&mut self) -> Result<(), error::E> {
+        while self.peek_right().is_some() {
+            let r = self.pending.take();
+            self.report(Change::OnlyRight, None, r.as_ref())?;
+        }
+        if let Some(t) = self.thread.take() {
+            t.join().unwrap()?;
+        }
+        self.out.flush()?;
+
+        let errors = self.errors + self.other_errors.load(atomic::Ordering::Relaxed);
+        if errors > 0 {
+            return Err(error::E::Incomplete { errors });
+        }
+        if self.differences > 0 {
+            return Err(error::E::Differences {
+                count: self.differences,
+            });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::options::Order;
+    use crate::printer::test_run;
+
+    fn diff(left: &Path, right: &Path, strictness: Strictness) -> (String, Result<(), error::E>) {
+        let mut opts = crate::options::test_option(left.to_str().unwrap());
+        opts.order = Order::Alphabetical;
+        opts.num_threads = 4;
+        let v = |out| Differ::new(out, &opts, right, strictness);
+        test_run(opts.clone(), v)
+    }
+
+    #[test]
+    fn trees() {
+        let files = ["a/b", "a/c", "d/e/f", "g", "h", "i/"];
+        let l = crate::options::test_tree("diff_l", &files);
+        let r = crate::options::test_tree("diff_r", &files);
+        let (s, res) = diff(&l, &r, Strictness::Bytes);
+        assert_eq!(s, "");
+        res.unwrap();
+
+        std::fs::remove_dir_all(r.join("d")).unwrap();
+        std::fs::create_dir_all(r.join("a/x/y")).unwrap();
+        std::fs::write(r.join("a/c"), "a/C").unwrap();
+        std::fs::remove_file(r.join("g")).unwrap();
+        std::fs::create_dir(r.join("g")).unwrap();
+        std::fs::write(r.join("g/z"), "").unwrap();
+        std::os::unix::fs::symlink("h", l.join("j")).unwrap();
+        std::os::unix::fs::symlink("i", r.join("j")).unwrap();
+
+        let (s, res) = diff(&l, &r, Strictness::Bytes);
+        let p = |root: &Path, rel: &str| root.join(rel).to_str().unwrap().to_owned();
+        assert_eq!(
+            s,
+            format!(
+                "Files {} and {} differ\n\
+                 Only in {}: x\n\
+                 Only in {}: d\n\
+                 File {} is a regular file while file {} is a directory\n\
+                 Symbolic links {} and {} differ\n",
+                p(&l, "a/c"),
+                p(&r, "a/c"),
+                p(&r, "a"),
+                p(&l, ""),
+                p(&l, "g"),
+                p(&r, "g"),
+                p(&l, "j"),
+                p(&r, "j"),
+            )
+            .replace("/:", ":")
+        );
+        assert!(matches!(res, Err(error::E::Differences { count: 5 })));
+
+        // the same size and mtime
+        let (s, _) = diff(&l, &r, Strictness::Mtime);
+        assert!(s.contains(&format!("Files {} and", p(&l, "a/c"))));
+        let mtime = std::fs::metadata(l.join("a/c"))
+            .unwrap()
+            .modified()
+            .unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(r.join("a/c"))
+            .unwrap()
+            .set_modified(mtime)
+            .unwrap();
+        let (s, _) = diff(&l, &r, Strictness::Mtime);
+        assert!(!s.contains("a/c"));
+        let (s, _) = diff(&l, &r, Strictness::Hash);
+        assert!(s.contains(&format!("Files {} and", p(&l, "a/c"))));
+    }
+
+    #[test]
+    fn metadata() {
+        use std::os::unix::fs::PermissionsExt;
+        let files = ["a/b", "c"];
+        let l = crate::options::test_tree("diff_meta_l", &files);
+        let r = crate::options::test_tree("diff_meta_r", &files);
+        let set_mode = |root: &Path, p: &str, m| {
+            std::fs::set_permissions(root.join(p), std::fs::Permissions::from_mode(m)).unwrap()
+        };
+        for (p, lm, rm) in [
+            ("a", 0o755, 0o700),
+            ("a/b", 0o644, 0o600),
+            ("c", 0o644, 0o644),
+        ] {
+            set_mode(&l, p, lm);
+            set_mode(&r, p, rm);
+        }
+        let (s, res) = diff(&l, &r, Strictness::Bytes);
+        assert_eq!(s, "");
+        res.unwrap();
+
+        let mut opts = crate::options::test_option(l.to_str().unwrap());
+        opts.order = Order::Alphabetical;
+        let v = |out| Differ::new(out, &opts, &r, Strictness::Bytes).metadata(true);
+        let (s, res) = test_run(opts.clone(), v);
+        let lines: Vec<_> = s.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with(&format!("Metadata of {} (0755 ", l.join("a").display())));
+        assert!(lines[1].contains(&format!("{} (0600 ", r.join("a/b").display())));
+        assert!(matches!(res, Err(error::E::Differences { count: 2 })));
+    }
+}
@@ -1,10 +1,10 @@
 use crate::error;
-use nix::fcntl::OFlag;
-use nix::sys::stat::Mode;
-use std::ffi::OsStr;
+use nix::fcntl::{AtFlags, OFlag};
+use nix::sys::stat::{FileStat, Mode};
+use std::ffi::{CStr, OsStr};
 use std::ops::DerefMut;
 use std::os::unix::ffi::OsStrExt;
-use std::os::unix::io::AsRawFd;
+use std::os::unix::io::{AsRawFd, RawFd};
 use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 
@@ -13,6 +13,8 @@ pub struct DirV {
     //parent: Option<Dir>, // None when start directory
     abs_path: PathBuf,
     dirfd: nix::dir::Dir,
+    root_dev: u64,   // st_dev of the root directory
+    root_len: usize, // length of the root path in abs_path
 }
 
 #[derive(Clone, Debug)]
@@ -21,6 +23,8 @@ pub struct Dir {
 }
 
 impl Dir {
+    /// `rel_path` is not followed when it is a symlink, e.g. a directory replaced since it was
+    /// read, so that the traversal does not leave the tree. that fails with `ELOOP`
     pub fn new_at(parent: &Dir, rel_path: &Path) -> Result<Dir, error::E> {
         let parent_dir = parent.v.lock().unwrap();
         let mut abs_path = parent_dir.abs_path.clone();
@@ -31,7 +35,7 @@ impl Dir {
             nix::dir::Dir::openat(
                 parent_dir.dirfd.as_raw_fd(),
                 rel_path,
-                OFlag::O_RDONLY | OFlag::O_DIRECTORY,
+                OFlag::O_RDONLY | OFlag::O_DIRECTORY | OFlag::O_NOFOLLOW,
                 Mode::empty(),
             ),
         )?;
@@ -40,20 +44,34 @@ impl Dir {
             v: Arc::new(Mutex::new(DirV {
                 dirfd: fd,
                 abs_path,
+                root_dev: parent_dir.root_dev,
+                root_len: parent_dir.root_len,
             })),
         })
     }
 
     pub fn new_root(path: &Path) -> Result<Dir, error::E> {
         let fd = error::maybe_open_dir_error(
-            &path,
+            path,
             nix::dir::Dir::open(path, OFlag::O_RDONLY | OFlag::O_DIRECTORY, Mode::empty()),
         )?;
 
+        let st = match nix::sys::stat::fstat(fd.as_raw_fd()) {
+            Ok(st) => st,
+            Err(eno) => {
+                return Err(error::E::StatError {
+                    path: path.to_owned(),
+                    eno,
+                })
+            }
+        };
+
         Ok(Dir {
             v: Arc::new(Mutex::new(DirV {
                 dirfd: fd,
                 abs_path: path.to_owned(),
+                root_dev: st.st_dev,
+                root_len: path.as_os_str().len(),
             })),
         })
     }
@@ -62,10 +80,8 @@ impl Dir {
         let mut v = self.v.lock().unwrap();
         let v = v.deref_mut();
         let mut ret = Vec::new();
-        let mut pos = 0;
-        for e in v.dirfd.iter() {
+        for (pos, e) in v.dirfd.iter().enumerate() {
             let e = error::maybe_readdir_error(&v.abs_path, pos, e)?;
-            pos += 1;
 
             let name = e.file_name();
             let bytes = name.to_bytes();
@@ -81,6 +97,64 @@ impl Dir {
         Ok(ret)
     }
 
+    pub fn stat(&self) -> Result<FileStat, error::E> {
+        let v = self.v.lock().unwrap();
+        match nix::sys::stat::fstat(v.dirfd.as_raw_fd()) {
+            Ok(st) => Ok(st),
+            Err(eno) => Err(error::E::StatError {
+                path: v.abs_path.clone(),
+                eno,
+            }),
+        }
+    }
+
+    pub fn root_dev(&self) -> u64 {
+        self.v.lock().unwrap().root_dev
+    }
+
+    /// length in bytes of the root directory path, which `abs_path` starts with
+    pub fn root_len(&self) -> usize {
+        self.v.lock().unwrap().root_len
+    }
+
+    pub fn abs_path(&self) -> PathBuf {
+        self.v.lock().unwrap().abs_path.clone()
+    }
+
+    pub fn stat_at(&self, e: &nix::dir::Entry) -> Result<FileStat, error::E> {
+        let v = self.v.lock().unwrap();
+        let r = nix::sys::stat::fstatat(
+            v.dirfd.as_raw_fd(),
+            e.file_name(),
+            AtFlags::AT_SYMLINK_NOFOLLOW,
+        );
+        drop(v);
+        match r {
+            Ok(st) => Ok(st),
+            Err(eno) => Err(error::E::StatError {
+                path: self.entry_abspath(e),
+                eno,
+            }),
+        }
+    }
+
+    /// call `f` with the fd, for `*at` functions
+    pub fn with_fd<R>(&self, f: impl FnOnce(RawFd) -> R) -> R {
+        let v = self.v.lock().unwrap();
+        f(v.dirfd.as_raw_fd())
+    }
+
+    /// unlink the entry, or rmdir with `is_dir`
+    pub fn unlink_at(&self, name: &CStr, is_dir: bool) -> nix::Result<()> {
+        let v = self.v.lock().unwrap();
+        let flag = if is_dir {
+            nix::unistd::UnlinkatFlags::RemoveDir
+        } else {
+            nix::unistd::UnlinkatFlags::NoRemoveDir
+        };
+        nix::unistd::unlinkat(Some(v.dirfd.as_raw_fd()), name, flag)
+    }
+
     pub fn entry_abspath(&self, e: &nix::dir::Entry) -> PathBuf {
         let v = self.v.lock().unwrap();
         let mut r = v.abs_path.clone();
@@ -0,0 +1,204 @@
+use crate::error;
+use crate::pathstr::PathFormat;
+use crate::record::{Format, Output, Record, DU_COLUMNS};
+use crate::visitor::{Entry, FileType, Visitor};
+use std::collections::HashSet;
+use std::io::Write;
+
+struct DirTotal {
+    path: Vec<u8>,
+    depth: usize,
+    bytes: u64,
+}
+
+/// `Method::DU`. prints the disk usage of each directory in KiB, like `du`.
+///
+/// Entries come in pre-order, so a directory is complete when an entry
+/// at the same or shallower depth arrives.
+/// Directories outside of the depth window are not printed, but are counted in their parents.
+/// With errors the totals are printed, but do not include the entries not read.
+pub struct DiskUsage {
+    out: Output,
+    count_inode: bool,
+    min_depth: usize,
+    max_depth: Option<usize>,
+    path_format: PathFormat,
+    seen_inodes: HashSet<(u64, u64)>,
+    stack: Vec<DirTotal>,
+    errors: usize,
+}
+
+impl DiskUsage {
+    pub fn new(
+        out: Box<dyn Write + Send>,
+        count_inode: bool,
+        min_depth: usize,
+        max_depth: Option<usize>,
+    ) -> DiskUsage {
+        DiskUsage {
+            out: Output::Text(out),
+            count_inode,
+            min_depth,
+            max_depth,
+            path_format: PathFormat::default(),
+            seen_inodes: HashSet::new(),
+            stack: Vec::new(),
+            errors: 0,
+        }
+    }
+
+    pub fn format(mut self, format: Format) -> Self {
+        self.out = self.out.with_format(format, DU_COLUMNS);
+        self
+    }
+
+    pub fn path_format(mut self, f: PathFormat) -> Self {
+        self.path_format = f;
+        self
+    }
+
+    fn pop(&mut self) -> Result<(), error::E> {
+        let d = self.stack.pop().unwrap();
+        if let Some(parent) = self.stack.last_mut() {
+            parent.bytes += d.bytes;
+        }
+
+        if self.min_depth <= d.depth && self.max_depth.is_none_or(|max| d.depth <= max) {
+            match &mut self.out {
+                Output::Text(out) => {
+                    let mut v = format!("{}\t", d.bytes.div_ceil(1024)).into_bytes();
+                    v.extend_from_slice(&d.path);
+                    v.push(b'\n');
+                    error::maybe_generic_io_error(out.write_all(v.as_slice()))?;
+                }
+                Output::Records(w) => w.write(&Record::du(&d.path, d.bytes))?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Visitor for DiskUsage {
+    fn wants_metadata(&self) -> bool {
+        true
+    }
+
+    fn wants_all_depths(&self) -> bool {
+        true
+    }
+
+    fn requires_order(&self) -> bool {
+        true
+    }
+
+    fn error(&mut self, e: &error::E) -> Result<(), error::E> {
+        self.errors += 1;
+        self.out.error(e)
+    }
+
+    fn visit(&mut self, entry: &Entry) -> Result<(), error::E> {
+        while self.stack.last().is_some_and(|d| d.depth >= entry.depth) {
+            self.pop()?;
+        }
+
+        let st = entry.metadata.as_ref().unwrap();
+        let mut bytes = st.st_blocks as u64 * 512;
+        if self.count_inode
+            && entry.file_type != FileType::Directory
+            && st.st_nlink > 1
+            && !self.seen_inodes.insert((st.st_dev, st.st_ino))
+        {
+            bytes = 0;
+        }
+
+        if entry.file_type == FileType::Directory {
+            let mut path = Vec::new();
+            self.path_format.push(entry, &mut path);
+            self.stack.push(DirTotal {
+                path,
+                depth: entry.depth,
+                bytes,
+            });
+        } else if let Some(parent) = self.stack.last_mut() {
+            parent.bytes += bytes;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), error::E> {
+        while !self.stack.is_empty() {
+            self.pop()?;
+        }
+        self.out.flush()?;
+        if self.errors > 0 {
+            return Err(error::E::Incomplete {
+                errors: self.errors,
+            });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::options::{Method, Order};
+    use crate::printer::test_run;
+
+    fn du(root: &std::path::Path, max_depth: Option<usize>) -> Vec<(u64, String)> {
+        let mut opts = crate::options::test_option(root.to_str().unwrap());
+        opts.order = Order::Alphabetical;
+        opts.method = Method::DU { count_inode: true };
+        opts.max_depth = max_depth;
+
+        let (out, r) = test_run(opts, |out| DiskUsage::new(out, true, 0, max_depth));
+        r.unwrap();
+
+        out.lines()
+            .map(|l| {
+                let (n, p) = l.split_once('\t').unwrap();
+                let p = std::path::Path::new(p).strip_prefix(root).unwrap();
+                (n.parse().unwrap(), p.to_str().unwrap().to_owned())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn max_depth_rollup() {
+        let root = crate::options::test_tree("du", &["a/b/c/x", "a/y", "d/z"]);
+        std::fs::write(root.join("a/b/c/x"), vec![1u8; 100000]).unwrap();
+        std::fs::hard_link(root.join("a/b/c/x"), root.join("d/x")).unwrap();
+
+        let all = du(&root, None);
+        let names: Vec<_> = all.iter().map(|(_, p)| p.as_str()).collect();
+        assert_eq!(names, ["a/b/c", "a/b", "a", "d", ""]);
+        assert!(all[0].0 >= 98);
+        // hard link is counted once
+        assert!(all[3].0 < 98);
+
+        let top = du(&root, Some(1));
+        assert_eq!(top, [all[2].clone(), all[3].clone(), all[4].clone()]);
+
+        let mut opts = crate::options::test_option(root.to_str().unwrap());
+        opts.method = Method::DU { count_inode: true };
+        opts.filter = Some("-type f".into());
+        assert!(matches!(
+            opts.validate(),
+            Err(error::E::InvalidOptionError { .. })
+        ));
+    }
+
+    #[test]
+    fn errors() {
+        let mut v = DiskUsage::new(Box::new(std::io::sink()), false, 0, None);
+        v.error(&error::E::OpenDirError {
+            path: "a".into(),
+            eno: nix::errno::Errno::EACCES,
+        })
+        .unwrap();
+        assert!(matches!(
+            v.finish(),
+            Err(error::E::Incomplete { errors: 1 })
+        ));
+    }
+}
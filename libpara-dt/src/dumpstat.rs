@@ -0,0 +1,147 @@
+use crate::error;
+use crate::pathstr::PathFormat;
+use crate::quote::{self, QuotingStyle};
+use crate::record::{Format, Output, Record, STAT_COLUMNS};
+use crate::template::mode_string;
+use crate::visitor::{Entry, Visitor};
+use std::io::Write;
+
+/// `Method::DumpSTAT`. prints `lstat` of each entry, and extended attributes with `get_xattr`.
+///
+/// The text format is `mode nlink uid gid size mtime path`, where mode is like `ls -l`, mtime is
+/// `{sec}.{nsec:09}` and path is quoted by the quoting style.
+/// A line `\tname=value` follows for each extended attribute, where value is quoted by
+/// `QuotingStyle::C`, e.g. `"a\nb"`, and name is as is.
+pub struct StatDumper {
+    out: Output,
+    get_xattr: bool,
+    quoting_style: QuotingStyle,
+    path_format: PathFormat,
+    buf: Vec<u8>,
+}
+
+impl StatDumper {
+    pub fn new(out: Box<dyn Write + Send>, get_xattr: bool) -> StatDumper {
+        StatDumper {
+            out: Output::Text(out),
+            get_xattr,
+            quoting_style: QuotingStyle::Literal,
+            path_format: PathFormat::default(),
+            buf: Vec::new(),
+        }
+    }
+
+    pub fn format(mut self, format: Format) -> Self {
+        self.out = self.out.with_format(format, STAT_COLUMNS);
+        self
+    }
+
+    pub fn quoting_style(mut self, style: QuotingStyle) -> Self {
+        self.quoting_style = style;
+        self
+    }
+
+    pub fn path_format(mut self, f: PathFormat) -> Self {
+        self.path_format = f;
+        self
+    }
+}
+
+impl Visitor for StatDumper {
+    fn wants_metadata(&self) -> bool {
+        true
+    }
+
+    fn visit(&mut self, entry: &Entry) -> Result<(), error::E> {
+        let st = match entry.metadata.as_ref() {
+            Some(st) => st,
+            None => return Ok(()),
+        };
+        let xattrs = if self.get_xattr {
+            match crate::xattr::get_all(&entry.path) {
+                Ok(x) => x,
+                Err(eno) => {
+                    return self.error(&error::E::XattrError {
+                        path: entry.path.clone(),
+                        eno,
+                    })
+                }
+            }
+        } else {
+            Vec::new()
+        };
+
+        let mut path = Vec::new();
+        self.path_format.push(entry, &mut path);
+
+        let out = match &mut self.out {
+            Output::Text(out) => out,
+            Output::Records(w) => {
+                let mut r = Record::stat(&path, entry, st);
+                if self.get_xattr {
+                    r.xattrs(&xattrs);
+                }
+                return w.write(&r);
+            }
+        };
+
+        self.buf.clear();
+        self.buf.extend_from_slice(
+            format!(
+                "{} {} {} {} {} {}.{:09} ",
+                mode_string(entry.file_type, st.st_mode),
+                st.st_nlink,
+                st.st_uid,
+                st.st_gid,
+                st.st_size,
+                st.st_mtime,
+                st.st_mtime_nsec
+            )
+            .as_bytes(),
+        );
+        quote::quote(&path, self.quoting_style, &mut self.buf);
+        self.buf.push(b'\n');
+        for (name, value) in &xattrs {
+            self.buf.push(b'\t');
+            self.buf.extend_from_slice(name);
+            self.buf.push(b'=');
+            quote::quote(value, QuotingStyle::C, &mut self.buf);
+            self.buf.push(b'\n');
+        }
+        error::maybe_generic_io_error(out.write_all(&self.buf))
+    }
+
+    fn error(&mut self, e: &error::E) -> Result<(), error::E> {
+        self.out.error(e)
+    }
+
+    fn finish(&mut self) -> Result<(), error::E> {
+        self.out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::options::Method;
+    use crate::printer::test_run;
+
+    #[test]
+    fn jsonl() {
+        let root = crate::options::test_tree("dumpstat", &["a"]);
+        std::fs::write(root.join("a"), b"12345").unwrap();
+        let mut opts = crate::options::test_option(root.to_str().unwrap());
+        opts.method = Method::DumpSTAT { get_xattr: false };
+
+        let (out, r) = test_run(opts, |out| {
+            StatDumper::new(out, false).format(Format::Jsonl)
+        });
+        r.unwrap();
+
+        let r: serde_json::Value = serde_json::from_str(out.trim_end()).unwrap();
+        assert_eq!(r["type"], "stat");
+        assert_eq!(r["size"], 5);
+        assert_eq!(r["path"], root.join("a").to_str().unwrap());
+        assert!(r.get("xattrs").is_none());
+    }
+}
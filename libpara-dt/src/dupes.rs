@@ -0,0 +1,311 @@
+//! `Method::Dupes`. finds regular files with the same contents, like `fdupes`.
+//!
+//! Files are collected by the traversal with their size. Then files of the same size are compared
+//! by their first block, and the rest by the hash of the whole contents, each step in parallel.
+//! A file not larger than the block is compared by its bytes, and a larger one by the hash of it.
+//! Empty files are skipped, and hard links of one inode count as one file.
+//! With `--dedup`, the groups are printed and then deduplicated by `dedup::dedup`.
+
+use crate::dedup::{self, DedupMode};
+use crate::error;
+use crate::hash::{self, HashAlgorithm};
+use crate::pathstr::PathFormat;
+use crate::quote::{self, QuotingStyle};
+use crate::record::{Format, Output, Record, DUPES_COLUMNS};
+use crate::visitor::{Entry, FileType, Visitor};
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+/// bytes hashed in the first step
+const HEAD_SIZE: u64 = 4096;
+
+pub struct File {
+    pub path: PathBuf,
+    pub size: u64,
+    /// with `--relative` and `--prefix`
+    pub output_path: Vec<u8>,
+    pub dev: u64,
+    pub nlink: u64,
+    pub uid: u32,
+    pub gid: u32,
+    /// permission bits
+    pub mode: u32,
+}
+
+/// the contents of a small file, or the hash of the first block
+fn head_key(f: &File) -> io::Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    std::fs::File::open(&f.path)?
+        .take(HEAD_SIZE)
+        .read_to_end(&mut buf)?;
+    if f.size <= HEAD_SIZE {
+        return Ok(buf);
+    }
+    hash::hash_reader(HashAlgorithm::Xxh3, &mut buf.as_slice())
+}
+
+fn full_hash(f: &File) -> io::Result<Vec<u8>> {
+    let mut r = std::fs::File::open(&f.path)?;
+    hash::hash_reader(HashAlgorithm::Blake3, &mut r)
+}
+
+/// Split each group by `key` of the files computed in `num_threads` threads.
+/// Groups of one file are dropped. errors are passed to `error`, and the files are dropped.
+fn refine(
+    files: &[File],
+    groups: Vec<Vec<usize>>,
+    num_threads: usize,
+    key: impl Fn(&File) -> io::Result<Vec<u8>> + Sync,
+    mut error: impl FnMut(error::E),
+) -> Vec<Vec<usize>> {
+    let todo: Vec<usize> = groups.iter().flatten().copied().collect();
+    let keys: Vec<Mutex<Option<io::Result<Vec<u8>>>>> =
+        todo.iter().map(|_| Mutex::new(None)).collect();
+    let next = AtomicUsize::new(0);
+    std::thread::scope(|s| {
+        for _ in 0..num_threads {
+            s.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                if i >= todo.len() {
+                    break;
+                }
+                *keys[i].lock().unwrap() = Some(key(&files[todo[i]]));
+            });
+        }
+    });
+
+    let mut keys = keys.into_iter().map(|k| k.into_inner().unwrap());
+    let mut ret = Vec::new();
+    for g in groups {
+        let mut split: HashMap<Vec<u8>, Vec<usize>> = HashMap::new();
+        for i in g {
+            match keys.next().flatten() {
+                Some(Ok(k)) => split.entry(k).or_default().push(i),
+                Some(Err(eno)) => error(error::E::ReadFileError {
+                    path: files[i].path.clone(),
+                    eno,
+                }),
+                None => {}
+            }
+        }
+        ret.extend(split.into_values().filter(|g| g.len() > 1));
+    }
+    // in the order of the first file
+    ret.sort();
+    ret
+}
+
+/// Groups of identical files, as indices into `files` in ascending order.
+/// `files` must not have the same inode twice.
+pub fn find_groups(
+    files: &[File],
+    num_threads: usize,
+    mut error: impl FnMut(error::E),
+) -> Vec<Vec<usize>> {
+    let mut by_size: HashMap<u64, Vec<usize>> = HashMap::new();
+    for (i, f) in files.iter().enumerate() {
+        by_size.entry(f.size).or_default().push(i);
+    }
+    let groups: Vec<Vec<usize>> = by_size.into_values().filter(|g| g.len() > 1).collect();
+    let groups = refine(files, groups, num_threads, head_key, &mut error);
+
+    // the first block was the whole file, and was compared as is
+    let (mut done, large): (Vec<_>, Vec<_>) = groups
+        .into_iter()
+        .partition(|g| files[g[0]].size <= HEAD_SIZE);
+    done.extend(refine(files, large, num_threads, full_hash, &mut error));
+    done.sort();
+    done
+}
+
+pub struct DupeFinder {
+    out: Output,
+    quoting_style: QuotingStyle,
+    path_format: PathFormat,
+    num_threads: usize,
+    dedup: Option<DedupMode>,
+    dry_run: bool,
+    files: Vec<File>,
+    seen_inodes: HashSet<(u64, u64)>,
+    errors: usize,
+}
+
+impl DupeFinder {
+    pub fn new(out: Box<dyn Write + Send>, num_threads: usize) -> DupeFinder {
+        DupeFinder {
+            out: Output::Text(out),
+            quoting_style: QuotingStyle::Literal,
+            path_format: PathFormat::default(),
+            num_threads,
+            dedup: None,
+            dry_run: false,
+            files: Vec::new(),
+            seen_inodes: HashSet::new(),
+            errors: 0,
+        }
+    }
+
+    pub fn quoting_style(mut self, style: QuotingStyle) -> Self {
+        self.quoting_style = style;
+        self
+    }
+
+    pub fn format(mut self, format: Format) -> Self {
+        self.out = self.out.with_format(format, DUPES_COLUMNS);
+        self
+    }
+
+    pub fn path_format(mut self, f: PathFormat) -> Self {
+        self.path_format = f;
+        self
+    }
+
+    /// replace the duplicates after printing them. only count the bytes with `dry_run`
+    pub fn dedup(mut self, mode: Option<DedupMode>, dry_run: bool) -> Self {
+        self.dedup = mode;
+        self.dry_run = dry_run;
+        self
+    }
+
+    fn print_groups(&mut self, groups: &[Vec<usize>]) -> Result<(), error::E> {
+        let mut buf = Vec::new();
+        for (n, g) in groups.iter().enumerate() {
+            buf.clear();
+            if n > 0 {
+                buf.push(b'\n');
+            }
+            for i in g {
+                let f = &self.files[*i];
+                match &mut self.out {
+                    Output::Text(_) => {
+                        quote::quote(&f.output_path, self.quoting_style, &mut buf);
+                        buf.push(b'\n');
+                    }
+                    Output::Records(w) => w.write(&Record::dupe(&f.output_path, n, f.size))?,
+                }
+            }
+            if let Output::Text(out) = &mut self.out {
+                error::maybe_generic_io_error(out.write_all(&buf))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Visitor for DupeFinder {
+    fn wants_metadata(&self) -> bool {
+        true
+    }
+
+    fn visit(&mut self, entry: &Entry) -> Result<(), error::E> {
+        let st = match (&entry.metadata, entry.file_type) {
+            (Some(st), FileType::File) => st,
+            _ => return Ok(()),
+        };
+        if st.st_size == 0 || !self.seen_inodes.insert((st.st_dev, st.st_ino)) {
+            return Ok(());
+        }
+        let mut output_path = Vec::new();
+        self.path_format.push(entry, &mut output_path);
+        self.files.push(File {
+            path: entry.path.clone(),
+            size: st.st_size as u64,
+            output_path,
+            dev: st.st_dev,
+            nlink: st.st_nlink,
+            uid: st.st_uid,
+            gid: st.st_gid,
+            mode: st.st_mode & 0o7777,
+        });
+        Ok(())
+    }
+
+    fn error(&mut self, e: &error::E) -> Result<(), error::E> {
+        self.errors += 1;
+        self.out.error(e)
+    }
+
+    fn finish(&mut self) -> Result<(), error::E> {
+        let mut errors = Vec::new();
+        let groups = find_groups(&self.files, self.num_threads, |e| errors.push(e));
+        for e in &errors {
+            self.errors += 1;
+            self.out.error(e)?;
+        }
+        self.print_groups(&groups)?;
+        self.out.flush()?;
+
+        if let Some(mode) = self.dedup {
+            let mut errors = Vec::new();
+            let s = dedup::dedup(
+                &self.files,
+                &groups,
+                mode,
+                self.dry_run,
+                self.num_threads,
+                |e| errors.push(e),
+            );
+            for e in &errors {
+                self.errors += 1;
+                self.out.error(e)?;
+            }
+            let (files, bytes) = (s.files.into_inner(), s.bytes.into_inner());
+            if self.dry_run {
+                eprintln!("would reclaim {} bytes by replacing {} files", bytes, files);
+            } else {
+                eprintln!("reclaimed {} bytes by replacing {} files", bytes, files);
+            }
+        }
+        if self.errors > 0 {
+            return Err(error::E::Incomplete {
+                errors: self.errors,
+            });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::options::Order;
+    use crate::printer::test_run;
+
+    #[test]
+    fn groups() {
+        let root = crate::options::test_tree("dupes", &["r1/a", "r1/b", "r2/c", "r2/d", "r2/e"]);
+        let big = vec![7u8; 10000];
+        let mut big2 = big.clone();
+        big2[9999] = 8;
+        std::fs::write(root.join("r1/a"), &big).unwrap();
+        std::fs::write(root.join("r2/c"), &big).unwrap();
+        std::fs::write(root.join("r2/d"), &big2).unwrap();
+        std::fs::write(root.join("r1/b"), "same").unwrap();
+        std::fs::write(root.join("r2/e"), "same").unwrap();
+        std::fs::hard_link(root.join("r1/a"), root.join("r2/f")).unwrap();
+        std::fs::write(root.join("r2/g"), "").unwrap();
+        std::fs::write(root.join("r2/h"), "").unwrap();
+
+        let mut opts = crate::options::test_option(root.join("r1").to_str().unwrap());
+        opts.src_paths.push(root.join("r2"));
+        opts.order = Order::Alphabetical;
+        opts.num_threads = 4;
+        let (out, r) = test_run(opts, |out| DupeFinder::new(out, 4));
+        r.unwrap();
+
+        let r = |p: &str| root.join(p).to_str().unwrap().to_owned();
+        assert_eq!(
+            out,
+            format!(
+                "{}\n{}\n\n{}\n{}\n",
+                r("r1/a"),
+                r("r2/c"),
+                r("r1/b"),
+                r("r2/e")
+            )
+        );
+    }
+}
@@ -20,24 +20,142 @@ pub enum E {
         entry_pos: usize,
         eno: nix::errno::Errno,
     },
+    StatError {
+        path: PathBuf,
+        eno: nix::errno::Errno,
+    },
     GenericIOError {
         eno: std::io::Error,
     },
+    XattrError {
+        path: PathBuf,
+        eno: std::io::Error,
+    },
+    OutputFileError {
+        path: PathBuf,
+        eno: std::io::Error,
+    },
+    ReadFileError {
+        path: PathBuf,
+        eno: std::io::Error,
+    },
+    /// an entry was not copied by `Method::CloneDirectory`
+    CopyError {
+        path: PathBuf,
+        eno: std::io::Error,
+    },
+    /// a duplicate was not replaced by `--dedup`
+    DedupError {
+        path: PathBuf,
+        eno: std::io::Error,
+    },
+    RemoveError {
+        path: PathBuf,
+        eno: nix::errno::Errno,
+    },
+    /// chmod, chown or touch of an entry failed
+    ChangeError {
+        op: &'static str,
+        path: PathBuf,
+        eno: nix::errno::Errno,
+    },
+    /// some entries were not removed or changed. the errors are printed already
+    Incomplete {
+        errors: usize,
+    },
+    /// `Method::Diff` found differences. they are printed already
+    Differences {
+        count: usize,
+    },
+    /// the consumer of the traversal went away
+    Cancelled,
+    InvalidOptionError {
+        name: &'static str,
+        reason: String,
+    },
 }
 
 impl E {
     pub fn is_ignorable_error(&self, opts: &Options) -> bool {
         match self {
-            E::OpenDirError { path: _, eno } => match eno {
-                nix::errno::Errno::EACCES => {
-                    eprintln!("ignored error {:?}", self);
-                    opts.ignore_eaccess
-                }
-                _ => false,
-            },
+            E::OpenDirError {
+                path: _,
+                eno: nix::errno::Errno::EACCES,
+            } => opts.ignore_eaccess,
             _ => false,
         }
     }
+
+    /// a directory that is not one any more when opened, e.g. replaced by a symlink
+    pub fn is_replaced_dir(&self) -> bool {
+        matches!(
+            self,
+            E::OpenDirError {
+                eno: nix::errno::Errno::ELOOP | nix::errno::Errno::ENOTDIR,
+                ..
+            }
+        )
+    }
+
+    /// the path the error is about, if any
+    pub fn path(&self) -> Option<&Path> {
+        match self {
+            E::OpenDirError { path, .. }
+            | E::StatError { path, .. }
+            | E::RemoveError { path, .. }
+            | E::ChangeError { path, .. }
+            | E::XattrError { path, .. }
+            | E::OutputFileError { path, .. }
+            | E::ReadFileError { path, .. }
+            | E::DedupError { path, .. }
+            | E::CopyError { path, .. } => Some(path),
+            E::ReadDirError { dirpath, .. } => Some(dirpath),
+            _ => None,
+        }
+    }
+
+    pub fn errno(&self) -> Option<i32> {
+        match self {
+            E::OpenDirError { eno, .. }
+            | E::ReadDirError { eno, .. }
+            | E::StatError { eno, .. }
+            | E::RemoveError { eno, .. }
+            | E::ChangeError { eno, .. } => Some(*eno as i32),
+            E::GenericIOError { eno }
+            | E::XattrError { eno, .. }
+            | E::OutputFileError { eno, .. }
+            | E::ReadFileError { eno, .. }
+            | E::DedupError { eno, .. }
+            | E::CopyError { eno, .. } => eno.raw_os_error(),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for E {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            E::SendFreeThreadError { e } => write!(f, "{}", e),
+            E::RecvFreeThreadError { e } => write!(f, "{}", e),
+            E::OpenDirError { path, eno } => write!(f, "open {:?}: {}", path, eno.desc()),
+            E::ReadDirError { dirpath, eno, .. } => {
+                write!(f, "readdir {:?}: {}", dirpath, eno.desc())
+            }
+            E::StatError { path, eno } => write!(f, "stat {:?}: {}", path, eno.desc()),
+            E::GenericIOError { eno } => write!(f, "{}", eno),
+            E::XattrError { path, eno } => write!(f, "xattr {:?}: {}", path, eno),
+            E::OutputFileError { path, eno } => write!(f, "output {:?}: {}", path, eno),
+            E::ReadFileError { path, eno } => write!(f, "read {:?}: {}", path, eno),
+            E::CopyError { path, eno } => write!(f, "copy {:?}: {}", path, eno),
+            E::DedupError { path, eno } => write!(f, "dedup {:?}: {}", path, eno),
+            E::RemoveError { path, eno } => write!(f, "remove {:?}: {}", path, eno.desc()),
+            E::ChangeError { op, path, eno } => write!(f, "{} {:?}: {}", op, path, eno.desc()),
+            E::Incomplete { errors } => write!(f, "{} entries failed", errors),
+            E::Differences { count } => write!(f, "{} differences", count),
+            E::Cancelled => write!(f, "cancelled"),
+            E::InvalidOptionError { name, reason } => write!(f, "invalid {}: {}", name, reason),
+        }
+    }
 }
 
 impl From<crossbeam_channel::SendError<Sender<Task>>> for E {
@@ -83,3 +201,10 @@ pub fn maybe_generic_io_error<V>(r: Result<V, std::io::Error>) -> Result<V, E> {
         Err(e) => Err(E::GenericIOError { eno: e }),
     }
 }
+
+pub fn invalid_option(name: &'static str, reason: &str) -> E {
+    E::InvalidOptionError {
+        name,
+        reason: reason.to_owned(),
+    }
+}
@@ -1,5 +1,5 @@
 use std::ops::{Deref, DerefMut};
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Condvar, Mutex};
 
 #[derive(Debug)]
 pub struct DepChainV {
@@ -22,7 +22,7 @@ impl DepChainV {
             self.waiter = Some(ret);
         }
 
-        return self.waiter.clone().unwrap();
+        self.waiter.clone().unwrap()
     }
 }
 
@@ -42,6 +42,12 @@ pub struct CompleteTestResult {
     pub wait_chan: Option<WaitChan>,
 }
 
+impl Default for DepChain {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl DepChain {
     pub fn is_completed(&self, get_channel: bool) -> CompleteTestResult {
         match self {
@@ -105,7 +111,7 @@ impl DepChain {
             DepChain::Dummy => {}
             DepChain::Value { v, pred: _ } => {
                 let mut v = v.lock().unwrap();
-                let mut v = v.deref_mut();
+                let v = v.deref_mut();
                 v.completed = true;
 
                 //println!("notify {:?}", v as *const DepChainV);
@@ -131,3 +137,32 @@ impl DepChain {
         }
     }
 }
+
+/// Counts outstanding tasks when there is no chain to wait for.
+#[derive(Debug, Default)]
+pub struct TaskCounter {
+    n: Mutex<usize>,
+    zero: Condvar,
+}
+
+impl TaskCounter {
+    pub fn add(&self) {
+        *self.n.lock().unwrap() += 1;
+    }
+
+    pub fn done(&self) {
+        let mut n = self.n.lock().unwrap();
+        *n -= 1;
+        if *n == 0 {
+            self.zero.notify_all();
+        }
+    }
+
+    /// wait until all added tasks are done
+    pub fn wait(&self) {
+        let mut n = self.n.lock().unwrap();
+        while *n != 0 {
+            n = self.zero.wait(n).unwrap();
+        }
+    }
+}
@@ -0,0 +1,578 @@
+//! find(1) like predicates.
+//!
+//! The expression is written in the syntax of find, for example
+//! `-name '*.rs' -a ! -path '*/target/*' -o -type d -empty`.
+
+use crate::error;
+use crate::visitor::FileType;
+use nix::sys::stat::FileStat;
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+/// `+n`, `-n` or `n` of find
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Cmp {
+    Greater(u64),
+    Less(u64),
+    Equal(u64),
+}
+
+impl Cmp {
+    fn parse(s: &str) -> Option<(Cmp, &str)> {
+        let (f, rest): (fn(u64) -> Cmp, &str) = if let Some(r) = s.strip_prefix('+') {
+            (Cmp::Greater, r)
+        } else if let Some(r) = s.strip_prefix('-') {
+            (Cmp::Less, r)
+        } else {
+            (Cmp::Equal, s)
+        };
+        let ndigits = rest.bytes().take_while(|c| c.is_ascii_digit()).count();
+        if ndigits == 0 {
+            return None;
+        }
+        let n = rest[..ndigits].parse().ok()?;
+        Some((f(n), &rest[ndigits..]))
+    }
+
+    fn test(&self, v: u64) -> bool {
+        match *self {
+            Cmp::Greater(n) => v > n,
+            Cmp::Less(n) => v < n,
+            Cmp::Equal(n) => v == n,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TimeField {
+    Access,
+    Modify,
+    Change,
+}
+
+impl TimeField {
+    fn get(&self, st: &FileStat) -> (i64, i64) {
+        match self {
+            TimeField::Access => (st.st_atime, st.st_atime_nsec),
+            TimeField::Modify => (st.st_mtime, st.st_mtime_nsec),
+            TimeField::Change => (st.st_ctime, st.st_ctime_nsec),
+        }
+    }
+}
+
+/// `-perm mode`, `-perm -mode` and `-perm /mode`
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Perm {
+    Exact(u32),
+    All(u32),
+    Any(u32),
+}
+
+#[derive(Clone, Debug)]
+pub enum Predicate {
+    True,
+    False,
+    /// glob on the file name. (pattern, ignore case)
+    Name(CString, bool),
+    /// glob on the whole path. (pattern, ignore case)
+    Path(CString, bool),
+    /// regex on the whole path
+    Regex(regex::bytes::Regex),
+    Type(Vec<FileType>),
+    /// size in `unit` bytes, rounded up
+    Size {
+        cmp: Cmp,
+        unit: u64,
+    },
+    /// elapsed time from the start of the traversal in `unit` seconds
+    Time {
+        field: TimeField,
+        cmp: Cmp,
+        unit: i64,
+    },
+    /// timestamp is newer than the reference (sec, nsec)
+    Newer {
+        field: TimeField,
+        than: (i64, i64),
+    },
+    Uid(Cmp),
+    Gid(Cmp),
+    Perm(Perm),
+    Empty,
+    Links(Cmp),
+    Not(Box<Predicate>),
+    And(Vec<Predicate>),
+    Or(Vec<Predicate>),
+}
+
+impl Predicate {
+    pub fn needs_stat(&self) -> bool {
+        match self {
+            Predicate::True
+            | Predicate::False
+            | Predicate::Name(..)
+            | Predicate::Path(..)
+            | Predicate::Regex(_)
+            | Predicate::Type(_) => false,
+            Predicate::Size { .. }
+            | Predicate::Time { .. }
+            | Predicate::Newer { .. }
+            | Predicate::Uid(_)
+            | Predicate::Gid(_)
+            | Predicate::Perm(_)
+            | Predicate::Empty
+            | Predicate::Links(_) => true,
+            Predicate::Not(p) => p.needs_stat(),
+            Predicate::And(v) | Predicate::Or(v) => v.iter().any(|p| p.needs_stat()),
+        }
+    }
+}
+
+/// Entry passed to the predicates
+pub struct Target<'a> {
+    pub path: &'a Path,
+    pub file_type: FileType,
+    /// must be filled when `Filter::needs_stat` is true
+    pub metadata: Option<&'a FileStat>,
+}
+
+/// Parsed filter expression
+#[derive(Clone, Debug)]
+pub struct Filter {
+    pred: Predicate,
+    needs_stat: bool,
+    /// reference time of `-mtime` and friends
+    now: i64,
+}
+
+impl Filter {
+    pub fn parse(expr: &str) -> Result<Filter, error::E> {
+        let words = split_words(expr)?;
+        let mut p = Parser {
+            words: &words,
+            pos: 0,
+        };
+        let pred = if words.is_empty() {
+            Predicate::True
+        } else {
+            p.parse_or()?
+        };
+        if p.pos != words.len() {
+            return Err(filter_error(format!("unexpected '{}'", words[p.pos])));
+        }
+
+        Ok(Filter {
+            needs_stat: pred.needs_stat(),
+            pred,
+            now: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|d| d.as_secs() as i64)
+                .unwrap_or(0),
+        })
+    }
+
+    /// matches an entry whose name matches one of the globs.
+    /// a glob containing '/' is matched against the whole path like `-path`
+    pub fn from_globs(globs: &[String]) -> Result<Filter, error::E> {
+        let mut v = Vec::new();
+        for g in globs {
+            let pat =
+                CString::new(g.as_str()).map_err(|_| filter_error("NUL in glob".to_owned()))?;
+            if g.contains('/') {
+                v.push(Predicate::Path(pat, false));
+            } else {
+                v.push(Predicate::Name(pat, false));
+            }
+        }
+
+        Ok(Filter {
+            pred: Predicate::Or(v),
+            needs_stat: false,
+            now: 0,
+        })
+    }
+
+    pub fn needs_stat(&self) -> bool {
+        self.needs_stat
+    }
+
+    pub fn matches(&self, t: &Target) -> bool {
+        self.eval(&self.pred, t)
+    }
+
+    fn eval(&self, p: &Predicate, t: &Target) -> bool {
+        let st = || t.metadata.expect("filter needs stat");
+        match p {
+            Predicate::True => true,
+            Predicate::False => false,
+            Predicate::Name(pat, icase) => match t.path.file_name() {
+                Some(name) => fnmatch(pat, name.as_bytes(), *icase),
+                None => fnmatch(pat, t.path.as_os_str().as_bytes(), *icase),
+            },
+            Predicate::Path(pat, icase) => fnmatch(pat, t.path.as_os_str().as_bytes(), *icase),
+            Predicate::Regex(re) => re.is_match(t.path.as_os_str().as_bytes()),
+            Predicate::Type(types) => types.contains(&t.file_type),
+            Predicate::Size { cmp, unit } => {
+                let size = st().st_size as u64;
+                cmp.test(size.div_ceil(*unit))
+            }
+            Predicate::Time { field, cmp, unit } => {
+                let (sec, _) = field.get(st());
+                let elapsed = (self.now - sec).div_euclid(*unit);
+                elapsed >= 0 && cmp.test(elapsed as u64)
+            }
+            Predicate::Newer { field, than } => field.get(st()) > *than,
+            Predicate::Uid(cmp) => cmp.test(st().st_uid as u64),
+            Predicate::Gid(cmp) => cmp.test(st().st_gid as u64),
+            Predicate::Perm(perm) => {
+                let mode = st().st_mode & 0o7777;
+                match *perm {
+                    Perm::Exact(m) => mode == m,
+                    Perm::All(m) => mode & m == m,
+                    Perm::Any(m) => m == 0 || mode & m != 0,
+                }
+            }
+            Predicate::Empty => match t.file_type {
+                FileType::File => st().st_size == 0,
+                FileType::Directory => std::fs::read_dir(t.path)
+                    .map(|mut d| d.next().is_none())
+                    .unwrap_or(false),
+                _ => false,
+            },
+            Predicate::Links(cmp) => cmp.test(st().st_nlink),
+            Predicate::Not(p) => !self.eval(p, t),
+            Predicate::And(v) => v.iter().all(|p| self.eval(p, t)),
+            Predicate::Or(v) => v.iter().any(|p| self.eval(p, t)),
+        }
+    }
+}
+
+fn fnmatch(pat: &CString, s: &[u8], icase: bool) -> bool {
+    let s = match CString::new(s) {
+        Ok(s) => s,
+        Err(_) => return false,
+    };
+    let flags = if icase { libc::FNM_CASEFOLD } else { 0 };
+    unsafe { libc::fnmatch(pat.as_ptr(), s.as_ptr(), flags) == 0 }
+}
+
+fn filter_error(reason: String) -> error::E {
+    error::E::InvalidOptionError {
+        name: "filter",
+        reason,
+    }
+}
+
+/// split into words like a shell. supports '...', "..." and backslash escape
+pub fn split_words(s: &str) -> Result<Vec<String>, error::E> {
+    let mut ret = Vec::new();
+    let mut cur: Option<String> = None;
+    let mut chars = s.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            ' ' | '\t' | '\n' => {
+                if let Some(w) = cur.take() {
+                    ret.push(w);
+                }
+            }
+            '\'' => {
+                let w = cur.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => w.push(c),
+                        None => return Err(filter_error("unterminated '".to_owned())),
+                    }
+                }
+            }
+            '"' => {
+                let w = cur.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c) => w.push(c),
+                            None => return Err(filter_error("trailing \\".to_owned())),
+                        },
+                        Some(c) => w.push(c),
+                        None => return Err(filter_error("unterminated \"".to_owned())),
+                    }
+                }
+            }
+            '\\' => match chars.next() {
+                Some(c) => cur.get_or_insert_with(String::new).push(c),
+                None => return Err(filter_error("trailing \\".to_owned())),
+            },
+            c => cur.get_or_insert_with(String::new).push(c),
+        }
+    }
+    if let Some(w) = cur.take() {
+        ret.push(w);
+    }
+
+    Ok(ret)
+}
+
+struct Parser<'a> {
+    words: &'a [String],
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&'a str> {
+        self.words.get(self.pos).map(|s| s.as_str())
+    }
+
+    fn next(&mut self) -> Option<&'a str> {
+        let r = self.peek();
+        if r.is_some() {
+            self.pos += 1;
+        }
+        r
+    }
+
+    fn arg(&mut self, op: &str) -> Result<&'a str, error::E> {
+        self.next()
+            .ok_or_else(|| filter_error(format!("missing argument to '{}'", op)))
+    }
+
+    fn parse_or(&mut self) -> Result<Predicate, error::E> {
+        let mut v = vec![self.parse_and()?];
+        while let Some("-o" | "-or") = self.peek() {
+            self.pos += 1;
+            v.push(self.parse_and()?);
+        }
+        Ok(if v.len() == 1 {
+            v.pop().unwrap()
+        } else {
+            Predicate::Or(v)
+        })
+    }
+
+    fn parse_and(&mut self) -> Result<Predicate, error::E> {
+        let mut v = vec![self.parse_not()?];
+        loop {
+            match self.peek() {
+                Some("-a" | "-and") => {
+                    self.pos += 1;
+                }
+                None | Some("-o" | "-or" | ")") => break,
+                _ => {} // implicit and
+            }
+            v.push(self.parse_not()?);
+        }
+        Ok(if v.len() == 1 {
+            v.pop().unwrap()
+        } else {
+            Predicate::And(v)
+        })
+    }
+
+    fn parse_not(&mut self) -> Result<Predicate, error::E> {
+        match self.peek() {
+            Some("!" | "-not") => {
+                self.pos += 1;
+                Ok(Predicate::Not(Box::new(self.parse_not()?)))
+            }
+            _ => self.parse_primary(),
+        }
+    }
+
+    fn parse_cmp(&mut self, op: &str) -> Result<Cmp, error::E> {
+        let a = self.arg(op)?;
+        match Cmp::parse(a) {
+            Some((c, "")) => Ok(c),
+            _ => Err(filter_error(format!(
+                "invalid argument '{}' to '{}'",
+                a, op
+            ))),
+        }
+    }
+
+    fn parse_glob(&mut self, op: &str) -> Result<CString, error::E> {
+        CString::new(self.arg(op)?).map_err(|_| filter_error(format!("NUL in '{}'", op)))
+    }
+
+    fn parse_primary(&mut self) -> Result<Predicate, error::E> {
+        let op = match self.next() {
+            Some(op) => op,
+            None => return Err(filter_error("expression expected".to_owned())),
+        };
+
+        let p = match op {
+            "(" => {
+                let p = self.parse_or()?;
+                if self.next() != Some(")") {
+                    return Err(filter_error("missing ')'".to_owned()));
+                }
+                p
+            }
+            "-true" => Predicate::True,
+            "-false" => Predicate::False,
+            "-name" => Predicate::Name(self.parse_glob(op)?, false),
+            "-iname" => Predicate::Name(self.parse_glob(op)?, true),
+            "-path" | "-wholename" => Predicate::Path(self.parse_glob(op)?, false),
+            "-ipath" | "-iwholename" => Predicate::Path(self.parse_glob(op)?, true),
+            "-regex" | "-iregex" => {
+                let r = self.arg(op)?;
+                let re = regex::bytes::RegexBuilder::new(&format!("^(?:{})$", r))
+                    .case_insensitive(op == "-iregex")
+                    .build()
+                    .map_err(|e| filter_error(e.to_string()))?;
+                Predicate::Regex(re)
+            }
+            "-type" => {
+                let a = self.arg(op)?;
+                let mut types = Vec::new();
+                for t in a.split(',') {
+                    types.push(match t {
+                        "f" => FileType::File,
+                        "d" => FileType::Directory,
+                        "l" => FileType::Symlink,
+                        "p" => FileType::Fifo,
+                        "s" => FileType::Socket,
+                        "c" => FileType::CharacterDevice,
+                        "b" => FileType::BlockDevice,
+                        _ => return Err(filter_error(format!("unknown type '{}'", t))),
+                    });
+                }
+                Predicate::Type(types)
+            }
+            "-size" => {
+                let a = self.arg(op)?;
+                let (cmp, suffix) =
+                    Cmp::parse(a).ok_or_else(|| filter_error(format!("invalid size '{}'", a)))?;
+                let unit = match suffix {
+                    "" | "b" => 512,
+                    "c" => 1,
+                    "w" => 2,
+                    "k" => 1024,
+                    "M" => 1024 * 1024,
+                    "G" => 1024 * 1024 * 1024,
+                    _ => return Err(filter_error(format!("invalid size '{}'", a))),
+                };
+                Predicate::Size { cmp, unit }
+            }
+            "-atime" | "-mtime" | "-ctime" | "-amin" | "-mmin" | "-cmin" => Predicate::Time {
+                field: time_field(op),
+                cmp: self.parse_cmp(op)?,
+                unit: if op.ends_with("min") { 60 } else { 86400 },
+            },
+            "-newer" | "-anewer" | "-cnewer" => {
+                let f = self.arg(op)?;
+                let st = nix::sys::stat::stat(f)
+                    .map_err(|e| filter_error(format!("{}: {}", f, e.desc())))?;
+                Predicate::Newer {
+                    field: time_field(op),
+                    than: (st.st_mtime, st.st_mtime_nsec),
+                }
+            }
+            "-uid" => Predicate::Uid(self.parse_cmp(op)?),
+            "-gid" => Predicate::Gid(self.parse_cmp(op)?),
+            "-user" => {
+                let a = self.arg(op)?;
+                let uid = match a.parse() {
+                    Ok(uid) => uid,
+                    Err(_) => match nix::unistd::User::from_name(a) {
+                        Ok(Some(u)) => u.uid.as_raw(),
+                        _ => return Err(filter_error(format!("unknown user '{}'", a))),
+                    },
+                };
+                Predicate::Uid(Cmp::Equal(uid as u64))
+            }
+            "-group" => {
+                let a = self.arg(op)?;
+                let gid = match a.parse() {
+                    Ok(gid) => gid,
+                    Err(_) => match nix::unistd::Group::from_name(a) {
+                        Ok(Some(g)) => g.gid.as_raw(),
+                        _ => return Err(filter_error(format!("unknown group '{}'", a))),
+                    },
+                };
+                Predicate::Gid(Cmp::Equal(gid as u64))
+            }
+            "-perm" => {
+                let a = self.arg(op)?;
+                let (f, m): (fn(u32) -> Perm, &str) = if let Some(m) = a.strip_prefix('-') {
+                    (Perm::All, m)
+                } else if let Some(m) = a.strip_prefix('/') {
+                    (Perm::Any, m)
+                } else {
+                    (Perm::Exact, a)
+                };
+                let m = u32::from_str_radix(m, 8)
+                    .map_err(|_| filter_error(format!("invalid mode '{}'", a)))?;
+                Predicate::Perm(f(m))
+            }
+            "-empty" => Predicate::Empty,
+            "-links" => Predicate::Links(self.parse_cmp(op)?),
+            _ => return Err(filter_error(format!("unknown predicate '{}'", op))),
+        };
+
+        Ok(p)
+    }
+}
+
+fn time_field(op: &str) -> TimeField {
+    match op.as_bytes()[1] {
+        b'a' => TimeField::Access,
+        b'c' => TimeField::Change,
+        _ => TimeField::Modify,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn m(expr: &str, path: &str, file_type: FileType) -> bool {
+        let f = Filter::parse(expr).unwrap();
+        assert!(!f.needs_stat());
+        f.matches(&Target {
+            path: Path::new(path),
+            file_type,
+            metadata: None,
+        })
+    }
+
+    #[test]
+    fn parse_and_match() {
+        assert!(m("-name '*.rs'", "a/b.rs", FileType::File));
+        assert!(!m("-name '*.rs'", "a.rs/b", FileType::File));
+        assert!(m("-iname '*.RS'", "a/b.rs", FileType::File));
+        assert!(m("-path '*/b/*'", "a/b/c", FileType::File));
+        assert!(m("-regex '.*/[bc]'", "a/c", FileType::File));
+        assert!(!m("-regex '[bc]'", "a/c", FileType::File));
+        assert!(m("-type f,l", "a", FileType::Symlink));
+        assert!(m("-type d -o -name x", "x", FileType::File));
+        assert!(!m("-type d -name x", "x", FileType::File));
+        assert!(m("! ( -type d -o -name y ) -name x", "x", FileType::File));
+        assert!(m("-not -false", "x", FileType::File));
+        assert!(m("", "x", FileType::File));
+
+        assert!(Filter::parse("-size +1k").unwrap().needs_stat());
+        assert!(Filter::parse("-name").is_err());
+        assert!(Filter::parse("( -true").is_err());
+        assert!(Filter::parse("-foo").is_err());
+        assert!(Filter::parse("-mtime x").is_err());
+    }
+
+    #[test]
+    fn stat_predicates() {
+        let st = nix::sys::stat::stat("Cargo.toml").unwrap();
+        let f = |expr: &str| {
+            Filter::parse(expr).unwrap().matches(&Target {
+                path: Path::new("Cargo.toml"),
+                file_type: FileType::File,
+                metadata: Some(&st),
+            })
+        };
+        assert!(f(&format!("-size {}c", st.st_size)));
+        assert!(f("-size -100M -links 1"));
+        assert!(!f("-empty"));
+        assert!(f(&format!("-uid {} -perm /444", st.st_uid)));
+        assert!(f("-mtime -100000 ! -mmin -0"));
+    }
+}
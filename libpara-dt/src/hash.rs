@@ -0,0 +1,279 @@
+//! Hash algorithms for `Method::Checksum` and `Method::TreeHash`, and hashing the contents of files.
+
+use crate::dir::Dir;
+use crate::error;
+use crate::visitor::{Action, Entry, FileType};
+use nix::sys::stat::FileStat;
+use serde::{Deserialize, Serialize};
+use stat_async::read::Reader;
+use std::cell::{OnceCell, RefCell};
+use std::collections::HashMap;
+use std::ffi::{CStr, CString};
+use std::fs::File;
+use std::io::{self, Read};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, Eq, PartialEq)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+pub enum HashAlgorithm {
+    /// like `sha256sum`
+    Sha256,
+    Blake3,
+    /// XXH3 128 bit, like `xxh128sum`. fast, but not cryptographic
+    Xxh3,
+}
+
+impl HashAlgorithm {
+    pub fn name(self) -> &'static str {
+        match self {
+            HashAlgorithm::Sha256 => "sha256",
+            HashAlgorithm::Blake3 => "blake3",
+            HashAlgorithm::Xxh3 => "xxh3",
+        }
+    }
+
+    /// length of the hash in bytes
+    pub fn hash_len(self) -> usize {
+        match self {
+            HashAlgorithm::Sha256 | HashAlgorithm::Blake3 => 32,
+            HashAlgorithm::Xxh3 => 16,
+        }
+    }
+}
+
+pub enum Hasher {
+    Sha256(sha2::Sha256),
+    Blake3(Box<blake3::Hasher>),
+    Xxh3(Box<xxhash_rust::xxh3::Xxh3>),
+}
+
+impl Hasher {
+    pub fn new(a: HashAlgorithm) -> Hasher {
+        use sha2::Digest;
+        match a {
+            HashAlgorithm::Sha256 => Hasher::Sha256(sha2::Sha256::new()),
+            HashAlgorithm::Blake3 => Hasher::Blake3(Box::new(blake3::Hasher::new())),
+            HashAlgorithm::Xxh3 => Hasher::Xxh3(Box::new(xxhash_rust::xxh3::Xxh3::new())),
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        use sha2::Digest;
+        match self {
+            Hasher::Sha256(h) => h.update(data),
+            Hasher::Blake3(h) => {
+                h.update(data);
+            }
+            Hasher::Xxh3(h) => h.update(data),
+        }
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        use sha2::Digest;
+        match self {
+            Hasher::Sha256(h) => h.finalize().to_vec(),
+            Hasher::Blake3(h) => h.finalize().as_bytes().to_vec(),
+            Hasher::Xxh3(h) => h.digest128().to_be_bytes().to_vec(),
+        }
+    }
+}
+
+const READ_BUFFER_SIZE: usize = 256 * 1024;
+
+/// hash of the contents read from `f`
+pub fn hash_reader(a: HashAlgorithm, f: &mut impl Read) -> io::Result<Vec<u8>> {
+    let mut h = Hasher::new(a);
+    let mut buf = vec![0u8; READ_BUFFER_SIZE];
+    loop {
+        match f.read(&mut buf) {
+            Ok(0) => return Ok(h.finish()),
+            Ok(n) => h.update(&buf[..n]),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// size of the blocks read by io_uring
+const URING_BLOCK_SIZE: usize = 64 * 1024;
+
+thread_local! {
+    /// io_uring of the thread, created by the first `hash_file`. `None` when it is not available
+    static READER: OnceCell<Option<RefCell<Reader>>> = const { OnceCell::new() };
+}
+
+/// hash of the contents of `f`. read with io_uring of stat-async, `depth` blocks ahead of the
+/// hashing, or with `read(2)` where io_uring is not available
+pub fn hash_file(a: HashAlgorithm, f: &mut File, depth: usize) -> io::Result<Vec<u8>> {
+    READER.with(|r| {
+        let r = r.get_or_init(|| {
+            let depth = u32::try_from(depth).unwrap_or(u32::MAX);
+            Reader::new(depth, URING_BLOCK_SIZE).ok().map(RefCell::new)
+        });
+        match r {
+            Some(r) => {
+                let mut h = Hasher::new(a);
+                r.borrow_mut().read_all(f.as_raw_fd(), |b| h.update(b))?;
+                Ok(h.finish())
+            }
+            None => hash_reader(a, f),
+        }
+    })
+}
+
+/// open the regular file `name` in `dirfd` without following symlinks
+pub fn open_at(dirfd: RawFd, name: &CStr) -> io::Result<File> {
+    let fd = unsafe {
+        libc::openat(
+            dirfd,
+            name.as_ptr(),
+            libc::O_RDONLY | libc::O_NOFOLLOW | libc::O_CLOEXEC,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let f = unsafe { File::from_raw_fd(fd) };
+    unsafe {
+        libc::posix_fadvise(fd, 0, 0, libc::POSIX_FADV_SEQUENTIAL);
+    }
+    Ok(f)
+}
+
+/// Hashes of regular files, and of symlink targets with `symlinks`, computed by the traverse threads.
+/// They are kept until the entries are visited.
+/// entries hashed ahead of the visitor at most, e.g. while it waits for a large directory in order.
+/// the entries after them are hashed by the visitor
+const MAX_PENDING_HASHES: usize = 65536;
+
+pub struct ContentHashes {
+    pub algorithm: HashAlgorithm,
+    /// blocks of a file read ahead of the hashing
+    pub ioreq_depth: usize,
+    symlinks: bool,
+    done: Mutex<HashMap<PathBuf, io::Result<Vec<u8>>>>,
+}
+
+impl ContentHashes {
+    pub fn new(algorithm: HashAlgorithm, symlinks: bool) -> ContentHashes {
+        ContentHashes {
+            algorithm,
+            ioreq_depth: crate::options::DEFAULT_MAX_IOREQ_DEPTH,
+            symlinks,
+            done: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// the hash of `entry`, computed by the action or now. `None` when it is not hashed
+    pub fn take(&self, entry: &Entry) -> Option<io::Result<Vec<u8>>> {
+        if let Some(r) = self.done.lock().unwrap().remove(&entry.path) {
+            return Some(r);
+        }
+        let path = match CString::new(entry.path.as_os_str().as_bytes()) {
+            Ok(p) => p,
+            Err(e) => return Some(Err(e.into())),
+        };
+        self.hash(libc::AT_FDCWD, &path, entry.file_type)
+    }
+
+    fn hash(&self, dirfd: RawFd, name: &CStr, t: FileType) -> Option<io::Result<Vec<u8>>> {
+        Some(match t {
+            FileType::File => open_at(dirfd, name)
+                .and_then(|mut f| hash_file(self.algorithm, &mut f, self.ioreq_depth)),
+            FileType::Symlink if self.symlinks => nix::fcntl::readlinkat(dirfd, name)
+                .map_err(io::Error::from)
+                .and_then(|t| hash_reader(self.algorithm, &mut t.as_bytes())),
+            _ => return None,
+        })
+    }
+}
+
+impl Action for ContentHashes {
+    fn entry(
+        &self,
+        dir: &Dir,
+        name: &CStr,
+        entry: &Entry,
+        _metadata: Option<&FileStat>,
+    ) -> Result<bool, error::E> {
+        if self.done.lock().unwrap().len() >= MAX_PENDING_HASHES {
+            return Ok(true);
+        }
+        if let Some(r) = dir.with_fd(|fd| self.hash(fd, name, entry.file_type)) {
+            self.done.lock().unwrap().insert(entry.path.clone(), r);
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn known() {
+        let h = |a| crate::record::hex(&hash_reader(a, &mut &b"abc"[..]).unwrap());
+        assert_eq!(
+            h(HashAlgorithm::Sha256),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(
+            h(HashAlgorithm::Blake3),
+            "6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85"
+        );
+        let empty = hash_reader(HashAlgorithm::Xxh3, &mut &b""[..]).unwrap();
+        assert_eq!(
+            crate::record::hex(&empty),
+            "99aa06d3014798d86001c324468d497f"
+        );
+    }
+
+    #[test]
+    fn file() {
+        let dir = crate::options::test_tree("hash_file", &[]);
+        let data: Vec<u8> = (0..1_000_000u32).map(|i| (i % 251) as u8).collect();
+        std::fs::write(dir.join("f"), &data).unwrap();
+        for a in [HashAlgorithm::Sha256, HashAlgorithm::Xxh3] {
+            let mut f = File::open(dir.join("f")).unwrap();
+            assert_eq!(
+                hash_file(a, &mut f, 4).unwrap(),
+                hash_reader(a, &mut &data[..]).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn not_hashed_ahead() {
+        let root = crate::options::test_tree("hash_pending", &["a", "d/"]);
+        std::os::unix::fs::symlink("a", root.join("l")).unwrap();
+        let entry = |p: &str, file_type| Entry {
+            path: root.join(p),
+            file_type,
+            depth: 1,
+            metadata: None,
+            root_len: root.as_os_str().len(),
+        };
+        let hashes = ContentHashes::new(HashAlgorithm::Sha256, false);
+        let h = hash_reader(HashAlgorithm::Sha256, &mut &b"a"[..]).unwrap();
+        assert_eq!(
+            hashes.take(&entry("a", FileType::File)).unwrap().unwrap(),
+            h
+        );
+        assert!(hashes.take(&entry("d", FileType::Directory)).is_none());
+        assert!(hashes.take(&entry("l", FileType::Symlink)).is_none());
+        // replaced by a symlink since it was read
+        assert!(hashes.take(&entry("l", FileType::File)).unwrap().is_err());
+
+        let hashes = ContentHashes::new(HashAlgorithm::Sha256, true);
+        assert_eq!(
+            hashes
+                .take(&entry("l", FileType::Symlink))
+                .unwrap()
+                .unwrap(),
+            h
+        );
+    }
+}
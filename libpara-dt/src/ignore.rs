@@ -0,0 +1,249 @@
+//! `.gitignore` / `.ignore` rules.
+//!
+//! Rules of an ignore file apply to the subtree of the directory containing it.
+//! Deeper files take precedence, and `.ignore` takes precedence over `.gitignore` in the same directory.
+
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+pub const IGNORE_FILE_NAMES: [&str; 2] = [".gitignore", ".ignore"];
+
+#[derive(Debug)]
+struct Rule {
+    re: regex::bytes::Regex,
+    negate: bool,
+    dir_only: bool,
+}
+
+/// Rules from the ignore files in one directory
+#[derive(Debug)]
+pub struct IgnoreRules {
+    base: PathBuf,
+    rules: Vec<Rule>,
+}
+
+impl IgnoreRules {
+    pub fn new(base: PathBuf) -> IgnoreRules {
+        IgnoreRules {
+            base,
+            rules: Vec::new(),
+        }
+    }
+
+    /// add the rules in the contents of an ignore file. invalid lines are skipped
+    pub fn add(&mut self, contents: &[u8]) {
+        for line in contents.split(|c| *c == b'\n') {
+            if let Some(r) = parse_line(line) {
+                self.rules.push(r);
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Some(true) if ignored, Some(false) if whitelisted by `!`, None if no rule matches
+    fn matched(&self, path: &Path, is_dir: bool) -> Option<bool> {
+        let rel = path.strip_prefix(&self.base).ok()?;
+        let rel = rel.as_os_str().as_bytes();
+
+        self.rules
+            .iter()
+            .rev()
+            .find(|r| (is_dir || !r.dir_only) && r.re.is_match(rel))
+            .map(|r| !r.negate)
+    }
+}
+
+/// Rules of the ignore files from the root to the current directory
+#[derive(Debug)]
+pub struct IgnoreStack {
+    rules: IgnoreRules,
+    parent: Option<Arc<IgnoreStack>>,
+}
+
+impl IgnoreStack {
+    pub fn push(parent: Option<Arc<IgnoreStack>>, rules: IgnoreRules) -> Arc<IgnoreStack> {
+        Arc::new(IgnoreStack { rules, parent })
+    }
+
+    pub fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        let mut s = Some(self);
+        while let Some(n) = s {
+            if let Some(ign) = n.rules.matched(path, is_dir) {
+                return ign;
+            }
+            s = n.parent.as_deref();
+        }
+        false
+    }
+}
+
+fn parse_line(line: &[u8]) -> Option<Rule> {
+    let mut line = line.strip_suffix(b"\r").unwrap_or(line);
+    if line.is_empty() || line[0] == b'#' {
+        return None;
+    }
+
+    // trailing spaces are ignored unless escaped
+    while let Some(l) = line.strip_suffix(b" ") {
+        if l.ends_with(b"\\") {
+            break;
+        }
+        line = l;
+    }
+
+    let (negate, line) = match line.strip_prefix(b"!") {
+        Some(l) => (true, l),
+        None => (false, line),
+    };
+    let (dir_only, line) = match line.strip_suffix(b"/") {
+        Some(l) => (true, l),
+        None => (false, line),
+    };
+    if line.is_empty() {
+        return None;
+    }
+
+    // a pattern with a slash is relative to the directory of the ignore file
+    let (anchored, line) = match line.strip_prefix(b"/") {
+        Some(l) => (true, l),
+        None => (line.contains(&b'/'), line),
+    };
+
+    let mut re = String::from("(?-u)^");
+    if !anchored {
+        re.push_str("(?:.*/)?");
+    }
+    re.push_str(&glob_to_regex(line)?);
+    re.push('$');
+
+    let re = regex::bytes::Regex::new(&re).ok()?;
+    Some(Rule {
+        re,
+        negate,
+        dir_only,
+    })
+}
+
+/// translate a gitignore glob into a regex. `**` matches any number of directories
+fn glob_to_regex(glob: &[u8]) -> Option<String> {
+    let mut re = String::new();
+    let mut i = 0;
+
+    while i < glob.len() {
+        let c = glob[i];
+        match c {
+            b'*' if glob.get(i + 1) == Some(&b'*') => {
+                let at_start = i == 0 || glob[i - 1] == b'/';
+                match glob.get(i + 2) {
+                    Some(b'/') if at_start => {
+                        re.push_str("(?:.*/)?");
+                        i += 3;
+                    }
+                    None if at_start => {
+                        re.push_str(".*");
+                        i += 2;
+                    }
+                    _ => {
+                        re.push_str("[^/]*");
+                        i += 2;
+                    }
+                }
+                continue;
+            }
+            b'*' => re.push_str("[^/]*"),
+            b'?' => re.push_str("[^/]"),
+            b'[' => {
+                let end = glob[i + 1..].iter().skip(1).position(|c| *c == b']')? + i + 2;
+                re.push('[');
+                let mut class = &glob[i + 1..end];
+                if let Some(c) = class.strip_prefix(b"!") {
+                    re.push('^');
+                    class = c;
+                }
+                for (j, c) in class.iter().enumerate() {
+                    // `&&`, `--` and `~~` are set operations in regex classes.
+                    // a '-' between two characters is a range
+                    let range = *c == b'-' && j > 0 && j + 1 < class.len();
+                    if !range && b"\\[]&-~^".contains(c) {
+                        re.push('\\');
+                    }
+                    push_byte(&mut re, *c);
+                }
+                re.push(']');
+                i = end;
+            }
+            b'\\' => {
+                i += 1;
+                push_escaped(&mut re, *glob.get(i)?);
+            }
+            c => push_escaped(&mut re, c),
+        }
+        i += 1;
+    }
+
+    Some(re)
+}
+
+fn push_byte(re: &mut String, c: u8) {
+    if c.is_ascii() {
+        re.push(c as char);
+    } else {
+        re.push_str(&format!("\\x{:02x}", c));
+    }
+}
+
+fn push_escaped(re: &mut String, c: u8) {
+    if c.is_ascii() && regex_syntax_char(c) {
+        re.push('\\');
+    }
+    push_byte(re, c);
+}
+
+fn regex_syntax_char(c: u8) -> bool {
+    b"\\.+*?()|[]{}^$#&-~".contains(&c)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gitignore_rules() {
+        let mut top = IgnoreRules::new(PathBuf::from("/r"));
+        top.add(b"# comment\n*.o\n/build\ndoc/\n!keep.o\nfoo/**/bar\n");
+        let top = IgnoreStack::push(None, top);
+
+        let ign = |s: &IgnoreStack, p: &str, d: bool| s.is_ignored(Path::new(p), d);
+        assert!(ign(&top, "/r/a.o", false));
+        assert!(ign(&top, "/r/x/y/a.o", false));
+        assert!(!ign(&top, "/r/x/keep.o", false));
+        assert!(ign(&top, "/r/build", true));
+        assert!(!ign(&top, "/r/x/build", true));
+        assert!(ign(&top, "/r/x/doc", true));
+        assert!(!ign(&top, "/r/x/doc", false));
+        assert!(ign(&top, "/r/foo/bar", false));
+        assert!(ign(&top, "/r/foo/a/b/bar", false));
+        assert!(!ign(&top, "/r/a.c", false));
+
+        let mut sub = IgnoreRules::new(PathBuf::from("/r/x"));
+        sub.add(b"!*.o\n[ab].c\n");
+        let sub = IgnoreStack::push(Some(top.clone()), sub);
+        assert!(!ign(&sub, "/r/x/a.o", false));
+        assert!(ign(&sub, "/r/x/b.c", false));
+        assert!(!ign(&top, "/r/b.c", false));
+
+        let mut sub = IgnoreRules::new(PathBuf::from("/r/y"));
+        sub.add(b"[a&&b]\n[-c-]d\n[e~~f]\n[!^g-i]x\n");
+        let sub = IgnoreStack::push(None, sub);
+        for p in ["a", "&", "b", "-d", "cd", "e", "~", "f", "jx"] {
+            assert!(ign(&sub, &format!("/r/y/{}", p), false), "{}", p);
+        }
+        for p in ["bd", "gx", "hx", "^x"] {
+            assert!(!ign(&sub, &format!("/r/y/{}", p), false), "{}", p);
+        }
+    }
+}
@@ -1,6 +1,28 @@
+pub mod builder;
+pub mod checksum;
+pub mod clone;
+pub mod dedup;
+pub mod diff;
 pub mod dir;
+pub mod du;
+pub mod dumpstat;
+pub mod dupes;
+pub mod error;
 pub mod events;
+pub mod filter;
+pub mod hash;
+pub mod ignore;
+pub mod modify;
+pub mod mounts;
 pub mod options;
 pub mod pathstr;
+pub mod printer;
+pub mod quote;
+pub mod record;
+pub mod remove;
+pub mod sort;
+pub mod template;
 pub mod traverse;
-pub mod error;
+pub mod treehash;
+pub mod visitor;
+pub mod xattr;
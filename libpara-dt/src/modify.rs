@@ -0,0 +1,663 @@
+//! `Method::Chmod`, `Method::Chown` and `Method::Touch`.
+//!
+//! Like `Method::Remove`, entries are changed by the traverse threads in parallel relative to the fd of
+//! their directory, and directories in post-order by the visitor, after the entries below them are read.
+//! Permissions added by `Method::Chmod` are given to a directory before it is read too, so that
+//! e.g. `u+rx` fixes an unreadable directory.
+//! Entries already as requested are not changed. The count of changed entries is printed to stderr.
+
+use crate::dir::Dir;
+use crate::error;
+use crate::options::Method;
+use crate::visitor::{Action, Entry, FileType, Visitor};
+use nix::sys::stat::{FileStat, Mode};
+use nix::sys::time::TimeSpec;
+use nix::unistd::{Gid, Group, Uid, User};
+use nix::NixPath;
+use std::ffi::CStr;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::RawFd;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Op {
+    Add,
+    Remove,
+    Set,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Perm {
+    /// bits of `rwxXst`
+    Bits { bits: u32, cond_x: bool },
+    /// copy of the permissions of `u`, `g` or `o`, by the shift
+    Copy(u32),
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Clause {
+    /// 0 when no `ugoa` is given. the umask applies then
+    who: u32,
+    actions: Vec<(Op, Perm)>,
+}
+
+/// a mode of chmod(1), symbolic or octal
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ModeSpec {
+    Octal(u32),
+    Symbolic(Vec<Clause>),
+}
+
+const WHO_U: u32 = 0o4700;
+const WHO_G: u32 = 0o2070;
+const WHO_O: u32 = 0o1007;
+
+impl ModeSpec {
+    pub fn parse(s: &str) -> Result<ModeSpec, error::E> {
+        let bad = || error::invalid_option("mode", &format!("invalid mode {:?}", s));
+        if !s.is_empty() && s.bytes().all(|c| (b'0'..=b'7').contains(&c)) {
+            let m = u32::from_str_radix(s, 8).map_err(|_| bad())?;
+            if m > 0o7777 {
+                return Err(bad());
+            }
+            return Ok(ModeSpec::Octal(m));
+        }
+
+        let mut clauses = Vec::new();
+        for c in s.split(',') {
+            let c = c.as_bytes();
+            let mut i = 0;
+            let mut who = 0;
+            while i < c.len() {
+                who |= match c[i] {
+                    b'u' => WHO_U,
+                    b'g' => WHO_G,
+                    b'o' => WHO_O,
+                    b'a' => 0o7777,
+                    _ => break,
+                };
+                i += 1;
+            }
+
+            let mut actions = Vec::new();
+            while i < c.len() {
+                let op = match c[i] {
+                    b'+' => Op::Add,
+                    b'-' => Op::Remove,
+                    b'=' => Op::Set,
+                    _ => return Err(bad()),
+                };
+                i += 1;
+                let perm = match c.get(i) {
+                    Some(b'u') => Some(Perm::Copy(6)),
+                    Some(b'g') => Some(Perm::Copy(3)),
+                    Some(b'o') => Some(Perm::Copy(0)),
+                    _ => None,
+                };
+                let perm = match perm {
+                    Some(p) => {
+                        i += 1;
+                        p
+                    }
+                    None => {
+                        let (mut bits, mut cond_x) = (0, false);
+                        while i < c.len() {
+                            bits |= match c[i] {
+                                b'r' => 0o444,
+                                b'w' => 0o222,
+                                b'x' => 0o111,
+                                b'X' => {
+                                    cond_x = true;
+                                    0
+                                }
+                                b's' => 0o6000,
+                                b't' => 0o1000,
+                                _ => break,
+                            };
+                            i += 1;
+                        }
+                        Perm::Bits { bits, cond_x }
+                    }
+                };
+                actions.push((op, perm));
+            }
+            if actions.is_empty() {
+                return Err(bad());
+            }
+            clauses.push(Clause { who, actions });
+        }
+        Ok(ModeSpec::Symbolic(clauses))
+    }
+
+    /// the new mode from the permission bits of `mode`
+    pub fn apply(&self, mode: u32, is_dir: bool, umask: u32) -> u32 {
+        let clauses = match self {
+            ModeSpec::Octal(m) => return *m,
+            ModeSpec::Symbolic(c) => c,
+        };
+        let mut mode = mode & 0o7777;
+        for c in clauses {
+            let (who, mask) = if c.who == 0 {
+                (0o7777, !umask & 0o777 | 0o7000)
+            } else {
+                (c.who, 0o7777)
+            };
+            for (op, perm) in &c.actions {
+                let bits = match *perm {
+                    Perm::Bits { bits, cond_x } => {
+                        let x = cond_x && (is_dir || mode & 0o111 != 0);
+                        bits | if x { 0o111 } else { 0 }
+                    }
+                    Perm::Copy(shift) => ((mode >> shift) & 7) * 0o111,
+                };
+                let bits = bits & who & mask;
+                match op {
+                    Op::Add => mode |= bits,
+                    Op::Remove => mode &= !bits,
+                    Op::Set => mode = (mode & !(who & mask)) | bits,
+                }
+            }
+        }
+        mode
+    }
+}
+
+/// the change applied by a method
+#[derive(Clone, Debug)]
+pub enum Change {
+    Mode {
+        spec: ModeSpec,
+        umask: u32,
+    },
+    Owner {
+        uid: Option<Uid>,
+        gid: Option<Gid>,
+    },
+    /// `None` is not changed
+    Times {
+        atime: Option<TimeSpec>,
+        mtime: Option<TimeSpec>,
+    },
+}
+
+fn parse_owner(s: &str) -> Result<(Option<Uid>, Option<Gid>), error::E> {
+    let bad = |what: &str| error::invalid_option("owner", &format!("invalid {} in {:?}", what, s));
+    let (user, group) = match s.split_once(':') {
+        Some((u, g)) => (u, Some(g)),
+        None => (s, None),
+    };
+    let uid = match user {
+        "" => None,
+        u => Some(match u.parse() {
+            Ok(n) => Uid::from_raw(n),
+            Err(_) => match User::from_name(u) {
+                Ok(Some(u)) => u.uid,
+                _ => return Err(bad("user")),
+            },
+        }),
+    };
+    let gid = match group {
+        None | Some("") => None,
+        Some(g) => Some(match g.parse() {
+            Ok(n) => Gid::from_raw(n),
+            Err(_) => match Group::from_name(g) {
+                Ok(Some(g)) => g.gid,
+                _ => return Err(bad("group")),
+            },
+        }),
+    };
+    if uid.is_none() && gid.is_none() {
+        return Err(bad("owner"));
+    }
+    Ok((uid, gid))
+}
+
+/// `[@]SECONDS[.FRACTION]`
+fn parse_date(s: &str) -> Result<TimeSpec, error::E> {
+    let bad = || error::invalid_option("date", &format!("invalid date {:?}", s));
+    let t = s.strip_prefix('@').unwrap_or(s);
+    let (sec, frac) = t.split_once('.').unwrap_or((t, ""));
+    let sec: i64 = sec.parse().map_err(|_| bad())?;
+    if frac.len() > 9 || !frac.bytes().all(|c| c.is_ascii_digit()) {
+        return Err(bad());
+    }
+    let nsec = format!("{:0<9}", frac).parse::<i64>().map_err(|_| bad())?;
+    Ok(TimeSpec::new(sec, nsec))
+}
+
+impl Change {
+    /// `None` for the other methods
+    pub fn new(method: &Method) -> Result<Option<Change>, error::E> {
+        Ok(Some(match method {
+            Method::Chmod { mode } => Change::Mode {
+                spec: ModeSpec::parse(mode)?,
+                umask: current_umask(),
+            },
+            Method::Chown { owner } => {
+                let (uid, gid) = parse_owner(owner)?;
+                Change::Owner { uid, gid }
+            }
+            Method::Touch {
+                date,
+                reference,
+                atime_only,
+                mtime_only,
+            } => {
+                let (atime, mtime) = match (date, reference) {
+                    (_, Some(r)) => {
+                        let m = std::fs::metadata(r).map_err(|e| {
+                            error::invalid_option("reference", &format!("{:?}: {}", r, e))
+                        })?;
+                        (
+                            TimeSpec::new(m.atime(), m.atime_nsec()),
+                            TimeSpec::new(m.mtime(), m.mtime_nsec()),
+                        )
+                    }
+                    (Some(d), None) => {
+                        let t = parse_date(d)?;
+                        (t, t)
+                    }
+                    (None, None) => {
+                        let now = std::time::SystemTime::now()
+                            .duration_since(std::time::UNIX_EPOCH)
+                            .unwrap_or_default();
+                        let now = TimeSpec::from_duration(now);
+                        (now, now)
+                    }
+                };
+                // -a and -m together are both, like touch
+                let both = atime_only == mtime_only;
+                Change::Times {
+                    atime: (both || *atime_only).then_some(atime),
+                    mtime: (both || *mtime_only).then_some(mtime),
+                }
+            }
+            _ => return Ok(None),
+        }))
+    }
+
+    fn op(&self) -> &'static str {
+        match self {
+            Change::Mode { .. } => "chmod",
+            Change::Owner { .. } => "chown",
+            Change::Times { .. } => "touch",
+        }
+    }
+
+    /// change `path` in `dirfd`, or from the current directory with `None`.
+    /// false when the entry is already as requested, or is skipped
+    fn apply<P: ?Sized + NixPath>(
+        &self,
+        dirfd: Option<RawFd>,
+        path: &P,
+        t: FileType,
+        st: &FileStat,
+    ) -> nix::Result<bool> {
+        match self {
+            Change::Mode { spec, umask } => {
+                // symlinks have no mode of their own on Linux
+                if t == FileType::Symlink {
+                    return Ok(false);
+                }
+                let old = st.st_mode & 0o7777;
+                let new = spec.apply(old, t == FileType::Directory, *umask);
+                if new == old {
+                    return Ok(false);
+                }
+                chmod(dirfd, path, new)?;
+            }
+            Change::Owner { uid, gid } => {
+                let uid = uid.filter(|u| u.as_raw() != st.st_uid);
+                let gid = gid.filter(|g| g.as_raw() != st.st_gid);
+                if uid.is_none() && gid.is_none() {
+                    return Ok(false);
+                }
+                nix::unistd::fchownat(
+                    dirfd,
+                    path,
+                    uid,
+                    gid,
+                    nix::unistd::FchownatFlags::NoFollowSymlink,
+                )?;
+            }
+            Change::Times { atime, mtime } => {
+                let same = |t: &Option<TimeSpec>, sec, nsec| {
+                    t.is_none_or(|t| t.tv_sec() == sec && t.tv_nsec() == nsec)
+                };
+                if same(atime, st.st_atime, st.st_atime_nsec)
+                    && same(mtime, st.st_mtime, st.st_mtime_nsec)
+                {
+                    return Ok(false);
+                }
+                let omit = TimeSpec::from(libc::timespec {
+                    tv_sec: 0,
+                    tv_nsec: libc::UTIME_OMIT,
+                });
+                nix::sys::stat::utimensat(
+                    dirfd,
+                    path,
+                    &atime.unwrap_or(omit),
+                    &mtime.unwrap_or(omit),
+                    nix::sys::stat::UtimensatFlags::NoFollowSymlink,
+                )?;
+            }
+        }
+        Ok(true)
+    }
+
+    /// give a directory the permissions added by `Change::Mode`, before it is read.
+    /// the others are changed by `apply` later
+    fn apply_added<P: ?Sized + NixPath>(
+        &self,
+        dirfd: Option<RawFd>,
+        path: &P,
+        st: &FileStat,
+    ) -> nix::Result<()> {
+        if let Change::Mode { spec, umask } = self {
+            let old = st.st_mode & 0o7777;
+            let added = old | spec.apply(old, true, *umask);
+            if added != old {
+                chmod(dirfd, path, added)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// an entry in `dirfd` is not followed, in case it is replaced by a symlink since it was read.
+/// a root is followed, like chmod(1)
+fn chmod<P: ?Sized + NixPath>(dirfd: Option<RawFd>, path: &P, mode: u32) -> nix::Result<()> {
+    let flags = if dirfd.is_some() {
+        nix::sys::stat::FchmodatFlags::NoFollowSymlink
+    } else {
+        nix::sys::stat::FchmodatFlags::FollowSymlink
+    };
+    nix::sys::stat::fchmodat(dirfd, path, Mode::from_bits_truncate(mode), flags)
+}
+
+pub(crate) fn current_umask() -> u32 {
+    let m = nix::sys::stat::umask(Mode::empty());
+    nix::sys::stat::umask(m);
+    m.bits()
+}
+
+#[derive(Default)]
+struct Stats {
+    entries: AtomicUsize,
+    changed: AtomicUsize,
+    errors: AtomicUsize,
+}
+
+impl Stats {
+    fn error(&self, e: &error::E) {
+        eprintln!("{}", e);
+        self.errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn changed(&self, change: &Change, path: &Path, r: nix::Result<bool>) {
+        self.entries.fetch_add(1, Ordering::Relaxed);
+        match r {
+            Ok(true) => {
+                self.changed.fetch_add(1, Ordering::Relaxed);
+            }
+            Ok(false) => {}
+            Err(eno) => self.error(&error::E::ChangeError {
+                op: change.op(),
+                path: path.to_owned(),
+                eno,
+            }),
+        }
+    }
+}
+
+/// changes entries in the traverse threads
+struct Changer {
+    change: Change,
+    stats: Arc<Stats>,
+}
+
+impl Action for Changer {
+    fn entry(
+        &self,
+        dir: &Dir,
+        name: &CStr,
+        entry: &Entry,
+        _metadata: Option<&FileStat>,
+    ) -> Result<bool, error::E> {
+        // filled by wants_metadata
+        if let Some(st) = &entry.metadata {
+            let r = dir.with_fd(|fd| self.change.apply(Some(fd), name, entry.file_type, st));
+            self.stats.changed(&self.change, &entry.path, r);
+        }
+        Ok(false)
+    }
+
+    /// a failure is reported by `leave_dir`, which changes the directory again
+    fn enter_dir(&self, dir: &Dir, name: &CStr, entry: &Entry) -> Result<(), error::E> {
+        if let Some(st) = &entry.metadata {
+            let _ = dir.with_fd(|fd| self.change.apply_added(Some(fd), name, st));
+        }
+        Ok(())
+    }
+
+    fn leave_dir(&self, dir: &Dir, name: &CStr, entry: &Entry) -> Result<bool, error::E> {
+        self.entry(dir, name, entry, None)
+    }
+}
+
+pub struct Modifier {
+    change: Change,
+    stats: Arc<Stats>,
+}
+
+impl Modifier {
+    pub fn new(change: Change) -> Modifier {
+        Modifier {
+            change,
+            stats: Arc::new(Stats::default()),
+        }
+    }
+}
+
+impl Visitor for Modifier {
+    fn wants_metadata(&self) -> bool {
+        true
+    }
+
+    /// a directory is changed after it is read, so that e.g. `a-r` does not stop the traversal
+    fn requires_post_order(&self) -> bool {
+        true
+    }
+
+    fn action(&self) -> Option<Arc<dyn Action>> {
+        Some(Arc::new(Changer {
+            change: self.change.clone(),
+            stats: self.stats.clone(),
+        }))
+    }
+
+    /// the permissions added to the root directories, like `Changer::enter_dir`
+    fn start(&mut self, roots: &[PathBuf]) -> Result<(), error::E> {
+        for r in roots {
+            if let Ok(st) = nix::sys::stat::stat(r.as_path()) {
+                if FileType::from_stat(&st) == FileType::Directory {
+                    let _ = self.change.apply_added(None, r.as_path(), &st);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// the roots. other directories are changed by `Changer::leave_dir`
+    fn visit(&mut self, entry: &Entry) -> Result<(), error::E> {
+        if let Some(st) = &entry.metadata {
+            let r = self
+                .change
+                .apply(None, entry.path.as_path(), entry.file_type, st);
+            self.stats.changed(&self.change, &entry.path, r);
+        }
+        Ok(())
+    }
+
+    fn error(&mut self, e: &error::E) -> Result<(), error::E> {
+        self.stats.error(e);
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), error::E> {
+        let entries = self.stats.entries.load(Ordering::Relaxed);
+        let changed = self.stats.changed.load(Ordering::Relaxed);
+        let errors = self.stats.errors.load(Ordering::Relaxed);
+        eprintln!("changed {} of {} entries", changed, entries);
+        if errors > 0 {
+            return Err(error::E::Incomplete { errors });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::traverse::{traverse_with_visitor, Traverser};
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn modes() {
+        let m = |s: &str, mode, is_dir| ModeSpec::parse(s).unwrap().apply(mode, is_dir, 0o022);
+        assert_eq!(m("755", 0o600, false), 0o755);
+        assert_eq!(m("u+x,go-w", 0o666, false), 0o744);
+        assert_eq!(m("+w", 0o444, false), 0o644);
+        assert_eq!(m("a+X", 0o644, false), 0o644);
+        assert_eq!(m("a+X", 0o644, true), 0o755);
+        assert_eq!(m("a+X", 0o744, false), 0o755);
+        assert_eq!(m("g=u", 0o640, false), 0o660);
+        assert_eq!(m("o=", 0o777, false), 0o770);
+        assert_eq!(m("u+s,+t", 0o755, true), 0o5755);
+        assert_eq!(m("u=rw,go=r", 0o4777, false), 0o644);
+        assert!(ModeSpec::parse("u+q").is_err());
+        assert!(ModeSpec::parse("u").is_err());
+        assert!(ModeSpec::parse("17777").is_err());
+    }
+
+    #[test]
+    fn chmod_dirs() {
+        let root = crate::options::test_tree("chmod", &["a/b/c", "a/d", "e/"]);
+        let mut opts = crate::options::test_option(root.to_str().unwrap());
+        opts.method = Method::Chmod { mode: "a-r".into() };
+        opts.filter = Some("-type d".into());
+        opts.num_threads = 4;
+
+        let change = Change::new(&opts.method).unwrap().unwrap();
+        let stats = {
+            let v = Modifier::new(change);
+            let stats = v.stats.clone();
+            traverse_with_visitor(&mut Traverser { opt: opts }, Box::new(v)).unwrap();
+            stats
+        };
+        let mode = |p: &str| {
+            std::fs::metadata(root.join(p))
+                .unwrap()
+                .permissions()
+                .mode()
+                & 0o777
+        };
+        assert_eq!(stats.changed.load(Ordering::Relaxed), 4);
+        assert_eq!(mode("a/b") & 0o444, 0);
+        assert_eq!(mode("e") & 0o444, 0);
+        assert_ne!(mode("a/d") & 0o444, 0);
+    }
+
+    #[test]
+    fn touch() {
+        let root = crate::options::test_tree("touch", &["a/b", "c"]);
+        let mut opts = crate::options::test_option(root.to_str().unwrap());
+        opts.method = Method::Touch {
+            date: Some("@1000000000.5".into()),
+            reference: None,
+            atime_only: false,
+            mtime_only: true,
+        };
+        let change = Change::new(&opts.method).unwrap().unwrap();
+        traverse_with_visitor(
+            &mut Traverser { opt: opts },
+            Box::new(Modifier::new(change)),
+        )
+        .unwrap();
+        for p in ["", "a", "a/b", "c"] {
+            let m = std::fs::symlink_metadata(root.join(p)).unwrap();
+            assert_eq!((m.mtime(), m.mtime_nsec()), (1000000000, 500000000));
+            assert_ne!(m.atime(), 1000000000);
+        }
+    }
+
+    #[test]
+    fn chmod_symlink_swap() {
+        let outside = crate::options::test_tree("chmod_swap_outside", &["z"]);
+        let root = crate::options::test_tree("chmod_swap", &["a/x", "b/y"]);
+        let mut opts = crate::options::test_option(root.to_str().unwrap());
+        opts.method = Method::Chmod { mode: "0".into() };
+        opts.order = crate::options::Order::Alphabetical;
+
+        // b is replaced when a/x is changed, before b is opened
+        let (b, target) = (root.join("b"), outside.to_path_buf());
+        let once = std::sync::Once::new();
+        let hook = move |_: &Entry| {
+            once.call_once(|| {
+                std::fs::remove_dir_all(&b).unwrap();
+                std::os::unix::fs::symlink(&target, &b).unwrap();
+            })
+        };
+        let change = Change::new(&opts.method).unwrap().unwrap();
+        let v = crate::visitor::EntryHook {
+            inner: Modifier::new(change),
+            hook: Arc::new(hook),
+        };
+        let r = traverse_with_visitor(&mut Traverser { opt: opts }, Box::new(v));
+        assert!(matches!(r, Err(error::E::Incomplete { .. })));
+        for p in [outside.to_path_buf(), outside.join("z")] {
+            assert_ne!(
+                std::fs::metadata(p).unwrap().permissions().mode() & 0o777,
+                0
+            );
+        }
+        std::fs::set_permissions(&*root, std::fs::Permissions::from_mode(0o700)).unwrap();
+        std::fs::set_permissions(root.join("a"), std::fs::Permissions::from_mode(0o700)).unwrap();
+    }
+
+    #[test]
+    fn chmod_unreadable() {
+        let root = crate::options::test_tree("chmod_unreadable", &["a/b/f"]);
+        let b = root.join("a/b");
+        std::fs::set_permissions(&b, std::fs::Permissions::from_mode(0o000)).unwrap();
+        let mut opts = crate::options::test_option(root.to_str().unwrap());
+        opts.method = Method::Chmod {
+            mode: "u+rwx".into(),
+        };
+
+        // the mode of a/b when a/b/f is changed. also root can read a/b without it
+        let mode_in_b = Arc::new(AtomicUsize::new(0));
+        let hook = {
+            let (b, f, mode_in_b) = (b.clone(), b.join("f"), mode_in_b.clone());
+            move |e: &Entry| {
+                if e.path == f {
+                    let m = std::fs::metadata(&b).unwrap().permissions().mode();
+                    mode_in_b.store(m as usize & 0o777, Ordering::Relaxed);
+                }
+            }
+        };
+        let change = Change::new(&opts.method).unwrap().unwrap();
+        let v = crate::visitor::EntryHook {
+            inner: Modifier::new(change),
+            hook: Arc::new(hook),
+        };
+        traverse_with_visitor(&mut Traverser { opt: opts }, Box::new(v)).unwrap();
+
+        assert_eq!(mode_in_b.load(Ordering::Relaxed), 0o700);
+        let m = std::fs::metadata(&b).unwrap().permissions().mode();
+        assert_eq!(m & 0o777, 0o700);
+        assert!(std::fs::metadata(b.join("f")).unwrap().permissions().mode() & 0o700 == 0o700);
+    }
+}
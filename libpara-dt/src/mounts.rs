@@ -0,0 +1,59 @@
+use crate::error;
+use std::collections::HashMap;
+
+/// Filesystem type of each device, read from `/proc/self/mountinfo`
+#[derive(Debug, Default)]
+pub struct MountTable {
+    fstypes: HashMap<u64, String>,
+}
+
+impl MountTable {
+    pub fn load() -> Result<MountTable, error::E> {
+        let s = error::maybe_generic_io_error(std::fs::read_to_string("/proc/self/mountinfo"))?;
+        Ok(MountTable::parse(&s))
+    }
+
+    /// lines that can not be parsed are skipped
+    pub fn parse(mountinfo: &str) -> MountTable {
+        let mut fstypes = HashMap::new();
+
+        for line in mountinfo.lines() {
+            // 36 35 98:0 /mnt1 /mnt2 rw,noatime master:1 - ext3 /dev/root rw,errors=continue
+            let mut fields = line.split(' ');
+            let dev = fields.nth(2).and_then(|d| d.split_once(':'));
+            let fstype = fields.skip_while(|f| *f != "-").nth(1);
+
+            if let (Some((major, minor)), Some(fstype)) = (dev, fstype) {
+                if let (Ok(major), Ok(minor)) = (major.parse(), minor.parse()) {
+                    fstypes.insert(libc::makedev(major, minor), fstype.to_owned());
+                }
+            }
+        }
+
+        MountTable { fstypes }
+    }
+
+    pub fn fstype(&self, dev: u64) -> Option<&str> {
+        self.fstypes.get(&dev).map(|s| s.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let t = MountTable::parse(
+            "23 28 0:22 / /proc rw,relatime - proc proc rw\n\
+             36 35 98:0 /mnt1 /mnt2 rw,noatime master:1 - ext3 /dev/root rw,errors=continue\n\
+             broken\n",
+        );
+        assert_eq!(t.fstype(libc::makedev(0, 22)), Some("proc"));
+        assert_eq!(t.fstype(libc::makedev(98, 0)), Some("ext3"));
+        assert_eq!(t.fstype(libc::makedev(1, 1)), None);
+
+        let st = nix::sys::stat::stat("/proc/self").unwrap();
+        assert_eq!(MountTable::load().unwrap().fstype(st.st_dev), Some("proc"));
+    }
+}
@@ -1,77 +1,535 @@
-use clap::Parser;
+use crate::clone::Preserve;
+use crate::dedup::DedupMode;
+use crate::diff::Strictness;
+use crate::error;
+use crate::hash::HashAlgorithm;
+use crate::quote::QuotingStyle;
+use crate::record::Format;
+use crate::sort::SortKey;
 use serde::{Deserialize, Serialize};
+use std::ffi::{OsStr, OsString};
+use std::io::Read;
+use std::os::unix::ffi::OsStrExt;
 use std::path::PathBuf;
 
 #[cfg(test)]
 use std::str::FromStr;
 
-#[derive(Clone, Serialize, Deserialize, Debug, clap::Subcommand, Eq, PartialEq)]
+#[derive(Clone, Serialize, Deserialize, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "clap", derive(clap::Subcommand))]
 pub enum Method {
-    ///
+    /// traverse only. nothing is output
     DryRun,
 
     /// dump all file paths. like `find .`
     List,
-    /// count file size. like `du`
+    /// count file size. like `du`. `--filter` is not supported
     DU {
         /// Count each inode object. Uncount second and subsequent hard link.
-        #[arg(long, default_value_t = true)]
+        #[cfg_attr(feature = "clap", arg(long, default_value_t = true))]
         count_inode: bool,
     },
     DumpSTAT {
-        #[arg(long, default_value_t = false)]
+        #[cfg_attr(feature = "clap", arg(long, default_value_t = false))]
         get_xattr: bool,
     },
+    /// remove the entries and the root directories, like `rm -rf`.
+    /// files are unlinked in parallel, and directories are removed after the entries in them
+    Remove {
+        /// print the paths to remove instead of removing them
+        #[cfg_attr(feature = "clap", arg(long))]
+        dry_run: bool,
+    },
+    /// change the mode of the entries, like `chmod -R`. symlinks are skipped
+    Chmod {
+        /// symbolic like `u+x,go-w`, or octal like `644`
+        #[cfg_attr(feature = "clap", arg(long))]
+        mode: String,
+    },
+    /// change the owner and the group of the entries, not following symlinks, like `chown -R`
+    Chown {
+        /// `user`, `user:group` or `:group`, by name or numeric id
+        #[cfg_attr(feature = "clap", arg(long))]
+        owner: String,
+    },
+    /// set the access and modification times of the entries, like `touch -h`. nothing is created
+    Touch {
+        /// seconds since the epoch with an optional fraction, like `@1700000000.5`. default is now
+        #[cfg_attr(feature = "clap", arg(long))]
+        date: Option<String>,
+        /// use the times of the file instead
+        #[cfg_attr(feature = "clap", arg(long, conflicts_with = "date"))]
+        reference: Option<PathBuf>,
+        /// change only the access time
+        #[cfg_attr(feature = "clap", arg(long, short = 'a'))]
+        atime_only: bool,
+        /// change only the modification time
+        #[cfg_attr(feature = "clap", arg(long, short = 'm'))]
+        mtime_only: bool,
+    },
+    /// print the hash of each regular file, like `sha256sum`. symlinks are not followed
+    Checksum {
+        #[cfg_attr(feature = "clap", arg(long, value_enum, default_value_t = HashAlgorithm::Sha256))]
+        algorithm: HashAlgorithm,
+        /// verify the hashes listed in the file instead, like `sha256sum --check`.
+        /// relative paths in it are from the root directory
+        #[cfg_attr(feature = "clap", arg(long))]
+        check: Option<PathBuf>,
+    },
+    /// print one hash of each root directory, from the names, types, modes, symlink targets and
+    /// contents below it. the format of the hash is in `libpara_dt::treehash`
+    TreeHash {
+        #[cfg_attr(feature = "clap", arg(long, value_enum, default_value_t = HashAlgorithm::Sha256))]
+        algorithm: HashAlgorithm,
+        /// print the hash of each directory in `--min-depth`/`--max-depth` too, after the entries in it
+        #[cfg_attr(feature = "clap", arg(long))]
+        per_dir: bool,
+    },
+    /// print groups of regular files with the same contents, separated by an empty line, like `fdupes`.
+    /// empty files are skipped, and hard links of one file are not duplicates
+    Dupes {
+        /// replace the duplicates with the first file of each group on the same filesystem.
+        /// each is compared byte by byte before it is replaced
+        #[cfg_attr(feature = "clap", arg(long, value_enum))]
+        dedup: Option<DedupMode>,
+        /// print the bytes `--dedup` would reclaim instead of replacing files
+        #[cfg_attr(feature = "clap", arg(long, requires = "dedup"))]
+        dry_run: bool,
+    },
+    /// compare the root directory with another directory, like `diff -rq`.
+    /// entries are paired by the relative path, and both are traversed with the same options.
+    /// only the contents are compared unless `--metadata`: regular files by `--compare`,
+    /// the targets of symlinks, and the numbers of devices
+    Diff {
+        #[cfg_attr(feature = "clap", arg(long))]
+        other: PathBuf,
+        /// how regular files are compared
+        #[cfg_attr(feature = "clap", arg(long, value_enum, default_value_t = Strictness::Mtime))]
+        compare: Strictness,
+        /// also report entries of any type, directories too, that differ in mode, owner or group
+        #[cfg_attr(feature = "clap", arg(long))]
+        metadata: bool,
+    },
     CloneDirectory {
-        #[arg(long)]
+        #[cfg_attr(feature = "clap", arg(long))]
         dst: PathBuf,
-        #[arg(long)]
+        #[cfg_attr(feature = "clap", arg(long))]
         use_o_direct: bool,
-        #[arg(long, default_value_t = true)]
+        #[cfg_attr(feature = "clap", arg(long, default_value_t = true))]
         use_fallocate: bool,
-        #[arg(long, default_value_t = 1024*1024)]
+        #[cfg_attr(feature = "clap", arg(long, default_value_t = 1024*1024))]
         buffer_byte_size: u64,
+        /// skip regular files whose size and modification time match at the destination, like `rsync`
+        #[cfg_attr(feature = "clap", arg(long))]
+        incremental: bool,
+        /// delete entries in the destination which are not in the root directory, like `rsync --delete`.
+        /// entries excluded by filters are deleted too when they are only in the destination
+        #[cfg_attr(feature = "clap", arg(long))]
+        delete: bool,
+        /// print a line for each change, in the format of `rsync --itemize-changes`
+        #[cfg_attr(feature = "clap", arg(long))]
+        itemize_changes: bool,
+        /// metadata to copy, separated by `,` like `cp --preserve`.
+        /// directories get it after the entries in them are copied
+        #[cfg_attr(feature = "clap", arg(long, value_enum, value_delimiter = ',', default_values_t = [Preserve::Timestamps]))]
+        preserve: Vec<Preserve>,
     },
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize, clap::ValueEnum, Eq, PartialEq)]
+#[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
 pub enum Order {
     Alphabetical,
     Readdir,
+    /// output entries as soon as they are read, from any thread. `du` uses readdir order instead
     Unordered,
 }
 
-#[derive(Clone, Serialize, Deserialize, Debug, Parser)]
-#[command(about)]
+pub const DEFAULT_READDIR_DIRENT_BUFFER_SIZE: usize = 64;
+pub const DEFAULT_MAX_IOREQ_DEPTH: usize = 32;
+pub const DEFAULT_NUM_THREADS: usize = 4;
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[cfg_attr(feature = "clap", derive(clap::Parser), command(about))]
 pub struct Options {
-    #[arg(long)]
-    pub src_path: PathBuf,
-    #[arg(long, default_value_t = 64)]
+    /// root directories, given after the method (`para-dt list DIR...`). entries are output in this order
+    #[cfg_attr(feature = "clap", arg(global = true))]
+    pub src_paths: Vec<PathBuf>,
+    /// `--src-path DIR`, the flag used before the roots were positional. moved to `src_paths` by the binary
+    #[cfg_attr(feature = "clap", arg(long, hide = true, global = true))]
+    #[serde(skip)]
+    pub src_path: Vec<PathBuf>,
+    /// read more root directories from the file, one per line. `-` for stdin
+    #[cfg_attr(feature = "clap", arg(long))]
+    pub files_from: Option<PathBuf>,
+    /// paths in `--files-from` are separated by NUL instead of newline
+    #[cfg_attr(feature = "clap", arg(long, short = '0'))]
+    pub null: bool,
+    #[cfg_attr(feature = "clap", arg(long, default_value_t = DEFAULT_READDIR_DIRENT_BUFFER_SIZE))]
     pub readdir_dirent_buffer_size: usize,
-    #[arg(long, default_value_t = 32)]
+    #[cfg_attr(feature = "clap", arg(long, default_value_t = DEFAULT_MAX_IOREQ_DEPTH))]
     pub max_ioreq_depth: usize,
-    #[arg(long)]
+    #[cfg_attr(feature = "clap", arg(long))]
     pub follow_symlink: bool,
-    #[arg(long, value_enum, default_value_t = Order::Alphabetical)]
+    #[cfg_attr(feature = "clap", arg(long, value_enum, default_value_t = Order::Alphabetical))]
     pub order: Order,
-    #[arg(long, default_value_t = 4)]
+    /// sort entries of each directory by the key. default is name for `--order alphabetical`
+    #[cfg_attr(feature = "clap", arg(long, value_enum))]
+    pub sort: Option<SortKey>,
+    /// sort directories before other entries
+    #[cfg_attr(feature = "clap", arg(long))]
+    pub dirs_first: bool,
+    /// output the entries in a directory before the directory, like `find -depth`.
+    /// `--order unordered` gives readdir order then
+    #[cfg_attr(feature = "clap", arg(long))]
+    pub post_order: bool,
+    /// reverse the order of `--sort`
+    #[cfg_attr(feature = "clap", arg(long, short = 'r'))]
+    pub reverse: bool,
+    #[cfg_attr(feature = "clap", arg(long, default_value_t = DEFAULT_NUM_THREADS))]
     pub num_threads: usize,
-    #[arg(long, default_value_t = false)]
+    #[cfg_attr(feature = "clap", arg(long, default_value_t = false))]
     pub ignore_eaccess: bool,
+    /// output only entries matching the find(1) style expression. e.g. `-name '*.rs' -o -type d`
+    #[cfg_attr(feature = "clap", arg(long, allow_hyphen_values = true))]
+    pub filter: Option<String>,
+    /// skip entries matching the glob, and do not descend into them. can be repeated
+    #[cfg_attr(feature = "clap", arg(long))]
+    pub exclude: Vec<String>,
+    /// do not descend into directories matching the find(1) style expression
+    #[cfg_attr(feature = "clap", arg(long, allow_hyphen_values = true))]
+    pub prune: Option<String>,
+    /// skip entries ignored by `.gitignore` and `.ignore` files found in the traversal
+    #[cfg_attr(feature = "clap", arg(long))]
+    pub ignore_files: bool,
+    /// do not output entries below this depth, and do not descend further.
+    /// entries in a root directory have depth 1
+    #[cfg_attr(feature = "clap", arg(long))]
+    pub max_depth: Option<usize>,
+    /// do not output entries above this depth. 0 outputs root directories too.
+    /// default is 0 for `du`, `remove`, `chmod`, `chown`, `touch` and `tree-hash`, and 1 for others
+    #[cfg_attr(feature = "clap", arg(long))]
+    pub min_depth: Option<usize>,
+    /// do not descend into directories on other filesystems than their root. like `find -xdev`
+    #[cfg_attr(feature = "clap", arg(long, short = 'x'))]
+    pub one_file_system: bool,
+    /// descend only into directories on the filesystem types. can be repeated
+    #[cfg_attr(feature = "clap", arg(long))]
+    pub include_fstype: Vec<String>,
+    /// skip directories on the filesystem type, e.g. `proc` or `nfs`. can be repeated
+    #[cfg_attr(feature = "clap", arg(long))]
+    pub exclude_fstype: Vec<String>,
+    /// write the output to the file instead of stdout
+    #[cfg_attr(feature = "clap", arg(long, short = 'o'))]
+    pub output: Option<PathBuf>,
+    /// output format. the records of jsonl and csv are described in `libpara_dt::record`
+    #[cfg_attr(feature = "clap", arg(long, value_enum, default_value_t = Format::Text))]
+    pub format: Format,
+    /// terminate output paths with NUL instead of newline, like `find -print0`
+    #[cfg_attr(feature = "clap", arg(long))]
+    pub print0: bool,
+    /// how to quote special characters in output paths
+    #[cfg_attr(feature = "clap", arg(long, value_enum, default_value_t = QuotingStyle::Literal))]
+    pub quoting_style: QuotingStyle,
+    /// output paths relative to their root directory, like `find -printf %P`. the root itself is `.`
+    #[cfg_attr(feature = "clap", arg(long))]
+    pub relative: bool,
+    /// prepend the string to each output path, e.g. `--relative --prefix backup/`
+    #[cfg_attr(feature = "clap", arg(long))]
+    pub prefix: Option<OsString>,
+    /// output entries with the template instead of paths, like `find -printf`. e.g. `'%M %u %s %p\n'`
+    #[cfg_attr(feature = "clap", arg(long))]
+    pub printf: Option<String>,
 
-    #[command(subcommand)]
+    #[cfg_attr(feature = "clap", command(subcommand))]
     pub method: Method,
 }
+
+impl Options {
+    /// options with the same defaults as the command line
+    pub fn new(src_paths: Vec<PathBuf>, method: Method) -> Options {
+        Options {
+            src_paths,
+            src_path: Vec::new(),
+            files_from: None,
+            null: false,
+            readdir_dirent_buffer_size: DEFAULT_READDIR_DIRENT_BUFFER_SIZE,
+            max_ioreq_depth: DEFAULT_MAX_IOREQ_DEPTH,
+            follow_symlink: false,
+            order: Order::Alphabetical,
+            sort: None,
+            dirs_first: false,
+            post_order: false,
+            reverse: false,
+            num_threads: DEFAULT_NUM_THREADS,
+            ignore_eaccess: false,
+            filter: None,
+            exclude: Vec::new(),
+            prune: None,
+            ignore_files: false,
+            max_depth: None,
+            min_depth: None,
+            one_file_system: false,
+            include_fstype: Vec::new(),
+            exclude_fstype: Vec::new(),
+            output: None,
+            format: Format::Text,
+            print0: false,
+            quoting_style: QuotingStyle::Literal,
+            relative: false,
+            prefix: None,
+            printf: None,
+            method,
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), error::E> {
+        if self.src_paths.is_empty() && self.files_from.is_none() {
+            return Err(error::invalid_option("src_paths", "no root directory"));
+        }
+        if self.num_threads == 0 {
+            return Err(error::invalid_option("num_threads", "must be at least 1"));
+        }
+        if self.readdir_dirent_buffer_size == 0 {
+            return Err(error::invalid_option(
+                "readdir_dirent_buffer_size",
+                "must be at least 1",
+            ));
+        }
+        if self.max_ioreq_depth == 0 {
+            return Err(error::invalid_option(
+                "max_ioreq_depth",
+                "must be at least 1",
+            ));
+        }
+        if let Method::CloneDirectory {
+            buffer_byte_size: 0,
+            ..
+        } = self.method
+        {
+            return Err(error::invalid_option(
+                "buffer_byte_size",
+                "must be at least 1",
+            ));
+        }
+        if let Some(f) = &self.filter {
+            crate::filter::Filter::parse(f)?;
+            // the sizes of directories are summed from the entries the visitor gets
+            if let Method::DU { .. } = self.method {
+                return Err(error::invalid_option(
+                    "filter",
+                    "du counts all entries. use --exclude or --prune",
+                ));
+            }
+        }
+        crate::modify::Change::new(&self.method)?;
+        if let Method::Checksum { check: Some(_), .. } = self.method {
+            if self.src_paths.len() > 1 || self.files_from.is_some() {
+                return Err(error::invalid_option(
+                    "check",
+                    "needs one root directory to resolve the paths, not --files-from",
+                ));
+            }
+            if self.format != Format::Text {
+                return Err(error::invalid_option("check", "requires --format text"));
+            }
+        }
+        if let Method::Dupes {
+            dedup: None,
+            dry_run: true,
+        } = self.method
+        {
+            return Err(error::invalid_option("dry_run", "requires --dedup"));
+        }
+        if let Method::CloneDirectory {
+            incremental,
+            ref preserve,
+            ..
+        } = self.method
+        {
+            if self.src_paths.len() > 1 {
+                return Err(error::invalid_option(
+                    "dst",
+                    "copies exactly one root directory",
+                ));
+            }
+            if incremental
+                && !preserve
+                    .iter()
+                    .any(|p| matches!(p, Preserve::Timestamps | Preserve::All))
+            {
+                return Err(error::invalid_option(
+                    "incremental",
+                    "needs --preserve timestamps to compare the modification times",
+                ));
+            }
+        }
+        if let Method::Diff { .. } = self.method {
+            if self.src_paths.len() > 1 {
+                return Err(error::invalid_option(
+                    "other",
+                    "compares exactly one root directory",
+                ));
+            }
+            if self.order != Order::Alphabetical
+                || self.sort.is_some_and(|k| k != SortKey::Name)
+                || self.dirs_first
+                || self.reverse
+            {
+                return Err(error::invalid_option(
+                    "order",
+                    "diff pairs entries in alphabetical order only",
+                ));
+            }
+        }
+        if self.order == Order::Unordered
+            && (self.sort.is_some_and(|k| k != SortKey::None) || self.dirs_first || self.reverse)
+        {
+            return Err(error::invalid_option(
+                "sort",
+                "not available with --order unordered",
+            ));
+        }
+        if let (Some(min), Some(max)) = (self.min_depth, self.max_depth) {
+            if min > max {
+                return Err(error::invalid_option(
+                    "min_depth",
+                    "must not be larger than max_depth",
+                ));
+            }
+        }
+        if let Some(f) = &self.prune {
+            crate::filter::Filter::parse(f)?;
+        }
+        if let Some(t) = &self.printf {
+            crate::template::Template::parse(t)?;
+            if self.format != Format::Text {
+                return Err(error::invalid_option("printf", "requires --format text"));
+            }
+        }
+        Ok(())
+    }
+
+    /// `min_depth`, or the default of the method
+    pub fn default_min_depth(&self) -> usize {
+        self.min_depth.unwrap_or(match self.method {
+            Method::DU { .. }
+            | Method::Remove { .. }
+            | Method::Chmod { .. }
+            | Method::Chown { .. }
+            | Method::Touch { .. }
+            | Method::TreeHash { .. }
+            | Method::CloneDirectory { .. } => 0,
+            _ => 1,
+        })
+    }
+
+    /// `src_paths` followed by the paths listed in `files_from`
+    pub fn root_paths(&self) -> Result<Vec<PathBuf>, error::E> {
+        let mut ret = self.src_paths.clone();
+
+        if let Some(f) = &self.files_from {
+            let mut buf = Vec::new();
+            if f.as_os_str() == "-" {
+                error::maybe_generic_io_error(std::io::stdin().lock().read_to_end(&mut buf))?;
+            } else {
+                buf = error::maybe_generic_io_error(std::fs::read(f))?;
+            }
+
+            let sep = if self.null { b'\0' } else { b'\n' };
+            for p in buf.split(|c| *c == sep) {
+                if !p.is_empty() {
+                    ret.push(PathBuf::from(OsStr::from_bytes(p)));
+                }
+            }
+        }
+
+        Ok(ret)
+    }
+}
+
 #[cfg(test)]
 pub fn test_option(path: &str) -> Options {
-    Options {
-        src_path: PathBuf::from_str(path).unwrap(),
-        readdir_dirent_buffer_size: 64,
-        max_ioreq_depth: 32,
-        follow_symlink: false,
-        order: Order::Unordered,
-        num_threads: 1,
-        method: Method::List,
-        ignore_eaccess: true,
+    crate::builder::TraverserBuilder::new(PathBuf::from_str(path).unwrap())
+        .order(Order::Unordered)
+        .num_threads(1)
+        .ignore_eaccess(true)
+        .build()
+        .unwrap()
+        .opt
+}
+
+/// a temporary directory for tests, removed with its contents when dropped,
+/// so it is cleaned up also after a failed assertion
+#[cfg(test)]
+pub struct TestDir(PathBuf);
+
+#[cfg(test)]
+impl TestDir {
+    /// a fresh path under the temporary directory. it is not created
+    pub fn new(name: &str) -> TestDir {
+        let root =
+            std::env::temp_dir().join(format!("libpara-dt-test-{}-{}", std::process::id(), name));
+        let d = TestDir(root);
+        d.remove();
+        d
+    }
+
+    fn remove(&self) {
+        use std::os::unix::fs::PermissionsExt;
+        if std::fs::remove_dir_all(&self.0).is_ok() {
+            return;
+        }
+        // tests may leave directories without write permission
+        let mut dirs = vec![self.0.clone()];
+        while let Some(d) = dirs.pop() {
+            let _ = std::fs::set_permissions(&d, std::fs::Permissions::from_mode(0o700));
+            for e in std::fs::read_dir(&d).into_iter().flatten().flatten() {
+                if e.file_type().is_ok_and(|t| t.is_dir()) {
+                    dirs.push(e.path());
+                }
+            }
+        }
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+#[cfg(test)]
+impl std::ops::Deref for TestDir {
+    type Target = std::path::Path;
+
+    fn deref(&self) -> &std::path::Path {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+impl AsRef<std::path::Path> for TestDir {
+    fn as_ref(&self) -> &std::path::Path {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        self.remove();
+    }
+}
+
+/// create files under a fresh temporary directory. a path ending with '/' is a directory
+#[cfg(test)]
+pub fn test_tree(name: &str, files: &[&str]) -> TestDir {
+    let root = TestDir::new(name);
+    std::fs::create_dir_all(&*root).unwrap();
+    for f in files {
+        let p = root.join(f);
+        if f.ends_with('/') {
+            std::fs::create_dir_all(&p).unwrap();
+        } else {
+            std::fs::create_dir_all(p.parent().unwrap()).unwrap();
+            std::fs::write(&p, f.as_bytes()).unwrap();
+        }
     }
+    root
 }
@@ -1,8 +1,42 @@
 use std::ffi::OsStr;
 use std::os::unix::ffi::OsStrExt;
 
-pub fn entry_to_path<'a>(e: &'a nix::dir::Entry) -> &'a std::path::Path {
+pub fn entry_to_path(e: &nix::dir::Entry) -> &std::path::Path {
     let name = e.file_name();
     let osstr_name = OsStr::from_bytes(name.to_bytes());
     std::path::Path::new(osstr_name)
 }
+
+/// How entry paths are output. `--relative` and `--prefix`
+#[derive(Clone, Debug, Default)]
+pub struct PathFormat {
+    /// relative to the root directory. the root itself is `.`
+    pub relative: bool,
+    /// prepended to each path as is
+    pub prefix: Vec<u8>,
+}
+
+impl PathFormat {
+    pub fn new(opts: &crate::options::Options) -> PathFormat {
+        PathFormat {
+            relative: opts.relative,
+            prefix: opts
+                .prefix
+                .as_ref()
+                .map(|p| p.as_bytes().to_vec())
+                .unwrap_or_default(),
+        }
+    }
+
+    /// append the output path of `entry` to `out`
+    pub fn push(&self, entry: &crate::visitor::Entry, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.prefix);
+        if !self.relative {
+            out.extend_from_slice(entry.path.as_os_str().as_bytes());
+        } else if entry.depth == 0 {
+            out.push(b'.');
+        } else {
+            out.extend_from_slice(entry.relative_path().as_os_str().as_bytes());
+        }
+    }
+}
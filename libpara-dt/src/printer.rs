@@ -0,0 +1,304 @@
+use crate::checksum::Checksummer;
+use crate::clone::Cloner;
+use crate::diff::Differ;
+use crate::du::DiskUsage;
+use crate::dumpstat::StatDumper;
+use crate::dupes::DupeFinder;
+use crate::error;
+use crate::modify::{Change, Modifier};
+use crate::options::{Method, Options};
+use crate::pathstr::PathFormat;
+use crate::quote::{self, QuotingStyle};
+use crate::record::{Format, Output, Record, LIST_COLUMNS};
+use crate::remove::Remover;
+use crate::template::{Renderer, Template};
+use crate::treehash::TreeHasher;
+use crate::visitor::{Entry, NullVisitor, Visitor};
+use std::io::{BufWriter, Write};
+
+/// print one path per line. used by `Method::List`
+pub struct PathPrinter {
+    out: Output,
+    quoting_style: QuotingStyle,
+    path_format: PathFormat,
+    terminator: u8,
+    path: Vec<u8>,
+    buf: Vec<u8>,
+}
+
+impl PathPrinter {
+    pub fn new(out: Box<dyn Write + Send>) -> PathPrinter {
+        PathPrinter {
+            out: Output::Text(out),
+            quoting_style: QuotingStyle::Literal,
+            path_format: PathFormat::default(),
+            terminator: b'\n',
+            path: Vec::new(),
+            buf: Vec::new(),
+        }
+    }
+
+    pub fn quoting_style(mut self, style: QuotingStyle) -> Self {
+        self.quoting_style = style;
+        self
+    }
+
+    pub fn format(mut self, format: Format) -> Self {
+        self.out = self.out.with_format(format, LIST_COLUMNS);
+        self
+    }
+
+    pub fn path_format(mut self, f: PathFormat) -> Self {
+        self.path_format = f;
+        self
+    }
+
+    /// terminate paths with NUL instead of newline
+    pub fn print0(mut self, b: bool) -> Self {
+        self.terminator = if b { b'\0' } else { b'\n' };
+        self
+    }
+}
+
+impl Visitor for PathPrinter {
+    fn visit(&mut self, entry: &Entry) -> Result<(), error::E> {
+        self.path.clear();
+        self.path_format.push(entry, &mut self.path);
+        match &mut self.out {
+            Output::Text(out) => {
+                self.buf.clear();
+                quote::quote(&self.path, self.quoting_style, &mut self.buf);
+                self.buf.push(self.terminator);
+                error::maybe_generic_io_error(out.write_all(self.buf.as_slice()))
+            }
+            Output::Records(w) => w.write(&Record::entry(&self.path, entry)),
+        }
+    }
+
+    fn error(&mut self, e: &error::E) -> Result<(), error::E> {
+        self.out.error(e)
+    }
+
+    fn finish(&mut self) -> Result<(), error::E> {
+        self.out.flush()
+    }
+}
+
+/// print entries with a `--printf` template. used by `Method::List`
+pub struct TemplatePrinter {
+    out: Box<dyn Write + Send>,
+    renderer: Renderer,
+    buf: Vec<u8>,
+}
+
+impl TemplatePrinter {
+    pub fn new(out: Box<dyn Write + Send>, renderer: Renderer) -> TemplatePrinter {
+        TemplatePrinter {
+            out,
+            renderer,
+            buf: Vec::new(),
+        }
+    }
+}
+
+impl Visitor for TemplatePrinter {
+    fn wants_metadata(&self) -> bool {
+        self.renderer.needs_stat()
+    }
+
+    fn visit(&mut self, entry: &Entry) -> Result<(), error::E> {
+        self.buf.clear();
+        self.renderer.render(entry, &mut self.buf);
+        error::maybe_generic_io_error(self.out.write_all(self.buf.as_slice()))
+    }
+
+    fn finish(&mut self) -> Result<(), error::E> {
+        error::maybe_generic_io_error(self.out.flush())
+    }
+}
+
+const OUTPUT_BUFFER_SIZE: usize = 64 * 1024;
+
+/// buffered stdout, or the file of `--output`. printers flush it in `finish`
+pub fn output(opts: &Options) -> Result<Box<dyn Write + Send>, error::E> {
+    Ok(match &opts.output {
+        Some(path) => {
+            let f = std::fs::File::create(path).map_err(|eno| error::E::OutputFileError {
+                path: path.clone(),
+                eno,
+            })?;
+            Box::new(BufWriter::with_capacity(OUTPUT_BUFFER_SIZE, f))
+        }
+        None => Box::new(BufWriter::with_capacity(
+            OUTPUT_BUFFER_SIZE,
+            std::io::stdout(),
+        )),
+    })
+}
+
+pub fn default_visitor(opts: &Options) -> Result<Box<dyn Visitor>, error::E> {
+    let out = output(opts)?;
+    Ok(match opts.method {
+        Method::List if opts.printf.is_some() => {
+            // validated by Options::validate
+            let t = Template::parse(opts.printf.as_deref().unwrap()).unwrap();
+            Box::new(TemplatePrinter::new(
+                out,
+                Renderer::new(t, opts.quoting_style).path_format(PathFormat::new(opts)),
+            ))
+        }
+        Method::List => Box::new(
+            PathPrinter::new(out)
+                .quoting_style(opts.quoting_style)
+                .format(opts.format)
+                .path_format(PathFormat::new(opts))
+                .print0(opts.print0),
+        ),
+        Method::DU { count_inode } => Box::new(
+            DiskUsage::new(out, count_inode, opts.default_min_depth(), opts.max_depth)
+                .format(opts.format)
+                .path_format(PathFormat::new(opts)),
+        ),
+        Method::DumpSTAT { get_xattr } => Box::new(
+            StatDumper::new(out, get_xattr)
+                .quoting_style(opts.quoting_style)
+                .format(opts.format)
+                .path_format(PathFormat::new(opts)),
+        ),
+        Method::Remove { dry_run: false } => Box::new(Remover::new(opts.one_file_system)),
+        Method::Remove { dry_run: true } => Box::new(
+            Remover::new(opts.one_file_system).dry_run(
+                PathPrinter::new(out)
+                    .quoting_style(opts.quoting_style)
+                    .format(opts.format)
+                    .path_format(PathFormat::new(opts))
+                    .print0(opts.print0),
+            ),
+        ),
+        Method::Chmod { .. } | Method::Chown { .. } | Method::Touch { .. } => {
+            // validated by Options::validate
+            Box::new(Modifier::new(Change::new(&opts.method)?.unwrap()))
+        }
+        Method::Checksum { algorithm, .. } => Box::new(
+            Checksummer::new(out, algorithm)
+                .format(opts.format)
+                .path_format(PathFormat::new(opts))
+                .ioreq_depth(opts.max_ioreq_depth),
+        ),
+        Method::TreeHash { algorithm, per_dir } => {
+            let v = TreeHasher::new(out, algorithm)
+                .format(opts.format)
+                .path_format(PathFormat::new(opts))
+                .ioreq_depth(opts.max_ioreq_depth);
+            Box::new(if per_dir {
+                v.per_dir(opts.default_min_depth(), opts.max_depth)
+            } else {
+                v
+            })
+        }
+        Method::Dupes { dedup, dry_run } => Box::new(
+            DupeFinder::new(out, opts.num_threads)
+                .quoting_style(opts.quoting_style)
+                .format(opts.format)
+                .path_format(PathFormat::new(opts))
+                .dedup(dedup, dry_run),
+        ),
+        Method::Diff {
+            ref other,
+            compare,
+            metadata,
+        } => Box::new(
+            Differ::new(out, opts, other, compare)
+                .metadata(metadata)
+                .quoting_style(opts.quoting_style)
+                .format(opts.format)
+                .path_format(PathFormat::new(opts)),
+        ),
+        Method::CloneDirectory {
+            ref dst,
+            use_o_direct,
+            use_fallocate,
+            buffer_byte_size,
+            incremental,
+            delete,
+            itemize_changes,
+            ref preserve,
+        } => Box::new(
+            Cloner::new(out, dst)
+                .use_o_direct(use_o_direct)
+                .use_fallocate(use_fallocate)
+                .buffer_size(buffer_byte_size)
+                .incremental(incremental)
+                .delete(delete)
+                .itemize(itemize_changes)
+                .preserve(preserve)
+                .quoting_style(opts.quoting_style)
+                .format(opts.format),
+        ),
+        _ => Box::new(NullVisitor),
+    })
+}
+
+/// `Write` to a shared buffer, to check output in tests
+#[cfg(test)]
+#[derive(Clone, Default)]
+pub struct SharedBuf(pub std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+#[cfg(test)]
+impl SharedBuf {
+    pub fn string(&self) -> String {
+        String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+    }
+}
+
+#[cfg(test)]
+impl Write for SharedBuf {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// run the visitor made by `visitor` over `opts`, with its output in a buffer.
+/// returns the output and the result of the traversal
+#[cfg(test)]
+pub fn test_run<V: Visitor + 'static>(
+    opts: Options,
+    visitor: impl FnOnce(Box<dyn Write + Send>) -> V,
+) -> (String, Result<(), error::E>) {
+    let buf = SharedBuf::default();
+    let v = visitor(Box::new(buf.clone()));
+    let r = crate::traverse::traverse_with_visitor(
+        &mut crate::traverse::Traverser { opt: opts },
+        Box::new(v),
+    );
+    (buf.string(), r)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::options::Order;
+
+    #[test]
+    fn relative_prefix() {
+        let root = crate::options::test_tree("relative", &["a/b", "c d"]);
+        let mut opts = crate::options::test_option(root.to_str().unwrap());
+        opts.order = Order::Alphabetical;
+        opts.min_depth = Some(0);
+        opts.relative = true;
+        opts.prefix = Some("x/".into());
+
+        let path_format = PathFormat::new(&opts);
+        let (out, r) = test_run(opts, |out| {
+            PathPrinter::new(out)
+                .quoting_style(QuotingStyle::ShellEscape)
+                .path_format(path_format)
+        });
+        r.unwrap();
+        assert_eq!(out, "x/.\nx/a\nx/a/b\n'x/c d'\n");
+    }
+}
@@ -0,0 +1,175 @@
+//! Quoting of file names in the output, like `ls --quoting-style`.
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, Eq, PartialEq)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+pub enum QuotingStyle {
+    /// output names as is
+    Literal,
+    /// quote for shell only if needed. control characters and invalid UTF-8 use `$'...'`
+    ShellEscape,
+    /// same as shell-escape, but always quote
+    ShellEscapeAlways,
+    /// C string literal with double quotes
+    C,
+    /// C escapes without quotes. spaces are escaped too
+    Escape,
+}
+
+/// quote `name` into `out`
+pub fn quote(name: &[u8], style: QuotingStyle, out: &mut Vec<u8>) {
+    match style {
+        QuotingStyle::Literal => out.extend_from_slice(name),
+        QuotingStyle::ShellEscape => shell_escape(name, false, out),
+        QuotingStyle::ShellEscapeAlways => shell_escape(name, true, out),
+        QuotingStyle::C => {
+            out.push(b'"');
+            c_escape(name, b"\"", out);
+            out.push(b'"');
+        }
+        QuotingStyle::Escape => c_escape(name, b" ", out),
+    }
+}
+
+fn c_escape_char(c: u8) -> Option<u8> {
+    Some(match c {
+        0x07 => b'a',
+        0x08 => b'b',
+        0x0c => b'f',
+        b'\n' => b'n',
+        b'\r' => b'r',
+        b'\t' => b't',
+        0x0b => b'v',
+        _ => return None,
+    })
+}
+
+fn push_escaped_byte(c: u8, out: &mut Vec<u8>) {
+    match c_escape_char(c) {
+        Some(e) => out.extend_from_slice(&[b'\\', e]),
+        None => out.extend_from_slice(format!("\\{:03o}", c).as_bytes()),
+    }
+}
+
+/// backslash escape for control characters, invalid UTF-8, `\` and `extra`
+fn c_escape(name: &[u8], extra: &[u8], out: &mut Vec<u8>) {
+    for chunk in name.utf8_chunks() {
+        for ch in chunk.valid().chars() {
+            if ch.is_control() {
+                let mut b = [0; 4];
+                for c in ch.encode_utf8(&mut b).bytes() {
+                    push_escaped_byte(c, out);
+                }
+            } else if ch == '\\' || (ch.is_ascii() && extra.contains(&(ch as u8))) {
+                out.push(b'\\');
+                out.push(ch as u8);
+            } else {
+                let mut b = [0; 4];
+                out.extend_from_slice(ch.encode_utf8(&mut b).as_bytes());
+            }
+        }
+        for c in chunk.invalid() {
+            push_escaped_byte(*c, out);
+        }
+    }
+}
+
+fn shell_safe(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || b"%+,-./:=@_^".contains(&c) || c >= 0x80
+}
+
+fn shell_escape(name: &[u8], always: bool, out: &mut Vec<u8>) {
+    let printable = name
+        .utf8_chunks()
+        .all(|c| c.invalid().is_empty() && !c.valid().chars().any(|ch| ch.is_control()));
+
+    if printable {
+        if !always && !name.is_empty() && name.iter().all(|c| shell_safe(*c)) && name[0] != b'~' {
+            out.extend_from_slice(name);
+            return;
+        }
+        single_quote(name, out);
+        return;
+    }
+
+    // 'printable part'$'\n''printable part'
+    let mut run = Vec::new();
+    let mut esc = Vec::new();
+    for chunk in name.utf8_chunks() {
+        for ch in chunk.valid().chars() {
+            let mut b = [0; 4];
+            let bytes = ch.encode_utf8(&mut b).as_bytes();
+            if ch.is_control() {
+                flush_quoted(&mut run, out);
+                esc.extend_from_slice(bytes);
+            } else {
+                flush_escaped(&mut esc, out);
+                run.extend_from_slice(bytes);
+            }
+        }
+        if !chunk.invalid().is_empty() {
+            flush_quoted(&mut run, out);
+            esc.extend_from_slice(chunk.invalid());
+        }
+    }
+    flush_quoted(&mut run, out);
+    flush_escaped(&mut esc, out);
+}
+
+fn flush_quoted(run: &mut Vec<u8>, out: &mut Vec<u8>) {
+    if !run.is_empty() {
+        single_quote(run, out);
+        run.clear();
+    }
+}
+
+fn flush_escaped(esc: &mut Vec<u8>, out: &mut Vec<u8>) {
+    if !esc.is_empty() {
+        out.extend_from_slice(b"$'");
+        for c in esc.iter() {
+            push_escaped_byte(*c, out);
+        }
+        out.push(b'\'');
+        esc.clear();
+    }
+}
+
+fn single_quote(s: &[u8], out: &mut Vec<u8>) {
+    out.push(b'\'');
+    for c in s {
+        if *c == b'\'' {
+            out.extend_from_slice(b"'\\''");
+        } else {
+            out.push(*c);
+        }
+    }
+    out.push(b'\'');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn q(name: &[u8], style: QuotingStyle) -> String {
+        let mut v = Vec::new();
+        quote(name, style, &mut v);
+        String::from_utf8(v).unwrap()
+    }
+
+    #[test]
+    fn styles() {
+        use QuotingStyle::*;
+        assert_eq!(q(b"a/b.c", ShellEscape), "a/b.c");
+        assert_eq!(q(b"a/b.c", ShellEscapeAlways), "'a/b.c'");
+        assert_eq!(q(b"a b", ShellEscape), "'a b'");
+        assert_eq!(q(b"it's", ShellEscape), "'it'\\''s'");
+        assert_eq!(q(b"a\nb", ShellEscape), "'a'$'\\n''b'");
+        assert_eq!(q(b"\xff\xfex", ShellEscape), "$'\\377\\376''x'");
+        assert_eq!(q("日本".as_bytes(), ShellEscape), "日本");
+
+        assert_eq!(q(b"a \"b\"\t\\\x01", C), "\"a \\\"b\\\"\\t\\\\\\001\"");
+        assert_eq!(q(b"a b\n", Escape), "a\\ b\\n");
+        assert_eq!(q(b"\xff", Escape), "\\377");
+    }
+}
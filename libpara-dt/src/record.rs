@@ -0,0 +1,412 @@
+//! Machine readable output for `--format jsonl` and `--format csv`.
+//!
+//! Every record has the schema version `v` (currently 1) and its `type`.
+//! Fields are only added within a version. A change of their meaning increments `v`.
+//!
+//! | type | method | fields |
+//! |---|---|---|
+//! | `entry` | list | `path`, `file_type`, `depth` |
+//! | `du` | du | `path`, `bytes` (disk usage, `st_blocks` * 512, of the directory and below) |
+//! | `stat` | dump-stat | `path`, `file_type`, `depth`, `dev`, `ino`, `mode`, `nlink`, `uid`, `gid`, `rdev`, `size`, `blocks`, `atime`, `atime_nsec`, `mtime`, `mtime_nsec`, `ctime`, `ctime_nsec`, `xattrs` |
+//! | `checksum` | checksum | `path`, `algorithm`, `hash` (in hex) |
+//! | `tree_hash` | tree-hash | `path` (a directory), `algorithm`, `hash` (in hex) |
+//! | `dupe` | dupes | `path`, `group` (from 0, in the order of the first file), `size` |
+//! | `diff` | diff | `path` (of the root directory, or the other for `only_right`), `change` (`only_left`, `only_right`, `type` or `differ`) |
+//! | `clone` | clone-directory | `path` (relative, `/` at the end of a directory), `change` (like `rsync --itemize-changes`, or `*deleting`) |
+//! | `error` | all | `path` (may be absent), `errno` (may be absent), `message` |
+//!
+//! - `path` is the output path with `--relative` and `--prefix` applied.
+//!   When it is not valid UTF-8, `path` has U+FFFD in place of invalid bytes, and `path_hex` has the exact bytes in hex.
+//! - `file_type` is one of `file`, `directory`, `symlink`, `fifo`, `socket`, `char_device` and `block_device`.
+//! - `mode` is `st_mode` including the file type bits. times are seconds since the epoch.
+//! - `xattrs` is present with `--get-xattr`, an object from attribute name to its value in hex.
+//! - error records are ignored errors, e.g. by `--ignore-eaccess`. They are in order with the other records.
+//!
+//! CSV output has a header line, and the columns are the union of the fields of the method and `error`.
+//! A field absent in a record is an empty column, and `xattrs` is the JSON object.
+
+use crate::error;
+use crate::visitor::{Entry, FileType};
+use nix::sys::stat::FileStat;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::io::Write;
+
+pub const SCHEMA_VERSION: u64 = 1;
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, Eq, PartialEq)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+pub enum Format {
+    /// human readable output
+    Text,
+    /// one JSON object per line
+    #[cfg_attr(feature = "clap", value(alias = "ndjson"))]
+    Jsonl,
+    Csv,
+}
+
+pub const LIST_COLUMNS: &[&str] = &[
+    "v",
+    "type",
+    "path",
+    "path_hex",
+    "file_type",
+    "depth",
+    "errno",
+    "message",
+];
+pub const DU_COLUMNS: &[&str] = &["v", "type", "path", "path_hex", "bytes", "errno", "message"];
+pub const CHECKSUM_COLUMNS: &[&str] = &[
+    "v",
+    "type",
+    "path",
+    "path_hex",
+    "algorithm",
+    "hash",
+    "errno",
+    "message",
+];
+pub const DUPES_COLUMNS: &[&str] = &[
+    "v", "type", "path", "path_hex", "group", "size", "errno", "message",
+];
+pub const DIFF_COLUMNS: &[&str] = &[
+    "v", "type", "path", "path_hex", "change", "errno", "message",
+];
+pub const CLONE_COLUMNS: &[&str] = &[
+    "v", "type", "path", "path_hex", "change", "errno", "message",
+];
+pub const STAT_COLUMNS: &[&str] = &[
+    "v",
+    "type",
+    "path",
+    "path_hex",
+    "file_type",
+    "depth",
+    "dev",
+    "ino",
+    "mode",
+    "nlink",
+    "uid",
+    "gid",
+    "rdev",
+    "size",
+    "blocks",
+    "atime",
+    "atime_nsec",
+    "mtime",
+    "mtime_nsec",
+    "ctime",
+    "ctime_nsec",
+    "xattrs",
+    "errno",
+    "message",
+];
+
+pub fn file_type_name(t: FileType) -> &'static str {
+    match t {
+        FileType::File => "file",
+        FileType::Directory => "directory",
+        FileType::Symlink => "symlink",
+        FileType::Fifo => "fifo",
+        FileType::Socket => "socket",
+        FileType::CharacterDevice => "char_device",
+        FileType::BlockDevice => "block_device",
+    }
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    let mut s = String::with_capacity(bytes.len() * 2);
+    for b in bytes {
+        s.push_str(&format!("{:02x}", b));
+    }
+    s
+}
+
+/// one output record
+pub struct Record(Map<String, Value>);
+
+impl Record {
+    fn new(kind: &str) -> Record {
+        let mut m = Map::new();
+        m.insert("v".to_owned(), SCHEMA_VERSION.into());
+        m.insert("type".to_owned(), kind.into());
+        Record(m)
+    }
+
+    pub fn insert(&mut self, key: &str, v: impl Into<Value>) {
+        self.0.insert(key.to_owned(), v.into());
+    }
+
+    fn path(&mut self, path: &[u8]) {
+        match std::str::from_utf8(path) {
+            Ok(s) => self.insert("path", s),
+            Err(_) => {
+                self.insert("path", String::from_utf8_lossy(path).into_owned());
+                self.insert("path_hex", hex(path));
+            }
+        }
+    }
+
+    pub fn entry(path: &[u8], entry: &Entry) -> Record {
+        let mut r = Record::new("entry");
+        r.path(path);
+        r.insert("file_type", file_type_name(entry.file_type));
+        r.insert("depth", entry.depth);
+        r
+    }
+
+    pub fn du(path: &[u8], bytes: u64) -> Record {
+        let mut r = Record::new("du");
+        r.path(path);
+        r.insert("bytes", bytes);
+        r
+    }
+
+    fn hash(kind: &str, path: &[u8], algorithm: &str, hash: &[u8]) -> Record {
+        let mut r = Record::new(kind);
+        r.path(path);
+        r.insert("algorithm", algorithm);
+        r.insert("hash", hex(hash));
+        r
+    }
+
+    pub fn checksum(path: &[u8], algorithm: &str, hash: &[u8]) -> Record {
+        Record::hash("checksum", path, algorithm, hash)
+    }
+
+    pub fn tree_hash(path: &[u8], algorithm: &str, hash: &[u8]) -> Record {
+        Record::hash("tree_hash", path, algorithm, hash)
+    }
+
+    pub fn dupe(path: &[u8], group: usize, size: u64) -> Record {
+        let mut r = Record::new("dupe");
+        r.path(path);
+        r.insert("group", group);
+        r.insert("size", size);
+        r
+    }
+
+    pub fn diff(path: &[u8], change: &str) -> Record {
+        let mut r = Record::new("diff");
+        r.path(path);
+        r.insert("change", change);
+        r
+    }
+
+    pub fn cloned(path: &[u8], change: &str) -> Record {
+        let mut r = Record::new("clone");
+        r.path(path);
+        r.insert("change", change.trim_end());
+        r
+    }
+
+    pub fn stat(path: &[u8], entry: &Entry, st: &FileStat) -> Record {
+        let mut r = Record::new("stat");
+        r.path(path);
+        r.insert("file_type", file_type_name(entry.file_type));
+        r.insert("depth", entry.depth);
+        r.insert("dev", st.st_dev);
+        r.insert("ino", st.st_ino);
+        r.insert("mode", st.st_mode);
+        r.insert("nlink", st.st_nlink);
+        r.insert("uid", st.st_uid);
+        r.insert("gid", st.st_gid);
+        r.insert("rdev", st.st_rdev);
+        r.insert("size", st.st_size);
+        r.insert("blocks", st.st_blocks);
+        r.insert("atime", st.st_atime);
+        r.insert("atime_nsec", st.st_atime_nsec);
+        r.insert("mtime", st.st_mtime);
+        r.insert("mtime_nsec", st.st_mtime_nsec);
+        r.insert("ctime", st.st_ctime);
+        r.insert("ctime_nsec", st.st_ctime_nsec);
+        r
+    }
+
+    pub fn xattrs(&mut self, xattrs: &[(Vec<u8>, Vec<u8>)]) {
+        let m: Map<String, Value> = xattrs
+            .iter()
+            .map(|(n, v)| (String::from_utf8_lossy(n).into_owned(), hex(v).into()))
+            .collect();
+        self.insert("xattrs", m);
+    }
+
+    pub fn error(e: &error::E) -> Record {
+        let mut r = Record::new("error");
+        if let Some(p) = e.path() {
+            use std::os::unix::ffi::OsStrExt;
+            r.path(p.as_os_str().as_bytes());
+        }
+        if let Some(eno) = e.errno() {
+            r.insert("errno", eno);
+        }
+        r.insert("message", e.to_string());
+        r
+    }
+}
+
+/// writes records as JSON lines or CSV rows
+pub struct RecordWriter {
+    out: Box<dyn Write + Send>,
+    format: Format,
+    columns: &'static [&'static str],
+    header_written: bool,
+    buf: Vec<u8>,
+}
+
+impl RecordWriter {
+    /// `columns` are used for CSV
+    pub fn new(
+        out: Box<dyn Write + Send>,
+        format: Format,
+        columns: &'static [&'static str],
+    ) -> RecordWriter {
+        RecordWriter {
+            out,
+            format,
+            columns,
+            header_written: false,
+            buf: Vec::new(),
+        }
+    }
+
+    fn header(&mut self) {
+        if self.format == Format::Csv && !self.header_written {
+            self.header_written = true;
+            self.buf
+                .extend_from_slice(self.columns.join(",").as_bytes());
+            self.buf.push(b'\n');
+        }
+    }
+
+    pub fn write(&mut self, r: &Record) -> Result<(), error::E> {
+        self.buf.clear();
+        self.header();
+        match self.format {
+            Format::Csv => {
+                for (i, c) in self.columns.iter().enumerate() {
+                    if i > 0 {
+                        self.buf.push(b',');
+                    }
+                    match r.0.get(*c) {
+                        None | Some(Value::Null) => {}
+                        Some(Value::String(s)) => csv_field(s, &mut self.buf),
+                        Some(v) => csv_field(&v.to_string(), &mut self.buf),
+                    }
+                }
+            }
+            _ => {
+                // a map of strings and numbers always serializes
+                serde_json::to_writer(&mut self.buf, &r.0).unwrap();
+            }
+        }
+        self.buf.push(b'\n');
+        error::maybe_generic_io_error(self.out.write_all(&self.buf))
+    }
+
+    /// also writes the CSV header when there were no records
+    pub fn flush(&mut self) -> Result<(), error::E> {
+        self.buf.clear();
+        self.header();
+        error::maybe_generic_io_error(self.out.write_all(&self.buf))?;
+        error::maybe_generic_io_error(self.out.flush())
+    }
+}
+
+/// where a printer writes. text, or records in jsonl or csv
+pub enum Output {
+    Text(Box<dyn Write + Send>),
+    Records(RecordWriter),
+}
+
+impl Output {
+    /// records with `columns` for csv, unless `format` is text
+    pub fn with_format(self, format: Format, columns: &'static [&'static str]) -> Output {
+        match self {
+            Output::Text(out) if format != Format::Text => {
+                Output::Records(RecordWriter::new(out, format, columns))
+            }
+            o => o,
+        }
+    }
+
+    /// report an ignored error. to stderr for text
+    pub fn error(&mut self, e: &error::E) -> Result<(), error::E> {
+        match self {
+            Output::Text(_) => {
+                eprintln!("ignored error: {}", e);
+                Ok(())
+            }
+            Output::Records(w) => w.write(&Record::error(e)),
+        }
+    }
+
+    pub fn flush(&mut self) -> Result<(), error::E> {
+        match self {
+            Output::Text(out) => error::maybe_generic_io_error(out.flush()),
+            Output::Records(w) => w.flush(),
+        }
+    }
+}
+
+fn csv_field(s: &str, out: &mut Vec<u8>) {
+    if s.contains([',', '"', '\n', '\r']) {
+        out.push(b'"');
+        out.extend_from_slice(s.replace('"', "\"\"").as_bytes());
+        out.push(b'"');
+    } else {
+        out.extend_from_slice(s.as_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::printer::SharedBuf;
+
+    #[test]
+    fn formats() {
+        let entry = Entry {
+            path: "r/a,\"b\"".into(),
+            file_type: FileType::File,
+            depth: 1,
+            metadata: None,
+            root_len: 1,
+        };
+        let err = error::E::StatError {
+            path: "r/x".into(),
+            eno: nix::errno::Errno::ENOENT,
+        };
+
+        let buf = SharedBuf::default();
+        let mut w = RecordWriter::new(Box::new(buf.clone()), Format::Jsonl, LIST_COLUMNS);
+        w.write(&Record::entry(b"r/\xffz", &entry)).unwrap();
+        w.write(&Record::error(&err)).unwrap();
+        let lines: Vec<Value> = buf
+            .string()
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(lines[0]["v"], 1);
+        assert_eq!(lines[0]["type"], "entry");
+        assert_eq!(lines[0]["path"], "r/\u{fffd}z");
+        assert_eq!(lines[0]["path_hex"], "722fff7a");
+        assert_eq!(lines[0]["file_type"], "file");
+        assert_eq!(lines[1]["type"], "error");
+        assert_eq!(lines[1]["path"], "r/x");
+        assert_eq!(lines[1]["errno"], libc::ENOENT);
+
+        let buf = SharedBuf::default();
+        let mut w = RecordWriter::new(Box::new(buf.clone()), Format::Csv, LIST_COLUMNS);
+        w.write(&Record::entry(b"r/a,\"b\"", &entry)).unwrap();
+        w.write(&Record::error(&err)).unwrap();
+        let s = buf.string();
+        let lines: Vec<_> = s.lines().collect();
+        assert_eq!(
+            lines[0],
+            "v,type,path,path_hex,file_type,depth,errno,message"
+        );
+        assert_eq!(lines[1], "1,entry,\"r/a,\"\"b\"\"\",,file,1,,");
+        assert!(lines[2].starts_with("1,error,r/x,,,,2,"));
+    }
+}
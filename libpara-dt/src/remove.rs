@@ -0,0 +1,271 @@
+//! `Method::Remove`. removes the entries and the root directories, like `rm -rf`.
+//!
+//! Non-directories are unlinked by the traverse threads in parallel, relative to the fd of their directory.
+//! Directories are removed in post-order, after everything below them, relative to the fd of their parent.
+//! Only the roots are removed by path. A root that is a symlink is refused, so its target is never entered.
+//! Errors are printed to stderr as they happen, and counted in the summary.
+
+use crate::dir::Dir;
+use crate::error;
+use crate::printer::PathPrinter;
+use crate::visitor::{Action, Entry, FileType, Visitor};
+use nix::errno::Errno;
+use nix::sys::stat::FileStat;
+use std::ffi::CStr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+#[derive(Default)]
+struct Stats {
+    files: AtomicUsize,
+    dirs: AtomicUsize,
+    errors: AtomicUsize,
+}
+
+impl Stats {
+    fn count(&self, is_dir: bool) {
+        let n = if is_dir { &self.dirs } else { &self.files };
+        n.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn error(&self, e: &error::E) {
+        eprintln!("{}", e);
+        self.errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// an entry already gone is not an error, like `rm -f`
+    fn removed(&self, path: &Path, is_dir: bool, r: nix::Result<()>) {
+        match r {
+            Ok(()) => self.count(is_dir),
+            Err(Errno::ENOENT) => {}
+            Err(eno) => self.error(&error::E::RemoveError {
+                path: path.to_owned(),
+                eno,
+            }),
+        }
+    }
+}
+
+/// unlinks entries in the traverse threads
+struct Unlinker {
+    stats: Arc<Stats>,
+    one_file_system: bool,
+}
+
+impl Action for Unlinker {
+    fn entry(
+        &self,
+        dir: &Dir,
+        name: &CStr,
+        entry: &Entry,
+        metadata: Option<&FileStat>,
+    ) -> Result<bool, error::E> {
+        let is_dir = entry.file_type == FileType::Directory;
+        // a mount point is not descended with --one-file-system. keep it as `rm --one-file-system`
+        if is_dir && self.one_file_system && metadata.is_some_and(|m| m.st_dev != dir.root_dev()) {
+            eprintln!(
+                "skipping {:?}, since it is on a different filesystem",
+                entry.path
+            );
+            self.stats.errors.fetch_add(1, Ordering::Relaxed);
+            return Ok(false);
+        }
+        self.stats
+            .removed(&entry.path, is_dir, dir.unlink_at(name, is_dir));
+        Ok(false)
+    }
+
+    fn leave_dir(&self, dir: &Dir, name: &CStr, entry: &Entry) -> Result<bool, error::E> {
+        self.stats
+            .removed(&entry.path, true, dir.unlink_at(name, true));
+        Ok(false)
+    }
+}
+
+pub struct Remover {
+    stats: Arc<Stats>,
+    one_file_system: bool,
+    /// print the entries instead of removing them
+    dry_run: Option<PathPrinter>,
+}
+
+impl Remover {
+    pub fn new(one_file_system: bool) -> Remover {
+        Remover {
+            stats: Arc::new(Stats::default()),
+            one_file_system,
+            dry_run: None,
+        }
+    }
+
+    /// print the paths with `printer`, and remove nothing
+    pub fn dry_run(mut self, printer: PathPrinter) -> Self {
+        self.dry_run = Some(printer);
+        self
+    }
+}
+
+impl Visitor for Remover {
+    fn requires_post_order(&self) -> bool {
+        true
+    }
+
+    fn action(&self) -> Option<Arc<dyn Action>> {
+        if self.dry_run.is_some() {
+            return None;
+        }
+        Some(Arc::new(Unlinker {
+            stats: self.stats.clone(),
+            one_file_system: self.one_file_system,
+        }))
+    }
+
+    /// refuse to remove `/`, like `rm --preserve-root`, and roots that are symlinks,
+    /// since the traversal would follow them and remove the entries of the target
+    fn start(&mut self, roots: &[PathBuf]) -> Result<(), error::E> {
+        for r in roots {
+            if std::fs::canonicalize(r).is_ok_and(|p| p == Path::new("/")) {
+                return Err(error::invalid_option(
+                    "src_paths",
+                    &format!("refusing to remove {:?}", r),
+                ));
+            }
+            // without a trailing slash, which would resolve the symlink
+            let path: PathBuf = r.components().collect();
+            if std::fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_symlink()) {
+                return Err(error::invalid_option(
+                    "src_paths",
+                    &format!("refusing to remove the symlink {:?}", r),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// the roots, and all entries with dry run
+    fn visit(&mut self, entry: &Entry) -> Result<(), error::E> {
+        let is_dir = entry.file_type == FileType::Directory;
+        if let Some(p) = &mut self.dry_run {
+            self.stats.count(is_dir);
+            return p.visit(entry);
+        }
+        self.stats.removed(
+            &entry.path,
+            is_dir,
+            nix::unistd::unlinkat(None, &entry.path, nix::unistd::UnlinkatFlags::RemoveDir),
+        );
+        Ok(())
+    }
+
+    fn error(&mut self, e: &error::E) -> Result<(), error::E> {
+        self.stats.error(e);
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), error::E> {
+        let files = self.stats.files.load(Ordering::Relaxed);
+        let dirs = self.stats.dirs.load(Ordering::Relaxed);
+        if let Some(p) = &mut self.dry_run {
+            p.finish()?;
+            eprintln!("would remove {} files and {} directories", files, dirs);
+            return Ok(());
+        }
+        let errors = self.stats.errors.load(Ordering::Relaxed);
+        if errors > 0 {
+            eprintln!(
+                "removed {} files and {} directories, {} errors",
+                files, dirs, errors
+            );
+            return Err(error::E::Incomplete { errors });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::options::{Method, Order};
+    use crate::printer::test_run;
+    use crate::traverse::{traverse_with_visitor, Traverser};
+
+    #[test]
+    fn remove() {
+        let files: Vec<String> = (0..10)
+            .flat_map(|i| (0..10).map(move |j| format!("d{}/e{}/f", i, j)))
+            .chain(["x/".to_owned(), "y".to_owned()])
+            .collect();
+        let files: Vec<&str> = files.iter().map(|f| f.as_str()).collect();
+        let root = crate::options::test_tree("remove", &files);
+        let mut opts = crate::options::test_option(root.to_str().unwrap());
+        opts.method = Method::Remove { dry_run: true };
+        opts.order = Order::Alphabetical;
+        opts.num_threads = 4;
+
+        let (s, r) = test_run(opts.clone(), |out| {
+            Remover::new(false).dry_run(PathPrinter::new(out))
+        });
+        r.unwrap();
+        let lines: Vec<_> = s.lines().collect();
+        assert_eq!(lines.len(), 10 * 10 * 2 + 10 + 3);
+        assert_eq!(lines[0], root.join("d0/e0/f").to_str().unwrap());
+        assert_eq!(lines[2], root.join("d0/e1/f").to_str().unwrap());
+        assert_eq!(*lines.last().unwrap(), root.to_str().unwrap());
+        assert!(root.join("d0/e0/f").exists());
+
+        opts.method = Method::Remove { dry_run: false };
+        traverse_with_visitor(&mut Traverser { opt: opts }, Box::new(Remover::new(false))).unwrap();
+        assert!(!root.exists());
+    }
+
+    #[test]
+    fn preserve_root() {
+        let mut opts = crate::options::test_option("/");
+        opts.method = Method::Remove { dry_run: true };
+        let (out, r) = test_run(opts, |out| {
+            Remover::new(false).dry_run(PathPrinter::new(out))
+        });
+        assert!(matches!(r, Err(error::E::InvalidOptionError { .. })));
+        assert_eq!(out, "");
+    }
+
+    #[test]
+    fn symlink_root() {
+        let root = crate::options::test_tree("remove_symlink", &["d/f"]);
+        std::os::unix::fs::symlink("d", root.join("l")).unwrap();
+        for l in ["l", "l/"] {
+            let mut opts = crate::options::test_option(root.join(l).to_str().unwrap());
+            opts.method = Method::Remove { dry_run: false };
+            let (_, r) = test_run(opts, |_| Remover::new(false));
+            assert!(matches!(r, Err(error::E::InvalidOptionError { .. })));
+        }
+        assert!(root.join("d/f").exists());
+    }
+
+    #[test]
+    fn symlink_swap() {
+        let outside = crate::options::test_tree("remove_swap_outside", &["z"]);
+        let root = crate::options::test_tree("remove_swap", &["a/x", "b/y"]);
+        let mut opts = crate::options::test_option(root.to_str().unwrap());
+        opts.method = Method::Remove { dry_run: false };
+        opts.order = Order::Alphabetical;
+
+        // b is replaced when a/x is removed, before b is opened
+        let (b, target) = (root.join("b"), outside.to_path_buf());
+        let once = std::sync::Once::new();
+        let hook = move |_: &Entry| {
+            once.call_once(|| {
+                std::fs::remove_dir_all(&b).unwrap();
+                std::os::unix::fs::symlink(&target, &b).unwrap();
+            })
+        };
+        let (_, r) = test_run(opts, |_| crate::visitor::EntryHook {
+            inner: Remover::new(false),
+            hook: Arc::new(hook),
+        });
+        assert!(matches!(r, Err(error::E::Incomplete { .. })));
+        assert!(outside.join("z").exists());
+        assert!(!root.join("a").exists());
+    }
+}
@@ -0,0 +1,194 @@
+//! Sorting of entries in each directory, for `--sort`, `--dirs-first` and `--reverse`.
+//!
+//! The order of a directory decides the order of the subtrees too,
+//! because the traversal keeps the output in pre-order.
+
+use crate::options::{Options, Order};
+use crate::visitor::FileType;
+use nix::sys::stat::FileStat;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::ffi::CStr;
+use std::sync::OnceLock;
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, Eq, PartialEq)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+pub enum SortKey {
+    /// byte-wise name
+    Name,
+    /// name by the collation of LC_COLLATE
+    Locale,
+    /// name with digit sequences compared as numbers, like `ls -v`. `file2` comes before `file10`
+    Version,
+    /// size, smallest first
+    Size,
+    /// modification time, oldest first
+    Mtime,
+    /// inode number. may be faster to read on some filesystems
+    Inode,
+    /// readdir order
+    None,
+}
+
+/// one entry to sort. `metadata` is filled when `Sorter::needs_stat` is true
+pub struct SortItem {
+    pub entry: nix::dir::Entry,
+    pub file_type: Option<FileType>,
+    pub metadata: Option<FileStat>,
+}
+
+impl SortItem {
+    fn name(&self) -> &CStr {
+        self.entry.file_name()
+    }
+}
+
+pub struct Sorter {
+    key: SortKey,
+    dirs_first: bool,
+    reverse: bool,
+}
+
+impl Sorter {
+    /// `None` when entries are used in readdir order
+    pub fn new(opts: &Options) -> Option<Sorter> {
+        let key = match (opts.sort, &opts.order) {
+            (Some(k), _) => k,
+            (None, Order::Alphabetical) => SortKey::Name,
+            (None, _) => SortKey::None,
+        };
+        if key == SortKey::None && !opts.dirs_first {
+            return None;
+        }
+        Some(Sorter {
+            key,
+            dirs_first: opts.dirs_first,
+            reverse: opts.reverse,
+        })
+    }
+
+    pub fn needs_stat(&self) -> bool {
+        matches!(self.key, SortKey::Size | SortKey::Mtime)
+    }
+
+    /// `file_type` is needed for all items
+    pub fn needs_type(&self) -> bool {
+        self.dirs_first
+    }
+
+    pub fn sort(&self, items: &mut [SortItem]) {
+        items.sort_by(|l, r| {
+            if self.dirs_first {
+                let ld = l.file_type == Some(FileType::Directory);
+                let rd = r.file_type == Some(FileType::Directory);
+                if ld != rd {
+                    return rd.cmp(&ld);
+                }
+            }
+            let o = self.compare(l, r);
+            if self.reverse {
+                o.reverse()
+            } else {
+                o
+            }
+        });
+    }
+
+    fn compare(&self, l: &SortItem, r: &SortItem) -> Ordering {
+        let by_name = || l.name().cmp(r.name());
+        match self.key {
+            SortKey::Name => by_name(),
+            SortKey::Locale => collate(l.name(), r.name()).then_with(by_name),
+            SortKey::Version => version_cmp(l.name().to_bytes(), r.name().to_bytes()),
+            SortKey::Size => stat_key(l, r, |st| st.st_size).then_with(by_name),
+            SortKey::Mtime => {
+                stat_key(l, r, |st| (st.st_mtime, st.st_mtime_nsec)).then_with(by_name)
+            }
+            SortKey::Inode => l.entry.ino().cmp(&r.entry.ino()),
+            SortKey::None => Ordering::Equal,
+        }
+    }
+}
+
+fn stat_key<K: Ord>(l: &SortItem, r: &SortItem, f: impl Fn(&FileStat) -> K) -> Ordering {
+    l.metadata
+        .as_ref()
+        .map(&f)
+        .cmp(&r.metadata.as_ref().map(&f))
+}
+
+/// `LC_COLLATE` of the environment, loaded with `newlocale` to leave the global locale as it is
+struct Collation(libc::locale_t);
+
+// only read by strcoll_l, which is thread-safe
+unsafe impl Send for Collation {}
+unsafe impl Sync for Collation {}
+
+extern "C" {
+    fn strcoll_l(
+        l: *const libc::c_char,
+        r: *const libc::c_char,
+        loc: libc::locale_t,
+    ) -> libc::c_int;
+}
+
+/// compare by the collation, or equal when the locale cannot be loaded
+fn collate(l: &CStr, r: &CStr) -> Ordering {
+    static COLLATION: OnceLock<Option<Collation>> = OnceLock::new();
+    let c = COLLATION.get_or_init(|| {
+        let loc =
+            unsafe { libc::newlocale(libc::LC_COLLATE_MASK, c"".as_ptr(), std::ptr::null_mut()) };
+        (!loc.is_null()).then_some(Collation(loc))
+    });
+    match c {
+        Some(c) => unsafe { strcoll_l(l.as_ptr(), r.as_ptr(), c.0) }.cmp(&0),
+        None => Ordering::Equal,
+    }
+}
+
+/// compare names with digit sequences as numbers
+pub fn version_cmp(l: &[u8], r: &[u8]) -> Ordering {
+    let (mut i, mut j) = (0, 0);
+    while i < l.len() && j < r.len() {
+        if l[i].is_ascii_digit() && r[j].is_ascii_digit() {
+            let si = i;
+            let sj = j;
+            while i < l.len() && l[i].is_ascii_digit() {
+                i += 1;
+            }
+            while j < r.len() && r[j].is_ascii_digit() {
+                j += 1;
+            }
+            let a = trim_zeros(&l[si..i]);
+            let b = trim_zeros(&r[sj..j]);
+            let o = a.len().cmp(&b.len()).then_with(|| a.cmp(b));
+            if o != Ordering::Equal {
+                return o;
+            }
+        } else {
+            if l[i] != r[j] {
+                return l[i].cmp(&r[j]);
+            }
+            i += 1;
+            j += 1;
+        }
+    }
+    (l.len() - i).cmp(&(r.len() - j)).then_with(|| l.cmp(r))
+}
+
+fn trim_zeros(s: &[u8]) -> &[u8] {
+    let n = s.iter().take_while(|c| **c == b'0').count();
+    &s[n..]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn version() {
+        let mut v = ["a10", "a2", "a1b", "a02", "b", "a", "a1"];
+        v.sort_by(|l, r| version_cmp(l.as_bytes(), r.as_bytes()));
+        assert_eq!(v, ["a", "a1", "a1b", "a02", "a2", "a10", "b"]);
+    }
+}
@@ -0,0 +1,524 @@
+//! `--printf` output templates, like `find -printf`.
+//!
+//! Directives:
+//!
+//! | | |
+//! |---|---|
+//! | `%p` | path, with `--relative` and `--prefix` applied |
+//! | `%P` | path relative to the root directory |
+//! | `%f` | file name |
+//! | `%h` | leading directories of the path |
+//! | `%d` | depth |
+//! | `%y` | type, one of `fcdbpls` |
+//! | `%l` | symlink target, empty for others |
+//! | `%s` | size in bytes |
+//! | `%b` | disk usage in 512 byte blocks |
+//! | `%k` | disk usage in KiB |
+//! | `%m` | permission bits in octal |
+//! | `%M` | mode like `ls -l`, e.g. `-rw-r--r--` |
+//! | `%u` `%g` | owner and group name, or id if unknown |
+//! | `%U` `%G` | owner and group id |
+//! | `%i` | inode number |
+//! | `%n` | number of hard links |
+//! | `%D` | device number |
+//! | `%a` `%c` `%t` | access, status change and modification time like `ctime(3)` |
+//! | `%Ak` `%Ck` `%Tk` | the time in strftime format `%k`. `@` for seconds since epoch |
+//! | `%A{fmt}` ... | the time in strftime format `fmt`, e.g. `%T{%Y-%m-%d}` |
+//! | `%%` | `%` |
+//!
+//! A directive may have `-` and a width between `%` and the letter to pad the field, e.g. `%-10u`.
+//! `\n`, `\t`, `\0` and `\\` are escapes.
+//! Nothing is added after the template, so end it with `\n`.
+
+use crate::error;
+use crate::pathstr::PathFormat;
+use crate::quote::{self, QuotingStyle};
+use crate::visitor::{Entry, FileType};
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum TimeKind {
+    Access,
+    Change,
+    Modify,
+}
+
+#[derive(Debug, PartialEq)]
+enum Field {
+    Path,
+    RelativePath,
+    Name,
+    Dirname,
+    Depth,
+    Type,
+    LinkTarget,
+    Size,
+    Blocks,
+    KiB,
+    OctalMode,
+    ModeString,
+    User,
+    Group,
+    Uid,
+    Gid,
+    Inode,
+    Links,
+    Device,
+    Time(TimeKind, String),
+}
+
+impl Field {
+    fn needs_stat(&self) -> bool {
+        !matches!(
+            self,
+            Field::Path
+                | Field::RelativePath
+                | Field::Name
+                | Field::Dirname
+                | Field::Depth
+                | Field::Type
+                | Field::LinkTarget
+        )
+    }
+
+    /// fields from the path are quoted with `--quoting-style`
+    fn is_name(&self) -> bool {
+        matches!(
+            self,
+            Field::Path | Field::RelativePath | Field::Name | Field::Dirname | Field::LinkTarget
+        )
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Piece {
+    Literal(Vec<u8>),
+    Field {
+        field: Field,
+        left: bool,
+        width: usize,
+    },
+}
+
+#[derive(Debug)]
+pub struct Template {
+    pieces: Vec<Piece>,
+}
+
+fn err(reason: String) -> error::E {
+    error::invalid_option("printf", &reason)
+}
+
+impl Template {
+    pub fn parse(s: &str) -> Result<Template, error::E> {
+        let mut pieces = Vec::new();
+        let mut lit = Vec::new();
+        let mut chars = s.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => {
+                    let e = match chars.next() {
+                        Some('n') => b'\n',
+                        Some('t') => b'\t',
+                        Some('0') => b'\0',
+                        Some('\\') => b'\\',
+                        Some(c) => return Err(err(format!("unknown escape \\{}", c))),
+                        None => return Err(err("trailing \\".to_owned())),
+                    };
+                    lit.push(e);
+                }
+                '%' => {
+                    if chars.peek() == Some(&'%') {
+                        chars.next();
+                        lit.push(b'%');
+                        continue;
+                    }
+
+                    let left = chars.next_if_eq(&'-').is_some();
+                    let mut width = 0;
+                    while let Some(d) = chars.peek().and_then(|c| c.to_digit(10)) {
+                        width = width * 10 + d as usize;
+                        chars.next();
+                    }
+
+                    let c = chars.next().ok_or_else(|| err("trailing %".to_owned()))?;
+                    let time = |kind, chars: &mut std::iter::Peekable<std::str::Chars>| match chars
+                        .next()
+                    {
+                        Some('{') => {
+                            let mut fmt = String::new();
+                            loop {
+                                match chars.next() {
+                                    Some('}') => break,
+                                    Some(c) => fmt.push(c),
+                                    None => return Err(err("unterminated %{".to_owned())),
+                                }
+                            }
+                            Ok(Field::Time(kind, fmt))
+                        }
+                        Some('@') => Ok(Field::Time(kind, "@".to_owned())),
+                        Some(c) if c.is_ascii_alphabetic() => {
+                            Ok(Field::Time(kind, format!("%{}", c)))
+                        }
+                        _ => Err(err(format!("missing time format after %{}", c))),
+                    };
+
+                    let field = match c {
+                        'p' => Field::Path,
+                        'P' => Field::RelativePath,
+                        'f' => Field::Name,
+                        'h' => Field::Dirname,
+                        'd' => Field::Depth,
+                        'y' => Field::Type,
+                        'l' => Field::LinkTarget,
+                        's' => Field::Size,
+                        'b' => Field::Blocks,
+                        'k' => Field::KiB,
+                        'm' => Field::OctalMode,
+                        'M' => Field::ModeString,
+                        'u' => Field::User,
+                        'g' => Field::Group,
+                        'U' => Field::Uid,
+                        'G' => Field::Gid,
+                        'i' => Field::Inode,
+                        'n' => Field::Links,
+                        'D' => Field::Device,
+                        'a' => Field::Time(TimeKind::Access, CTIME.to_owned()),
+                        'c' => Field::Time(TimeKind::Change, CTIME.to_owned()),
+                        't' => Field::Time(TimeKind::Modify, CTIME.to_owned()),
+                        'A' => time(TimeKind::Access, &mut chars)?,
+                        'C' => time(TimeKind::Change, &mut chars)?,
+                        'T' => time(TimeKind::Modify, &mut chars)?,
+                        c => return Err(err(format!("unknown directive %{}", c))),
+                    };
+
+                    if !lit.is_empty() {
+                        pieces.push(Piece::Literal(std::mem::take(&mut lit)));
+                    }
+                    pieces.push(Piece::Field { field, left, width });
+                }
+                c => {
+                    let mut b = [0; 4];
+                    lit.extend_from_slice(c.encode_utf8(&mut b).as_bytes());
+                }
+            }
+        }
+        if !lit.is_empty() {
+            pieces.push(Piece::Literal(lit));
+        }
+        Ok(Template { pieces })
+    }
+
+    /// true when the template uses fields from `lstat`
+    pub fn needs_stat(&self) -> bool {
+        self.pieces.iter().any(|p| match p {
+            Piece::Field { field, .. } => field.needs_stat(),
+            Piece::Literal(_) => false,
+        })
+    }
+}
+
+const CTIME: &str = "%a %b %e %H:%M:%S %Y";
+
+/// user and group names looked up so far
+#[derive(Default)]
+struct Names {
+    users: HashMap<u32, Vec<u8>>,
+    groups: HashMap<u32, Vec<u8>>,
+}
+
+impl Names {
+    fn user(&mut self, uid: u32) -> &[u8] {
+        self.users
+            .entry(uid)
+            .or_insert_with(|| match nix::unistd::User::from_uid(uid.into()) {
+                Ok(Some(u)) => u.name.into_bytes(),
+                _ => uid.to_string().into_bytes(),
+            })
+    }
+
+    fn group(&mut self, gid: u32) -> &[u8] {
+        self.groups
+            .entry(gid)
+            .or_insert_with(|| match nix::unistd::Group::from_gid(gid.into()) {
+                Ok(Some(g)) => g.name.into_bytes(),
+                _ => gid.to_string().into_bytes(),
+            })
+    }
+}
+
+/// expands a `Template` for each entry
+pub struct Renderer {
+    template: Template,
+    quoting_style: QuotingStyle,
+    path_format: PathFormat,
+    names: Names,
+}
+
+impl Renderer {
+    pub fn new(template: Template, quoting_style: QuotingStyle) -> Renderer {
+        Renderer {
+            template,
+            quoting_style,
+            path_format: PathFormat::default(),
+            names: Names::default(),
+        }
+    }
+
+    /// how `%p` is output
+    pub fn path_format(mut self, f: PathFormat) -> Self {
+        self.path_format = f;
+        self
+    }
+
+    pub fn needs_stat(&self) -> bool {
+        self.template.needs_stat()
+    }
+
+    pub fn render(&mut self, entry: &Entry, out: &mut Vec<u8>) {
+        let mut v = Vec::new();
+        for p in &self.template.pieces {
+            let (field, left, width) = match p {
+                Piece::Literal(l) => {
+                    out.extend_from_slice(l);
+                    continue;
+                }
+                Piece::Field { field, left, width } => (field, *left, *width),
+            };
+
+            v.clear();
+            if field.is_name() {
+                name_field(field, entry, self.quoting_style, &self.path_format, &mut v);
+            } else {
+                stat_field(field, entry, &mut self.names, &mut v);
+            }
+
+            let pad = width.saturating_sub(String::from_utf8_lossy(&v).chars().count());
+            if !left {
+                out.resize(out.len() + pad, b' ');
+            }
+            out.extend_from_slice(&v);
+            if left {
+                out.resize(out.len() + pad, b' ');
+            }
+        }
+    }
+}
+
+fn name_field(
+    field: &Field,
+    entry: &Entry,
+    quoting_style: QuotingStyle,
+    path_format: &PathFormat,
+    v: &mut Vec<u8>,
+) {
+    let path = entry.path.as_os_str().as_bytes();
+    let target: Vec<u8>;
+    let raw: &[u8] = match field {
+        Field::Path => {
+            target = {
+                let mut p = Vec::new();
+                path_format.push(entry, &mut p);
+                p
+            };
+            &target
+        }
+        Field::RelativePath => entry.relative_path().as_os_str().as_bytes(),
+        Field::Name if entry.depth > 0 => {
+            entry.path.file_name().map(|n| n.as_bytes()).unwrap_or(path)
+        }
+        Field::Dirname if entry.depth == 0 => b"",
+        Field::Dirname => entry
+            .path
+            .parent()
+            .map(|p| p.as_os_str().as_bytes())
+            .unwrap_or(b"."),
+        Field::LinkTarget => {
+            target = if entry.file_type == FileType::Symlink {
+                std::fs::read_link(&entry.path)
+                    .map(|p| p.into_os_string().into_vec())
+                    .unwrap_or_default()
+            } else {
+                Vec::new()
+            };
+            &target
+        }
+        _ => path,
+    };
+    quote::quote(raw, quoting_style, v);
+}
+
+fn stat_field(field: &Field, entry: &Entry, names: &mut Names, v: &mut Vec<u8>) {
+    let s = match field {
+        Field::Depth => entry.depth.to_string(),
+        Field::Type => type_char(entry.file_type).to_string(),
+        _ => {
+            // the traversal stats when `needs_stat` is true. the stat may fail otherwise
+            let st = match entry.metadata.as_ref() {
+                Some(st) => st,
+                None => {
+                    v.push(b'?');
+                    return;
+                }
+            };
+            match field {
+                Field::Size => st.st_size.to_string(),
+                Field::Blocks => st.st_blocks.to_string(),
+                Field::KiB => (st.st_blocks as u64).div_ceil(2).to_string(),
+                Field::OctalMode => format!("{:o}", st.st_mode & 0o7777),
+                Field::ModeString => mode_string(entry.file_type, st.st_mode),
+                Field::User => {
+                    v.extend_from_slice(names.user(st.st_uid));
+                    return;
+                }
+                Field::Group => {
+                    v.extend_from_slice(names.group(st.st_gid));
+                    return;
+                }
+                Field::Uid => st.st_uid.to_string(),
+                Field::Gid => st.st_gid.to_string(),
+                Field::Inode => st.st_ino.to_string(),
+                Field::Links => st.st_nlink.to_string(),
+                Field::Device => st.st_dev.to_string(),
+                Field::Time(kind, fmt) => {
+                    let (sec, nsec) = match kind {
+                        TimeKind::Access => (st.st_atime, st.st_atime_nsec),
+                        TimeKind::Change => (st.st_ctime, st.st_ctime_nsec),
+                        TimeKind::Modify => (st.st_mtime, st.st_mtime_nsec),
+                    };
+                    if fmt == "@" {
+                        format!("{}.{:09}", sec, nsec)
+                    } else {
+                        strftime(fmt, sec)
+                    }
+                }
+                _ => unreachable!(),
+            }
+        }
+    };
+    v.extend_from_slice(s.as_bytes());
+}
+
+fn type_char(t: FileType) -> char {
+    match t {
+        FileType::File => 'f',
+        FileType::Directory => 'd',
+        FileType::CharacterDevice => 'c',
+        FileType::BlockDevice => 'b',
+        FileType::Fifo => 'p',
+        FileType::Symlink => 'l',
+        FileType::Socket => 's',
+    }
+}
+
+/// mode like `ls -l`
+pub fn mode_string(t: FileType, mode: u32) -> String {
+    let mut s = String::with_capacity(10);
+    s.push(match t {
+        FileType::File => '-',
+        t => type_char(t),
+    });
+    let bit = |b: u32, c: char| if mode & b != 0 { c } else { '-' };
+    let exec =
+        |x: u32, special: u32, on: char, off: char| match (mode & x != 0, mode & special != 0) {
+            (true, true) => on,
+            (false, true) => off,
+            (true, false) => 'x',
+            (false, false) => '-',
+        };
+    s.push(bit(0o400, 'r'));
+    s.push(bit(0o200, 'w'));
+    s.push(exec(0o100, 0o4000, 's', 'S'));
+    s.push(bit(0o040, 'r'));
+    s.push(bit(0o020, 'w'));
+    s.push(exec(0o010, 0o2000, 's', 'S'));
+    s.push(bit(0o004, 'r'));
+    s.push(bit(0o002, 'w'));
+    s.push(exec(0o001, 0o1000, 't', 'T'));
+    s
+}
+
+/// format a time in the local time zone
+fn strftime(fmt: &str, sec: i64) -> String {
+    let fmt = match CString::new(fmt) {
+        Ok(f) => f,
+        Err(_) => return String::new(),
+    };
+    let t: libc::time_t = sec;
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    let mut buf = [0u8; 256];
+    let n = unsafe {
+        if libc::localtime_r(&t, &mut tm).is_null() {
+            return sec.to_string();
+        }
+        libc::strftime(
+            buf.as_mut_ptr() as *mut libc::c_char,
+            buf.len(),
+            fmt.as_ptr(),
+            &tm,
+        )
+    };
+    String::from_utf8_lossy(&buf[..n]).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(template: &str, entry: &Entry) -> String {
+        let mut r = Renderer::new(Template::parse(template).unwrap(), QuotingStyle::Literal);
+        let mut v = Vec::new();
+        r.render(entry, &mut v);
+        String::from_utf8(v).unwrap()
+    }
+
+    #[test]
+    fn fields() {
+        let root = crate::options::test_tree("template", &["d/f"]);
+        std::fs::write(root.join("d/f"), b"hello").unwrap();
+        std::os::unix::fs::symlink("f", root.join("d/l")).unwrap();
+        let path = root.join("d/f");
+        let st = nix::sys::stat::lstat(&path).unwrap();
+        let entry = Entry {
+            path,
+            file_type: FileType::File,
+            depth: 2,
+            metadata: Some(st),
+            root_len: root.as_os_str().len(),
+        };
+
+        assert_eq!(render("%P %f %d %y %s\\n", &entry), "d/f f 2 f 5\n");
+        assert_eq!(render("[%5s|%-3d]", &entry), "[    5|2  ]");
+        assert_eq!(render("%h", &entry), root.join("d").to_str().unwrap());
+        assert_eq!(render("%%%i", &entry), format!("%{}", st.st_ino));
+        assert_eq!(
+            render("%T@", &entry),
+            format!("{}.{:09}", st.st_mtime, st.st_mtime_nsec)
+        );
+        assert_eq!(render("%T{%%}", &entry), "%");
+
+        let link = Entry {
+            path: root.join("d/l"),
+            file_type: FileType::Symlink,
+            depth: 2,
+            metadata: None,
+            root_len: root.as_os_str().len(),
+        };
+        assert_eq!(render("%l %s", &link), "f ?");
+
+        assert!(!Template::parse("%p %P %f %y %l").unwrap().needs_stat());
+        assert!(Template::parse("%p %M").unwrap().needs_stat());
+        assert!(Template::parse("%q").is_err());
+        assert!(Template::parse("%T{").is_err());
+    }
+
+    #[test]
+    fn modes() {
+        assert_eq!(mode_string(FileType::File, 0o644), "-rw-r--r--");
+        assert_eq!(mode_string(FileType::Directory, 0o1777), "drwxrwxrwt");
+        assert_eq!(mode_string(FileType::File, 0o4644), "-rwSr--r--");
+    }
+}
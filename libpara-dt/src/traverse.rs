@@ -1,15 +1,21 @@
 use crate::dir::Dir;
 use crate::error;
 use crate::events;
+use crate::filter::{Filter, Target};
+use crate::ignore::{IgnoreRules, IgnoreStack, IGNORE_FILE_NAMES};
+use crate::mounts::MountTable;
 use crate::options::{Options, Order};
+use crate::sort::{SortItem, Sorter};
+use crate::visitor::{Action, Entry, FileType, Visitor};
 use crossbeam::channel::{select, Receiver, Sender};
 use events::CompleteTestResult;
+use nix::sys::stat::FileStat;
 use std::cell::RefCell;
-use std::ffi::OsString;
-use std::io::Write;
-use std::os::unix::ffi::OsStringExt;
+use std::ffi::CString;
 use std::path::{Path, PathBuf};
 use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 use std::thread;
 
 pub struct TraverseThread {
@@ -18,15 +24,250 @@ pub struct TraverseThread {
 
 #[derive(Debug)]
 pub enum TaskPostProc {
-    Show(OsString),
+    Visit(Entry),
+    /// a directory descended into, passed to `Action::leave_dir` before it is visited
+    LeaveDir {
+        dir: Dir,
+        name: CString,
+        entry: Entry,
+    },
+    /// an ignored error, reported in order with entries
+    Error(error::E),
 }
 
-fn run_postproc_task(t: TaskPostProc) -> Result<(), error::E> {
-    match t {
-        TaskPostProc::Show(s) => {
-            let mut v = s.into_vec();
-            v.push(b'\n');
-            error::maybe_generic_io_error(std::io::stdout().write_all(v.as_slice()))?;
+/// Visitor shared by all traverse threads.
+/// The first error from the visitor or from a traverse thread is kept,
+/// and after that, threads stop reading directories and later entries are not visited.
+struct Sink {
+    visitor: Mutex<Box<dyn Visitor>>,
+    wants_metadata: bool,
+    wants_all_depths: bool,
+    requires_order: bool,
+    requires_post_order: bool,
+    action: Option<Arc<dyn Action>>,
+    error: Mutex<Option<error::E>>,
+    stop: AtomicBool,
+}
+
+impl Sink {
+    fn new(visitor: Box<dyn Visitor>) -> Sink {
+        Sink {
+            wants_metadata: visitor.wants_metadata(),
+            wants_all_depths: visitor.wants_all_depths(),
+            requires_order: visitor.requires_order(),
+            requires_post_order: visitor.requires_post_order(),
+            action: visitor.action(),
+            visitor: Mutex::new(visitor),
+            error: Mutex::new(None),
+            stop: AtomicBool::new(false),
+        }
+    }
+
+    fn set_error(&self, e: error::E) {
+        let mut err = self.error.lock().unwrap();
+        if err.is_none() {
+            *err = Some(e);
+        }
+        self.stop.store(true, Ordering::Relaxed);
+    }
+
+    fn failed(&self) -> bool {
+        self.stop.load(Ordering::Relaxed)
+    }
+
+    fn finish(&self) -> Result<(), error::E> {
+        if let Some(e) = self.error.lock().unwrap().take() {
+            return Err(e);
+        }
+        self.visitor.lock().unwrap().finish()
+    }
+}
+
+/// Shared by all traverse threads of one traversal
+struct Context {
+    opts: Options,
+    sink: Sink,
+    filter: Option<Filter>,
+    exclude: Option<Filter>,
+    prune: Option<Filter>,
+    mounts: Option<MountTable>,
+    sorter: Option<Sorter>,
+    /// a directory is visited after the entries below it
+    post_order: bool,
+    /// `Order::Unordered` without the reorder machinery. entries are visited as soon as they are read
+    unordered: bool,
+    /// outstanding tasks in `unordered`
+    tasks: events::TaskCounter,
+}
+
+impl Context {
+    fn new(opts: Options, visitor: Box<dyn Visitor>) -> Result<Context, error::E> {
+        let filter = opts.filter.as_deref().map(Filter::parse).transpose()?;
+        let prune = opts.prune.as_deref().map(Filter::parse).transpose()?;
+        let exclude = if opts.exclude.is_empty() {
+            None
+        } else {
+            Some(Filter::from_globs(&opts.exclude)?)
+        };
+        let mounts = if opts.include_fstype.is_empty() && opts.exclude_fstype.is_empty() {
+            None
+        } else {
+            Some(MountTable::load()?)
+        };
+        let sink = Sink::new(visitor);
+        let post_order = (opts.post_order && !sink.requires_order) || sink.requires_post_order;
+        let unordered = opts.order == Order::Unordered && !sink.requires_order && !post_order;
+        let sorter = Sorter::new(&opts);
+        Ok(Context {
+            opts,
+            sink,
+            unordered,
+            tasks: events::TaskCounter::default(),
+            sorter,
+            post_order,
+            filter,
+            exclude,
+            prune,
+            mounts,
+        })
+    }
+
+    /// directories need stat to check the device
+    fn needs_dir_stat(&self) -> bool {
+        self.opts.one_file_system || self.mounts.is_some()
+    }
+
+    /// directories on a filesystem filtered by `--include-fstype`/`--exclude-fstype` are excluded
+    fn fstype_allowed(&self, st: Option<&FileStat>) -> bool {
+        let (m, st) = match (&self.mounts, st) {
+            (Some(m), Some(st)) => (m, st),
+            _ => return true,
+        };
+        let fstype = m.fstype(st.st_dev).unwrap_or("");
+        (self.opts.include_fstype.is_empty()
+            || self.opts.include_fstype.iter().any(|t| t == fstype))
+            && !self.opts.exclude_fstype.iter().any(|t| t == fstype)
+    }
+
+    fn needs_stat(&self) -> bool {
+        self.sink.wants_metadata
+            || self.filter.as_ref().is_some_and(|f| f.needs_stat())
+            || self.prune.as_ref().is_some_and(|f| f.needs_stat())
+    }
+
+    /// entries at `depth` are output
+    fn in_depth_window(&self, depth: usize) -> bool {
+        self.sink.wants_all_depths
+            || (self.opts.default_min_depth() <= depth
+                && self.opts.max_depth.is_none_or(|max| depth <= max))
+    }
+
+    /// entries in a directory at `depth` are read
+    fn descends_below(&self, depth: usize) -> bool {
+        self.sink.wants_all_depths || self.opts.max_depth.is_none_or(|max| depth < max)
+    }
+
+    /// the root directory itself is output as an entry at depth 0
+    fn root_entry(&self, d: &Dir) -> Result<Option<Entry>, error::E> {
+        if !self.in_depth_window(0) {
+            return Ok(None);
+        }
+
+        let metadata = if self.needs_stat() {
+            Some(d.stat()?)
+        } else {
+            None
+        };
+        let path = d.abs_path();
+        let target = Target {
+            path: &path,
+            file_type: FileType::Directory,
+            metadata: metadata.as_ref(),
+        };
+        if self.filter.as_ref().is_some_and(|f| !f.matches(&target)) {
+            return Ok(None);
+        }
+
+        Ok(Some(Entry {
+            path,
+            file_type: FileType::Directory,
+            depth: 0,
+            metadata: if self.sink.wants_metadata {
+                metadata
+            } else {
+                None
+            },
+            root_len: d.root_len(),
+        }))
+    }
+
+    /// excluded entries are not output, and not descended
+    fn is_excluded(&self, path: &Path, t: FileType, ignores: Option<&IgnoreStack>) -> bool {
+        if let Some(x) = &self.exclude {
+            let target = Target {
+                path,
+                file_type: t,
+                metadata: None,
+            };
+            if x.matches(&target) {
+                return true;
+            }
+        }
+        ignores.is_some_and(|i| i.is_ignored(path, t == FileType::Directory))
+    }
+}
+
+/// push the rules of ignore files in `d` to `ignores`
+fn read_ignore_files(
+    d: &Dir,
+    entries: &[nix::dir::Entry],
+    ignores: Option<Arc<IgnoreStack>>,
+) -> Option<Arc<IgnoreStack>> {
+    let mut rules = None;
+
+    for name in IGNORE_FILE_NAMES {
+        let e = entries
+            .iter()
+            .find(|e| e.file_name().to_bytes() == name.as_bytes());
+        if let Some(e) = e {
+            let path = d.entry_abspath(e);
+            match std::fs::read(&path) {
+                Ok(contents) => rules
+                    .get_or_insert_with(|| IgnoreRules::new(path.parent().unwrap().to_owned()))
+                    .add(&contents),
+                Err(err) => eprintln!("ignored error {:?}: {}", path, err),
+            }
+        }
+    }
+
+    match rules {
+        Some(r) if !r.is_empty() => Some(IgnoreStack::push(ignores, r)),
+        _ => ignores,
+    }
+}
+
+/// postprocs of the current segment are kept up to this, and are run with one lock of the visitor
+const POSTPROC_BATCH_SIZE: usize = 256;
+
+fn run_postproc_tasks(sink: &Sink, tasks: Vec<TaskPostProc>) -> Result<(), error::E> {
+    if tasks.is_empty() || sink.failed() {
+        return Ok(());
+    }
+
+    let mut visitor = sink.visitor.lock().unwrap();
+    for t in tasks {
+        let r = match t {
+            TaskPostProc::Visit(entry) => visitor.visit(&entry),
+            TaskPostProc::LeaveDir { dir, name, entry } => sink
+                .action
+                .as_ref()
+                .map_or(Ok(true), |a| a.leave_dir(&dir, &name, &entry))
+                .and_then(|v| if v { visitor.visit(&entry) } else { Ok(()) }),
+            TaskPostProc::Error(err) => visitor.error(&err),
+        };
+        if let Err(e) = r {
+            sink.set_error(e);
+            break;
         }
     }
 
@@ -46,7 +287,7 @@ struct DepPostProcs {
 
 impl PartialOrd for DepPostProcs {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        Some(self.key.0.cmp(&other.key.0))
+        Some(self.cmp(other))
     }
 }
 
@@ -86,20 +327,17 @@ impl ReorderKey {
 }
 
 struct TraverseState<'a> {
-    opts: &'a Options,
+    ctx: &'a Context,
     pendings: std::collections::BTreeSet<Rc<RefCell<DepPostProcs>>>,
     current: Rc<RefCell<DepPostProcs>>,
+    #[allow(dead_code)] // for debug print
     tid: usize,
     current_key: ReorderKey,
 }
 
 impl DepPostProcs {
-    fn flush_postprocs(&mut self) -> Result<(), error::E> {
-        let tmp_vec = std::mem::take(&mut self.postprocs);
-        for t in tmp_vec {
-            run_postproc_task(t)?;
-        }
-        Ok(())
+    fn flush_postprocs(&mut self, sink: &Sink) -> Result<(), error::E> {
+        run_postproc_tasks(sink, std::mem::take(&mut self.postprocs))
     }
 
     fn fixup(&mut self, succ: events::DepChain) {
@@ -111,24 +349,26 @@ impl DepPostProcs {
 impl<'a> TraverseState<'a> {
     fn flush_cur_postprocs(&mut self) -> Result<(), error::E> {
         let mut cur = self.current.borrow_mut();
-
-        let mut v = Vec::new();
-        std::mem::swap(&mut v, &mut cur.postprocs);
-        for t in v {
-            run_postproc_task(t)?;
-        }
-
-        Ok(())
+        cur.flush_postprocs(&self.ctx.sink)
     }
 
     fn push_postproc(&mut self, t: TaskPostProc) -> Result<(), error::E> {
+        if self.ctx.unordered {
+            // `current` stays the dummy segment, and is just a batch
+            let mut cur = self.current.borrow_mut();
+            cur.postprocs.push(t);
+            if cur.postprocs.len() >= POSTPROC_BATCH_SIZE {
+                cur.flush_postprocs(&self.ctx.sink)?;
+            }
+            return Ok(());
+        }
+
         self.pump(false)?;
         let mut cur = self.current.borrow_mut();
-        if cur.pred.is_completed(false).completed {
-            cur.flush_postprocs()?;
-            run_postproc_task(t)?;
-        } else {
-            cur.postprocs.push(t);
+        cur.postprocs.push(t);
+        // the rest is flushed when the segment ends
+        if cur.postprocs.len() >= POSTPROC_BATCH_SIZE && cur.pred.is_completed(false).completed {
+            cur.flush_postprocs(&self.ctx.sink)?;
         }
         Ok(())
     }
@@ -141,7 +381,9 @@ impl<'a> TraverseState<'a> {
                 let r = v.pred.is_completed(get_wait_channel);
                 if r.completed {
                     if v.current {
-                        v.flush_postprocs()?;
+                        if v.postprocs.len() >= POSTPROC_BATCH_SIZE {
+                            v.flush_postprocs(&self.ctx.sink)?;
+                        }
                         return Ok(CompleteTestResult {
                             completed: true,
                             wait_chan: None,
@@ -151,7 +393,7 @@ impl<'a> TraverseState<'a> {
                         let v = self.pendings.pop_first().unwrap();
                         let mut v = v.borrow_mut();
 
-                        v.flush_postprocs()?;
+                        v.flush_postprocs(&self.ctx.sink)?;
                         v.succ.notify_complete()
                     }
                 } else {
@@ -201,75 +443,253 @@ fn traverse_dir(
     free_thread_queue_rx: &Receiver<Sender<Task>>,
     parent_dirfd: Option<&Dir>,
     path: &Path,
+    depth: usize,
+    ignores: Option<Arc<IgnoreStack>>,
 ) -> Result<(), crate::error::E> {
     let d = if let Some(pd) = parent_dirfd {
-        Dir::new_at(&pd, &path)
+        Dir::new_at(pd, path)
     } else {
-        Dir::new_root(&path)
+        Dir::new_root(path)
     };
 
     //println!("{}: traverse dir {:?}", st.tid, path);
 
     match d {
         Err(e) => {
-            if e.is_ignorable_error(&st.opts) {
-                return Ok(());
+            // an entry replaced since it was read is reported like an ignored error
+            if e.is_ignorable_error(&st.ctx.opts) || (parent_dirfd.is_some() && e.is_replaced_dir())
+            {
+                return st.push_postproc(TaskPostProc::Error(e));
             } else {
                 return Err(e);
             }
         }
 
         Ok(d) => {
-            let mut entries = d.read_dir_all()?;
-
-            if st.opts.order == Order::Alphabetical {
-                entries.sort_by(|l, r| l.file_name().cmp(r.file_name()));
+            let root = if parent_dirfd.is_none() {
+                st.ctx.root_entry(&d)?
+            } else {
+                None
+            };
+            let (pre, post) = if st.ctx.post_order {
+                (None, root)
+            } else {
+                (root, None)
+            };
+            if let Some(root) = pre {
+                st.push_postproc(TaskPostProc::Visit(root))?;
             }
+            if st.ctx.descends_below(depth) {
+                traverse_entries(st, free_thread_queue_rx, &d, depth, ignores)?;
+            }
+            if let Some(root) = post {
+                st.push_postproc(TaskPostProc::Visit(root))?;
+            }
+        }
+    }
 
-            for e in entries {
-                let t = e.file_type().unwrap();
+    Ok(())
+}
 
-                if st.opts.method == crate::options::Method::List {
-                    let abspath = d.entry_abspath(&e);
-                    let path_str = abspath.into_os_string();
-                    st.push_postproc(TaskPostProc::Show(path_str))?;
+/// entries of `d`, and the subtrees below them
+fn traverse_entries(
+    st: &mut TraverseState,
+    free_thread_queue_rx: &Receiver<Sender<Task>>,
+    d: &Dir,
+    depth: usize,
+    ignores: Option<Arc<IgnoreStack>>,
+) -> Result<(), crate::error::E> {
+    let entries = d.read_dir_all()?;
+
+    let ignores = if st.ctx.opts.ignore_files {
+        read_ignore_files(d, &entries, ignores)
+    } else {
+        ignores
+    };
+
+    let mut items: Vec<SortItem> = entries
+        .into_iter()
+        .map(|e| SortItem {
+            file_type: e.file_type().map(FileType::from),
+            entry: e,
+            metadata: None,
+        })
+        .collect();
+    // entries failed to stat are reported before the others, and skipped
+    let mut errors = Vec::new();
+    if let Some(s) = &st.ctx.sorter {
+        items.retain_mut(|it| {
+            if s.needs_stat() || (s.needs_type() && it.file_type.is_none()) {
+                match d.stat_at(&it.entry) {
+                    Ok(m) => {
+                        it.file_type = Some(FileType::from_stat(&m));
+                        it.metadata = Some(m);
+                    }
+                    Err(e) => {
+                        errors.push(e);
+                        return false;
+                    }
                 }
+            }
+            true
+        });
+        s.sort(&mut items);
+    }
+    for e in errors {
+        st.push_postproc(TaskPostProc::Error(e))?;
+    }
 
-                match t {
-                    nix::dir::Type::Directory => {
-                        let nt = free_thread_queue_rx.try_recv();
+    for it in items {
+        if st.ctx.sink.failed() {
+            return Ok(());
+        }
 
-                        match nt {
-                            Ok(t) => {
-                                st.pump(false)?;
-                                let (new_pred, new_succ, new_key) = st.gen_chain();
+        let e = it.entry;
+        let mut metadata = it.metadata;
+        let t = match it.file_type {
+            Some(t) => t,
+            None => {
+                let m = match d.stat_at(&e) {
+                    Ok(m) => m,
+                    // e.g. removed since it was read. reported, and the traversal goes on
+                    Err(e) => {
+                        st.push_postproc(TaskPostProc::Error(e))?;
+                        continue;
+                    }
+                };
+                let t = FileType::from_stat(&m);
+                metadata = Some(m);
+                t
+            }
+        };
+        let path = d.entry_abspath(&e);
 
-                                let read_child = Task::ReadDir {
-                                    parent_dir: Some(d.clone()),
-                                    path: crate::pathstr::entry_to_path(&e).to_owned(),
-                                    dep_pred: new_pred,
-                                    dep_succ: new_succ,
-                                    key: new_key,
-                                };
+        if st.ctx.is_excluded(&path, t, ignores.as_deref()) {
+            continue;
+        }
 
-                                t.send(read_child).unwrap();
-                            }
+        let is_dir = t == FileType::Directory;
+        if metadata.is_none() && (st.ctx.needs_stat() || (is_dir && st.ctx.needs_dir_stat())) {
+            match d.stat_at(&e) {
+                Ok(m) => metadata = Some(m),
+                Err(e) => {
+                    st.push_postproc(TaskPostProc::Error(e))?;
+                    continue;
+                }
+            }
+        }
+        if is_dir && !st.ctx.fstype_allowed(metadata.as_ref()) {
+            continue;
+        }
+        let target = Target {
+            path: &path,
+            file_type: t,
+            metadata: metadata.as_ref(),
+        };
+        let matched = st.ctx.in_depth_window(depth + 1)
+            && match &st.ctx.filter {
+                Some(f) => f.matches(&target),
+                None => true,
+            };
+        // a mount point is output, but not descended with --one-file-system
+        let descend = is_dir
+            && !st.ctx.prune.as_ref().is_some_and(|p| p.matches(&target))
+            && !(st.ctx.opts.one_file_system
+                && metadata.as_ref().is_some_and(|m| m.st_dev != d.root_dev()));
+
+        let mut visit = matched.then(|| {
+            TaskPostProc::Visit(Entry {
+                path,
+                file_type: t,
+                depth: depth + 1,
+                metadata: if st.ctx.sink.wants_metadata {
+                    metadata
+                } else {
+                    None
+                },
+                root_len: d.root_len(),
+            })
+        });
+        if !descend {
+            if let (Some(a), Some(TaskPostProc::Visit(entry))) = (&st.ctx.sink.action, &visit) {
+                if !a.entry(d, e.file_name(), entry, metadata.as_ref())? {
+                    visit = None;
+                }
+            }
+        }
+        if descend {
+            if let (Some(a), Some(TaskPostProc::Visit(entry))) = (&st.ctx.sink.action, &visit) {
+                a.enter_dir(d, e.file_name(), entry)?;
+            }
+        }
+        if !st.ctx.post_order {
+            if let Some(v) = visit.take() {
+                st.push_postproc(v)?;
+            }
+        }
 
-                            Err(_) => {
-                                // traverse in own thread
-                                traverse_dir(
-                                    st,
-                                    &free_thread_queue_rx,
-                                    Some(&d),
-                                    crate::pathstr::entry_to_path(&e),
-                                )?;
-                            }
-                        }
-                    }
-                    _ => {}
+        if descend {
+            let nt = free_thread_queue_rx.try_recv();
+
+            match nt {
+                Ok(t) if st.ctx.unordered => {
+                    st.ctx.tasks.add();
+                    t.send(Task::ReadDir {
+                        parent_dir: Some(d.clone()),
+                        path: crate::pathstr::entry_to_path(&e).to_owned(),
+                        dep_pred: events::DepChain::new_dummy(),
+                        dep_succ: events::DepChain::new_dummy(),
+                        key: ReorderKey(vec![]),
+                        depth: depth + 1,
+                        ignores: ignores.clone(),
+                    })
+                    .unwrap();
+                }
+                Ok(t) => {
+                    st.pump(false)?;
+                    let (new_pred, new_succ, new_key) = st.gen_chain();
+
+                    let read_child = Task::ReadDir {
+                        parent_dir: Some(d.clone()),
+                        path: crate::pathstr::entry_to_path(&e).to_owned(),
+                        dep_pred: new_pred,
+                        dep_succ: new_succ,
+                        key: new_key,
+                        depth: depth + 1,
+                        ignores: ignores.clone(),
+                    };
+
+                    t.send(read_child).unwrap();
+                }
+
+                Err(_) => {
+                    // traverse in own thread
+                    traverse_dir(
+                        st,
+                        free_thread_queue_rx,
+                        Some(d),
+                        crate::pathstr::entry_to_path(&e),
+                        depth + 1,
+                        ignores.clone(),
+                    )?;
                 }
             }
         }
+
+        // after the subtree. a spawned subtree comes first by the chain
+        if let Some(v) = visit {
+            let v = match v {
+                TaskPostProc::Visit(entry) if descend && st.ctx.sink.action.is_some() => {
+                    TaskPostProc::LeaveDir {
+                        dir: d.clone(),
+                        name: e.file_name().to_owned(),
+                        entry,
+                    }
+                }
+                v => v,
+            };
+            st.push_postproc(v)?;
+        }
     }
 
     Ok(())
@@ -291,12 +711,36 @@ fn run_1task(
         #[cfg(test)]
         Task::Nop => {}
         Task::Quit => return Ok(true),
+        Task::ReadDir {
+            parent_dir,
+            path,
+            depth,
+            ignores,
+            ..
+        } if st.ctx.unordered => {
+            let r = traverse_dir(
+                st,
+                free_thread_queue_rx,
+                parent_dir.as_ref(),
+                &path,
+                depth,
+                ignores,
+            );
+            if let Err(e) = r {
+                st.ctx.sink.set_error(e);
+            }
+            let r = st.flush_cur_postprocs();
+            st.ctx.tasks.done();
+            r?;
+        }
         Task::ReadDir {
             parent_dir,
             path,
             dep_pred,
             dep_succ,
             mut key,
+            depth,
+            ignores,
         } => {
             //println!("{}: start path={:?}, pred={:?}, succ={:?}, key={:?}",
             //         st.tid,
@@ -319,19 +763,24 @@ fn run_1task(
             st.current = cur_dep;
 
             //println!("{}:traverse start {:?} {:?}", st.tid, path, st.current_key);
-            let mut err = traverse_dir(st, &free_thread_queue_rx, parent_dir.as_ref(), &path);
+            let r = traverse_dir(
+                st,
+                free_thread_queue_rx,
+                parent_dir.as_ref(),
+                &path,
+                depth,
+                ignores,
+            );
             //println!("{}:traverse finish {:?}", st.tid, path);
-
-            if let Ok(_) = err {
-                let mut cur = st.current.borrow_mut();
-                cur.fixup(dep_succ);
-                drop(cur);
-                err = st.pump(false).map(|_| ());
+            if let Err(e) = r {
+                // keep the chain going so that waiters are not blocked forever
+                st.ctx.sink.set_error(e);
             }
 
-            if let Err(e) = err {
-                return Err(e);
-            }
+            let mut cur = st.current.borrow_mut();
+            cur.fixup(dep_succ);
+            drop(cur);
+            st.pump(false)?;
         }
     };
 
@@ -340,14 +789,14 @@ fn run_1task(
 
 impl TraverseThread {
     fn new(
-        opts: Options,
+        ctx: Arc<Context>,
         free_thread_queue: (Sender<Sender<Task>>, Receiver<Sender<Task>>),
         tid: usize,
     ) -> TraverseThread {
         let th = thread::spawn(move || -> Result<(), error::E> {
             let mut st = TraverseState {
                 tid,
-                opts: &opts,
+                ctx: &ctx,
                 pendings: std::collections::BTreeSet::new(),
                 current: Rc::new(RefCell::new(DepPostProcs {
                     current: true,
@@ -417,13 +866,13 @@ pub struct ThreadList {
 }
 
 impl ThreadList {
-    fn new(opts: Options) -> ThreadList {
+    fn new(ctx: Arc<Context>) -> ThreadList {
         let mut v = Vec::new();
         let free_thread_queue = crossbeam::channel::unbounded();
 
-        for id in 0..opts.num_threads {
+        for id in 0..ctx.opts.num_threads {
             v.push(TraverseThread::new(
-                opts.clone(),
+                ctx.clone(),
                 free_thread_queue.clone(),
                 id,
             ));
@@ -470,6 +919,8 @@ pub enum Task {
         dep_pred: events::DepChain,
         dep_succ: events::DepChain,
         key: ReorderKey,
+        depth: usize,
+        ignores: Option<Arc<IgnoreStack>>,
     },
     #[cfg(test)]
     Nop,
@@ -478,28 +929,164 @@ pub enum Task {
 }
 
 pub fn traverse(t: &mut Traverser) -> Result<(), error::E> {
-    let tl = ThreadList::new(t.opt.clone());
+    let v = crate::printer::default_visitor(&t.opt)?;
+    traverse_with_visitor(t, v)
+}
 
-    let final_dep = events::DepChain::new();
-    let mut root_first = events::DepChain::new();
+/// traverse the root directories of `t.opt` and call `visitor` for each entry
+pub fn traverse_with_visitor(
+    t: &mut Traverser,
+    mut visitor: Box<dyn Visitor>,
+) -> Result<(), error::E> {
+    let roots = t.opt.root_paths()?;
+    visitor.start(&roots)?;
+    let ctx = Arc::new(Context::new(t.opt.clone(), visitor)?);
+    let tl = ThreadList::new(ctx.clone());
+
+    if ctx.unordered {
+        // held until all roots are sent, so that the count does not reach 0 in between
+        ctx.tasks.add();
+        for root in roots {
+            ctx.tasks.add();
+            tl.pop_free_thread()?
+                .send(Task::ReadDir {
+                    parent_dir: None,
+                    path: root,
+                    dep_pred: events::DepChain::new_dummy(),
+                    dep_succ: events::DepChain::new_dummy(),
+                    key: ReorderKey(vec![]),
+                    depth: 0,
+                    ignores: None,
+                })
+                .unwrap();
+        }
+        ctx.tasks.done();
+        ctx.tasks.wait();
+        drop(tl);
+        return ctx.sink.finish();
+    }
 
-    root_first.notify_complete();
+    let mut pred = events::DepChain::new();
+    pred.notify_complete();
+
+    // each root has its own top level key, and roots are chained in the argument order.
+    // segments of a task are keyed by incrementing the last element of its key,
+    // so a root key needs one more level to not collide with the next root.
+    for (i, root) in roots.into_iter().enumerate() {
+        let succ = events::DepChain::new();
+
+        let read_root = Task::ReadDir {
+            parent_dir: None,
+            path: root,
+            dep_pred: pred,
+            dep_succ: succ.clone(),
+            key: ReorderKey(vec![i, 0]),
+            depth: 0,
+            ignores: None,
+        };
+
+        let ft = tl.pop_free_thread()?;
+        ft.send(read_root).unwrap();
+
+        pred = succ;
+    }
 
-    let read_root = Task::ReadDir {
-        parent_dir: None,
-        path: t.opt.src_path.to_owned(),
-        dep_pred: root_first,
-        dep_succ: final_dep.clone(),
-        key: ReorderKey(vec![0]),
-    };
+    pred.wait();
+    drop(tl);
 
-    let ft = tl.pop_free_thread()?;
+    ctx.sink.finish()
+}
 
-    ft.send(read_root).unwrap();
+const WALK_CHANNEL_CAPACITY: usize = 1024;
 
-    final_dep.wait();
+struct ChannelVisitor {
+    tx: Sender<Result<Entry, error::E>>,
+    with_metadata: bool,
+}
 
-    Ok(())
+impl Visitor for ChannelVisitor {
+    fn wants_metadata(&self) -> bool {
+        self.with_metadata
+    }
+
+    fn visit(&mut self, entry: &Entry) -> Result<(), error::E> {
+        self.tx
+            .send(Ok(entry.clone()))
+            .map_err(|_| error::E::Cancelled)
+    }
+
+    fn error(&mut self, e: &error::E) -> Result<(), error::E> {
+        // the reported errors are about an entry. the others are kept as a message
+        let e = match e {
+            error::E::OpenDirError { path, eno } => error::E::OpenDirError {
+                path: path.clone(),
+                eno: *eno,
+            },
+            error::E::StatError { path, eno } => error::E::StatError {
+                path: path.clone(),
+                eno: *eno,
+            },
+            e => error::E::GenericIOError {
+                eno: std::io::Error::other(e.to_string()),
+            },
+        };
+        self.tx.send(Err(e)).map_err(|_| error::E::Cancelled)
+    }
+}
+
+/// Iterator over the entries of a traversal, returned by `walk`.
+///
+/// Entries come in the order given by `Options::order`.
+/// Ignored errors, e.g. by `--ignore-eaccess`, come as `Err` in order, and the walk goes on.
+/// Dropping it stops the traverse threads.
+pub struct Walk {
+    rx: Receiver<Result<Entry, error::E>>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Iterator for Walk {
+    type Item = Result<Entry, error::E>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.rx.recv().ok()
+    }
+}
+
+impl Drop for Walk {
+    fn drop(&mut self) {
+        // disconnect the channel to make the pending and later sends fail
+        let (_, rx) = crossbeam::channel::bounded(0);
+        drop(std::mem::replace(&mut self.rx, rx));
+
+        if let Some(th) = self.thread.take() {
+            // a panic of the traversal is not raised again while dropping
+            let _ = th.join();
+        }
+    }
+}
+
+/// traverse in background threads and return the entries as an iterator.
+/// A traversal error is returned as the last item.
+pub fn walk(mut t: Traverser, with_metadata: bool) -> Walk {
+    let (tx, rx) = crossbeam::channel::bounded(WALK_CHANNEL_CAPACITY);
+
+    let th = thread::spawn(move || {
+        let v = ChannelVisitor {
+            tx: tx.clone(),
+            with_metadata,
+        };
+        match traverse_with_visitor(&mut t, Box::new(v)) {
+            Ok(()) | Err(error::E::Cancelled) => {}
+            Err(e) => {
+                let _ = tx.send(Err(e));
+            }
+        }
+    });
+
+    Walk {
+        rx,
+        thread: Some(th),
+    }
 }
 
 #[cfg(test)]
@@ -508,15 +1095,294 @@ mod tests {
     #[test]
     fn t() -> Result<(), error::E> {
         let mut opts = crate::options::test_option(".");
-        let nthread = 16;
         opts.num_threads = 16;
-        let tl = ThreadList::new(opts);
+        let ctx = Context::new(opts, Box::new(crate::visitor::NullVisitor))?;
+        let tl = ThreadList::new(Arc::new(ctx));
 
-        for i in 0..4096 {
+        for _ in 0..4096 {
             let f = tl.pop_free_thread()?;
-            f.send(Task::Nop);
+            f.send(Task::Nop).unwrap();
         }
 
         Ok(())
     }
+
+    struct Collect(Arc<Mutex<Vec<Entry>>>);
+
+    impl Visitor for Collect {
+        fn wants_metadata(&self) -> bool {
+            true
+        }
+        fn visit(&mut self, entry: &Entry) -> Result<(), error::E> {
+            self.0.lock().unwrap().push(entry.clone());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn visitor_order() -> Result<(), error::E> {
+        let root = crate::options::test_tree("visitor_order", &["b/y", "a/x", "a/z/w", "c"]);
+        let mut opts = crate::options::test_option(root.to_str().unwrap());
+        opts.order = Order::Alphabetical;
+        opts.num_threads = 4;
+
+        let v = Arc::new(Mutex::new(Vec::new()));
+        traverse_with_visitor(&mut Traverser { opt: opts }, Box::new(Collect(v.clone())))?;
+
+        let v = v.lock().unwrap();
+        let paths: Vec<_> = v
+            .iter()
+            .map(|e| e.path.strip_prefix(&root).unwrap().to_str().unwrap())
+            .collect();
+        assert_eq!(paths, ["a", "a/x", "a/z", "a/z/w", "b", "b/y", "c"]);
+        assert!(v.iter().all(|e| e.metadata.is_some()));
+        assert_eq!(v[0].file_type, FileType::Directory);
+        assert_eq!(v[1].file_type, FileType::File);
+
+        Ok(())
+    }
+
+    #[test]
+    fn multiple_roots() -> Result<(), error::E> {
+        let root = crate::options::test_tree("multiple_roots", &["r1/x/y", "r2/z", "r3/w"]);
+        let mut opts = crate::options::test_option(root.join("r3").to_str().unwrap());
+        opts.src_paths.push(root.join("r1"));
+        opts.src_paths.push(root.join("r2"));
+        opts.order = Order::Alphabetical;
+        opts.num_threads = 2;
+
+        let paths = walk(Traverser { opt: opts }, false)
+            .map(|e| e.map(|e| e.path))
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(
+            paths,
+            ["r3/w", "r1/x", "r1/x/y", "r2/z"].map(|p| root.join(p))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn unordered() -> Result<(), error::E> {
+        let files: Vec<String> = (0..20)
+            .flat_map(|i| (0..20).map(move |j| format!("d{}/e{}/f", i, j)))
+            .collect();
+        let files: Vec<&str> = files.iter().map(|f| f.as_str()).collect();
+        let root = crate::options::test_tree("unordered", &files);
+        let mut opts = crate::options::test_option(root.to_str().unwrap());
+        opts.src_paths.push(root.join("d0"));
+        opts.num_threads = 4;
+
+        let mut paths = walk(Traverser { opt: opts.clone() }, false)
+            .map(|e| e.map(|e| e.path))
+            .collect::<Result<Vec<_>, _>>()?;
+        paths.sort();
+
+        opts.order = Order::Alphabetical;
+        let mut expected = walk(Traverser { opt: opts }, false)
+            .map(|e| e.map(|e| e.path))
+            .collect::<Result<Vec<_>, _>>()?;
+        expected.sort();
+        assert_eq!(paths.len(), 20 * 20 * 2 + 20 + 20 * 2);
+        assert_eq!(paths, expected);
+
+        Ok(())
+    }
+
+    #[test]
+    fn sort() -> Result<(), error::E> {
+        use crate::sort::SortKey;
+
+        let root = crate::options::test_tree("sort", &["f10", "f9", "d2/", "d10/", "big"]);
+        std::fs::write(root.join("big"), [0u8; 100]).unwrap();
+        std::fs::write(root.join("f9"), [0u8; 10]).unwrap();
+        let names = |sort: SortKey, dirs_first: bool, reverse: bool| {
+            let mut opts = crate::options::test_option(root.to_str().unwrap());
+            opts.order = Order::Readdir;
+            opts.num_threads = 2;
+            opts.sort = Some(sort);
+            opts.dirs_first = dirs_first;
+            opts.reverse = reverse;
+            walk(Traverser { opt: opts }, false)
+                .map(|e| {
+                    e.unwrap()
+                        .path
+                        .file_name()
+                        .unwrap()
+                        .to_str()
+                        .unwrap()
+                        .to_owned()
+                })
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            names(SortKey::Name, false, false),
+            ["big", "d10", "d2", "f10", "f9"]
+        );
+        assert_eq!(
+            names(SortKey::Version, true, false),
+            ["d2", "d10", "big", "f9", "f10"]
+        );
+        assert_eq!(
+            names(SortKey::Version, true, true),
+            ["d10", "d2", "f10", "f9", "big"]
+        );
+        // directories are sized by the filesystem
+        let by_size = names(SortKey::Size, false, false);
+        let files: Vec<_> = by_size.iter().filter(|n| !n.starts_with('d')).collect();
+        assert_eq!(files, ["f10", "f9", "big"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn post_order() -> Result<(), error::E> {
+        let root = crate::options::test_tree("post_order", &["a/b/c", "a/d", "e/f/", "g"]);
+        for num_threads in [1, 4] {
+            let mut opts = crate::options::test_option(root.to_str().unwrap());
+            opts.order = Order::Alphabetical;
+            opts.num_threads = num_threads;
+            opts.min_depth = Some(0);
+            opts.post_order = true;
+
+            let paths = walk(Traverser { opt: opts }, false)
+                .map(|e| e.map(|e| e.path))
+                .collect::<Result<Vec<_>, _>>()?;
+            assert_eq!(
+                paths,
+                ["a/b/c", "a/b", "a/d", "a", "e/f", "e", "g", ""].map(|p| root.join(p))
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn filter() -> Result<(), error::E> {
+        let root = crate::options::test_tree("filter", &["a/x.rs", "a/y.txt", "b/c/z.rs", "e/"]);
+        let mut opts = crate::options::test_option(root.to_str().unwrap());
+        opts.order = Order::Alphabetical;
+        opts.filter = Some("-name '*.rs' -o -type d -empty".to_owned());
+
+        let paths = walk(Traverser { opt: opts }, false)
+            .map(|e| e.map(|e| e.path))
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(paths, ["a/x.rs", "b/c/z.rs", "e"].map(|p| root.join(p)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn prune() -> Result<(), error::E> {
+        let root = crate::options::test_tree(
+            "prune",
+            &[
+                ".gitignore",
+                "a/.ignore",
+                "a/x.o",
+                "a/y.o",
+                "a/z.c",
+                "node_modules/m",
+                "p/q/r",
+                "s/t",
+            ],
+        );
+        std::fs::write(root.join(".gitignore"), "*.o\n").unwrap();
+        std::fs::write(root.join("a/.ignore"), "!y.o\nz.c\n").unwrap();
+
+        let mut opts = crate::options::test_option(root.to_str().unwrap());
+        opts.order = Order::Alphabetical;
+        opts.exclude = vec!["node_modules".to_owned(), "*/s".to_owned()];
+        opts.prune = Some("-name q".to_owned());
+        opts.ignore_files = true;
+
+        let paths = walk(Traverser { opt: opts }, false)
+            .map(|e| e.map(|e| e.path))
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(
+            paths,
+            [".gitignore", "a", "a/.ignore", "a/y.o", "p", "p/q"].map(|p| root.join(p))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn depth() -> Result<(), error::E> {
+        let root = crate::options::test_tree("depth", &["a/b/c", "d"]);
+        let mut opts = crate::options::test_option(root.to_str().unwrap());
+        opts.order = Order::Alphabetical;
+        opts.min_depth = Some(0);
+        opts.max_depth = Some(2);
+
+        let v = walk(Traverser { opt: opts.clone() }, false).collect::<Result<Vec<_>, _>>()?;
+        let v: Vec<_> = v.iter().map(|e| (e.path.clone(), e.depth)).collect();
+        assert_eq!(
+            v,
+            [("", 0), ("a", 1), ("a/b", 2), ("d", 1)].map(|(p, d)| (root.join(p), d))
+        );
+
+        opts.min_depth = Some(2);
+        opts.max_depth = None;
+        let paths = walk(Traverser { opt: opts }, false)
+            .map(|e| e.map(|e| e.path))
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(paths, ["a/b", "a/b/c"].map(|p| root.join(p)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn walk_iter() -> Result<(), error::E> {
+        let root = crate::options::test_tree("walk_iter", &["b/y", "a/x", "c"]);
+        let mut opts = crate::options::test_option(root.to_str().unwrap());
+        opts.order = Order::Alphabetical;
+        opts.num_threads = 4;
+
+        let paths = walk(Traverser { opt: opts.clone() }, false)
+            .map(|e| e.map(|e| e.path))
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(paths, ["a", "a/x", "b", "b/y", "c"].map(|p| root.join(p)));
+
+        // stop early
+        let first = walk(Traverser { opt: opts.clone() }, false)
+            .next()
+            .unwrap()?;
+        assert_eq!(first.path, root.join("a"));
+
+        opts.src_paths = vec![root.join("nonexistent")];
+        let r: Vec<_> = walk(Traverser { opt: opts }, false).collect();
+        assert!(matches!(r[..], [Err(error::E::OpenDirError { .. })]));
+
+        Ok(())
+    }
+
+    #[test]
+    fn walk_ignored_error() {
+        let (tx, rx) = crossbeam::channel::bounded(1);
+        let mut v = ChannelVisitor {
+            tx,
+            with_metadata: false,
+        };
+        v.error(&error::E::OpenDirError {
+            path: "a".into(),
+            eno: nix::errno::Errno::EACCES,
+        })
+        .unwrap();
+        assert!(matches!(
+            rx.try_recv(),
+            Ok(Err(error::E::OpenDirError {
+                eno: nix::errno::Errno::EACCES,
+                ..
+            }))
+        ));
+
+        // the walk was dropped
+        drop(rx);
+        assert!(matches!(
+            v.error(&error::E::Cancelled),
+            Err(error::E::Cancelled)
+        ));
+    }
 }
@@ -0,0 +1,274 @@
+//! `Method::TreeHash`. one hash of the whole tree below each root directory, computed bottom-up.
+//!
+//! Each entry is encoded as a record, and a directory hash is the hash of the records of its entries
+//! sorted by name byte-wise. So the hash does not depend on `--order`, the number of threads, or the root path.
+//!
+//! record = type (1 byte: `f`, `d`, `l`, `p`, `s`, `c` or `b`)
+//!        ‖ permission bits of `st_mode` (u32)
+//!        ‖ length of the name (u64) ‖ name
+//!        ‖ length of the payload (u64) ‖ payload
+//!
+//! integers are big endian. the payload is the content hash of a file, the hash of a directory,
+//! the hash of the target of a symlink, `st_rdev` (u64) of a device, and empty for others.
+//! Owners and times are not included.
+
+use crate::checksum::manifest_line;
+use crate::error;
+use crate::hash::{ContentHashes, HashAlgorithm, Hasher};
+use crate::pathstr::PathFormat;
+use crate::record::{Format, Output, Record, CHECKSUM_COLUMNS};
+use crate::visitor::{Action, Entry, FileType, Visitor};
+use nix::sys::stat::FileStat;
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::sync::Arc;
+
+fn type_char(t: FileType) -> u8 {
+    match t {
+        FileType::File => b'f',
+        FileType::Directory => b'd',
+        FileType::Symlink => b'l',
+        FileType::Fifo => b'p',
+        FileType::Socket => b's',
+        FileType::CharacterDevice => b'c',
+        FileType::BlockDevice => b'b',
+    }
+}
+
+fn encode(t: FileType, st: Option<&FileStat>, name: &[u8], payload: &[u8]) -> Vec<u8> {
+    let mut r = Vec::with_capacity(21 + name.len() + payload.len());
+    r.push(type_char(t));
+    r.extend_from_slice(&st.map_or(0, |st| st.st_mode & 0o7777).to_be_bytes());
+    r.extend_from_slice(&(name.len() as u64).to_be_bytes());
+    r.extend_from_slice(name);
+    r.extend_from_slice(&(payload.len() as u64).to_be_bytes());
+    r.extend_from_slice(payload);
+    r
+}
+
+struct DirState {
+    path: Vec<u8>,
+    name: Vec<u8>,
+    depth: usize,
+    metadata: Option<FileStat>,
+    /// (name, record) of the entries
+    children: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+/// Entries come in pre-order like `DiskUsage`, so a directory is complete when an entry
+/// at the same or shallower depth arrives.
+pub struct TreeHasher {
+    out: Output,
+    hashes: Arc<ContentHashes>,
+    per_dir: bool,
+    min_depth: usize,
+    max_depth: Option<usize>,
+    path_format: PathFormat,
+    stack: Vec<DirState>,
+    errors: usize,
+    buf: Vec<u8>,
+}
+
+impl TreeHasher {
+    pub fn new(out: Box<dyn Write + Send>, algorithm: HashAlgorithm) -> TreeHasher {
+        TreeHasher {
+            out: Output::Text(out),
+            hashes: Arc::new(ContentHashes::new(algorithm, true)),
+            per_dir: false,
+            min_depth: 0,
+            max_depth: None,
+            path_format: PathFormat::default(),
+            stack: Vec::new(),
+            errors: 0,
+            buf: Vec::new(),
+        }
+    }
+
+    /// print directories in the depth window too, after the entries in them
+    pub fn per_dir(mut self, min_depth: usize, max_depth: Option<usize>) -> Self {
+        self.per_dir = true;
+        self.min_depth = min_depth;
+        self.max_depth = max_depth;
+        self
+    }
+
+    pub fn format(mut self, format: Format) -> Self {
+        self.out = self.out.with_format(format, CHECKSUM_COLUMNS);
+        self
+    }
+
+    pub fn path_format(mut self, f: PathFormat) -> Self {
+        self.path_format = f;
+        self
+    }
+
+    /// blocks of a file read ahead of the hashing
+    pub fn ioreq_depth(mut self, n: usize) -> Self {
+        Arc::get_mut(&mut self.hashes).unwrap().ioreq_depth = n;
+        self
+    }
+
+    fn pop(&mut self) -> Result<(), error::E> {
+        let mut d = self.stack.pop().unwrap();
+        d.children.sort_by(|l, r| l.0.cmp(&r.0));
+        let mut h = Hasher::new(self.hashes.algorithm);
+        for (_, r) in &d.children {
+            h.update(r);
+        }
+        let hash = h.finish();
+
+        if let Some(parent) = self.stack.last_mut() {
+            let r = encode(FileType::Directory, d.metadata.as_ref(), &d.name, &hash);
+            parent.children.push((d.name, r));
+        }
+
+        let print = if self.per_dir {
+            self.min_depth <= d.depth && self.max_depth.is_none_or(|max| d.depth <= max)
+        } else {
+            d.depth == 0
+        };
+        if print {
+            match &mut self.out {
+                Output::Text(out) => {
+                    self.buf.clear();
+                    manifest_line(&hash, &d.path, &mut self.buf);
+                    error::maybe_generic_io_error(out.write_all(&self.buf))?;
+                }
+                Output::Records(w) => w.write(&Record::tree_hash(
+                    &d.path,
+                    self.hashes.algorithm.name(),
+                    &hash,
+                ))?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Visitor for TreeHasher {
+    fn wants_metadata(&self) -> bool {
+        true
+    }
+
+    fn wants_all_depths(&self) -> bool {
+        true
+    }
+
+    fn requires_order(&self) -> bool {
+        true
+    }
+
+    fn action(&self) -> Option<Arc<dyn Action>> {
+        Some(self.hashes.clone())
+    }
+
+    fn visit(&mut self, entry: &Entry) -> Result<(), error::E> {
+        while self.stack.last().is_some_and(|d| d.depth >= entry.depth) {
+            self.pop()?;
+        }
+
+        let name = entry
+            .path
+            .file_name()
+            .map(|n| n.as_bytes().to_vec())
+            .unwrap_or_default();
+        if entry.file_type == FileType::Directory {
+            let mut path = Vec::new();
+            self.path_format.push(entry, &mut path);
+            self.stack.push(DirState {
+                path,
+                name,
+                depth: entry.depth,
+                metadata: entry.metadata,
+                children: Vec::new(),
+            });
+            return Ok(());
+        }
+
+        let payload = match entry.file_type {
+            FileType::CharacterDevice | FileType::BlockDevice => entry
+                .metadata
+                .map_or(0, |st| st.st_rdev)
+                .to_be_bytes()
+                .to_vec(),
+            _ => match self.hashes.take(entry) {
+                Some(Ok(h)) => h,
+                Some(Err(eno)) => {
+                    self.errors += 1;
+                    self.out.error(&error::E::ReadFileError {
+                        path: entry.path.clone(),
+                        eno,
+                    })?;
+                    Vec::new()
+                }
+                None => Vec::new(),
+            },
+        };
+        let r = encode(entry.file_type, entry.metadata.as_ref(), &name, &payload);
+        if let Some(parent) = self.stack.last_mut() {
+            parent.children.push((name, r));
+        }
+        Ok(())
+    }
+
+    fn error(&mut self, e: &error::E) -> Result<(), error::E> {
+        self.errors += 1;
+        self.out.error(e)
+    }
+
+    /// the hash is printed even with errors, but it is not of the whole tree
+    fn finish(&mut self) -> Result<(), error::E> {
+        while !self.stack.is_empty() {
+            self.pop()?;
+        }
+        self.out.flush()?;
+        if self.errors > 0 {
+            return Err(error::E::Incomplete {
+                errors: self.errors,
+            });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::options::Order;
+    use crate::printer::test_run;
+    use std::path::Path;
+
+    fn tree_hash(root: &Path, order: Order, num_threads: usize) -> String {
+        let mut opts = crate::options::test_option(root.to_str().unwrap());
+        opts.order = order;
+        opts.num_threads = num_threads;
+        let (out, r) = test_run(opts, |out| TreeHasher::new(out, HashAlgorithm::Sha256));
+        r.unwrap();
+        out.split_once("  ").unwrap().0.to_owned()
+    }
+
+    #[test]
+    fn deterministic() {
+        let files: Vec<String> = (0..10)
+            .flat_map(|i| (0..10).map(move |j| format!("d{}/e{}", i, j)))
+            .collect();
+        let files: Vec<&str> = files.iter().map(|f| f.as_str()).collect();
+        let a = crate::options::test_tree("treehash_a", &files);
+        let b = crate::options::test_tree("treehash_b", &files);
+        std::os::unix::fs::symlink("d0", a.join("l")).unwrap();
+        std::os::unix::fs::symlink("d0", b.join("l")).unwrap();
+
+        let h = tree_hash(&a, Order::Alphabetical, 1);
+        assert_eq!(h, tree_hash(&a, Order::Readdir, 4));
+        assert_eq!(h, tree_hash(&b, Order::Unordered, 4));
+
+        std::fs::write(b.join("d3/e3"), "x").unwrap();
+        let h2 = tree_hash(&b, Order::Readdir, 4);
+        assert_ne!(h, h2);
+
+        std::fs::write(b.join("d3/e3"), "d3/e3").unwrap();
+        std::fs::remove_file(b.join("l")).unwrap();
+        std::os::unix::fs::symlink("d1", b.join("l")).unwrap();
+        assert_ne!(h, tree_hash(&b, Order::Readdir, 4));
+    }
+}
@@ -0,0 +1,236 @@
+use crate::dir::Dir;
+use crate::error;
+use nix::sys::stat::{FileStat, SFlag};
+use std::ffi::{CStr, OsStr};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FileType {
+    Fifo,
+    CharacterDevice,
+    Directory,
+    BlockDevice,
+    File,
+    Symlink,
+    Socket,
+}
+
+impl From<nix::dir::Type> for FileType {
+    fn from(t: nix::dir::Type) -> FileType {
+        match t {
+            nix::dir::Type::Fifo => FileType::Fifo,
+            nix::dir::Type::CharacterDevice => FileType::CharacterDevice,
+            nix::dir::Type::Directory => FileType::Directory,
+            nix::dir::Type::BlockDevice => FileType::BlockDevice,
+            nix::dir::Type::File => FileType::File,
+            nix::dir::Type::Symlink => FileType::Symlink,
+            nix::dir::Type::Socket => FileType::Socket,
+        }
+    }
+}
+
+impl FileType {
+    pub fn from_stat(st: &FileStat) -> FileType {
+        match SFlag::from_bits_truncate(st.st_mode) & SFlag::S_IFMT {
+            SFlag::S_IFIFO => FileType::Fifo,
+            SFlag::S_IFCHR => FileType::CharacterDevice,
+            SFlag::S_IFDIR => FileType::Directory,
+            SFlag::S_IFBLK => FileType::BlockDevice,
+            SFlag::S_IFLNK => FileType::Symlink,
+            SFlag::S_IFSOCK => FileType::Socket,
+            _ => FileType::File,
+        }
+    }
+}
+
+/// One directory entry found by the traversal.
+#[derive(Clone, Debug)]
+pub struct Entry {
+    pub path: PathBuf,
+    pub file_type: FileType,
+    /// 0 for a root directory, 1 for entries in it, and so on
+    pub depth: usize,
+    /// `lstat` of the entry. filled only when `Visitor::wants_metadata` returns true
+    pub metadata: Option<FileStat>,
+    /// length in bytes of the root directory path, which `path` starts with
+    pub root_len: usize,
+}
+
+impl Entry {
+    /// path relative to the root directory. empty for the root itself
+    pub fn relative_path(&self) -> &Path {
+        let bytes = &self.path.as_os_str().as_bytes()[self.root_len..];
+        let bytes = bytes.strip_prefix(b"/").unwrap_or(bytes);
+        Path::new(OsStr::from_bytes(bytes))
+    }
+}
+
+/// Receives entries in the order given by `Options::order`.
+///
+/// Calls are serialized, but may come from any traverse thread.
+pub trait Visitor: Send {
+    fn wants_metadata(&self) -> bool {
+        false
+    }
+
+    /// Return true to receive entries outside of `--min-depth`/`--max-depth` too.
+    /// The traversal does not stop at max depth then, and the visitor applies the limits by itself.
+    fn wants_all_depths(&self) -> bool {
+        false
+    }
+
+    /// Return true when the visitor relies on pre-order, a directory followed by everything below it.
+    /// `Order::Unordered` gives readdir order then, instead of the fast path without ordering,
+    /// and `--post-order` is ignored.
+    fn requires_order(&self) -> bool {
+        false
+    }
+
+    /// Return true when a directory must be visited after everything below it, e.g. to remove it.
+    /// This gives `--post-order` with the ordered chain, even for `Order::Unordered`.
+    fn requires_post_order(&self) -> bool {
+        false
+    }
+
+    /// Work done by the traverse threads in parallel, before entries reach `visit`.
+    fn action(&self) -> Option<Arc<dyn Action>> {
+        None
+    }
+
+    /// Called once with the root directories, before any of them is read.
+    fn start(&mut self, _roots: &[PathBuf]) -> Result<(), error::E> {
+        Ok(())
+    }
+
+    fn visit(&mut self, entry: &Entry) -> Result<(), error::E>;
+
+    /// Called for an error ignored by the options, e.g. `--ignore-eaccess`.
+    fn error(&mut self, e: &error::E) -> Result<(), error::E> {
+        eprintln!("ignored error: {}", e);
+        Ok(())
+    }
+
+    /// Called once after the last entry.
+    fn finish(&mut self) -> Result<(), error::E> {
+        Ok(())
+    }
+}
+
+/// Called by the traverse thread that read an entry, without the lock of the visitor.
+///
+/// Directories descended into are not passed, because entries below them may be still
+/// in other threads. They are passed to `enter_dir` before they are read, and with post-order
+/// traversal to `leave_dir` instead.
+pub trait Action: Send + Sync {
+    /// `name` is the entry in `dir`. `metadata` is `lstat` of the entry when the traversal has it.
+    /// Return false to not visit the entry.
+    fn entry(
+        &self,
+        dir: &Dir,
+        name: &CStr,
+        entry: &Entry,
+        metadata: Option<&FileStat>,
+    ) -> Result<bool, error::E>;
+
+    /// Called for a directory descended into, before it is opened. `dir` is its parent.
+    fn enter_dir(&self, _dir: &Dir, _name: &CStr, _entry: &Entry) -> Result<(), error::E> {
+        Ok(())
+    }
+
+    /// Called for a directory descended into, with post-order traversal only, after the entries
+    /// below it are visited and right before it is. `dir` is its parent, kept open until then.
+    /// Called with the lock of the visitor. Not called for the roots.
+    /// Return false to not visit the directory.
+    fn leave_dir(&self, _dir: &Dir, _name: &CStr, _entry: &Entry) -> Result<bool, error::E> {
+        Ok(true)
+    }
+}
+
+/// Visitor that does nothing. used for methods without output
+pub struct NullVisitor;
+
+impl Visitor for NullVisitor {
+    fn visit(&mut self, _entry: &Entry) -> Result<(), error::E> {
+        Ok(())
+    }
+}
+
+/// Wraps the visitor of a test. `hook` is called before each `Action::entry`, e.g. to change
+/// the tree as if by another process while the traversal runs.
+#[cfg(test)]
+pub struct EntryHook<V> {
+    pub inner: V,
+    pub hook: Arc<dyn Fn(&Entry) + Send + Sync>,
+}
+
+#[cfg(test)]
+struct EntryHookAction {
+    inner: Arc<dyn Action>,
+    hook: Arc<dyn Fn(&Entry) + Send + Sync>,
+}
+
+#[cfg(test)]
+impl Action for EntryHookAction {
+    fn entry(
+        &self,
+        dir: &Dir,
+        name: &CStr,
+        entry: &Entry,
+        metadata: Option<&FileStat>,
+    ) -> Result<bool, error::E> {
+        (self.hook)(entry);
+        self.inner.entry(dir, name, entry, metadata)
+    }
+
+    fn enter_dir(&self, dir: &Dir, name: &CStr, entry: &Entry) -> Result<(), error::E> {
+        self.inner.enter_dir(dir, name, entry)
+    }
+
+    fn leave_dir(&self, dir: &Dir, name: &CStr, entry: &Entry) -> Result<bool, error::E> {
+        self.inner.leave_dir(dir, name, entry)
+    }
+}
+
+#[cfg(test)]
+impl<V: Visitor> Visitor for EntryHook<V> {
+    fn wants_metadata(&self) -> bool {
+        self.inner.wants_metadata()
+    }
+
+    fn wants_all_depths(&self) -> bool {
+        self.inner.wants_all_depths()
+    }
+
+    fn requires_order(&self) -> bool {
+        self.inner.requires_order()
+    }
+
+    fn requires_post_order(&self) -> bool {
+        self.inner.requires_post_order()
+    }
+
+    fn action(&self) -> Option<Arc<dyn Action>> {
+        Some(Arc::new(EntryHookAction {
+            inner: self.inner.action()?,
+            hook: self.hook.clone(),
+        }))
+    }
+
+    fn start(&mut self, roots: &[PathBuf]) -> Result<(), error::E> {
+        self.inner.start(roots)
+    }
+
+    fn visit(&mut self, entry: &Entry) -> Result<(), error::E> {
+        self.inner.visit(entry)
+    }
+
+    fn error(&mut self, e: &error::E) -> Result<(), error::E> {
+        self.inner.error(e)
+    }
+
+    fn finish(&mut self) -> Result<(), error::E> {
+        self.inner.finish()
+    }
+}
@@ -0,0 +1,98 @@
+//! Extended attributes of a path, without following symlinks.
+
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+fn cpath(path: &Path) -> io::Result<CString> {
+    CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))
+}
+
+/// call `f` with a growing buffer until the value fits
+fn read_sized(mut f: impl FnMut(*mut libc::c_void, usize) -> libc::ssize_t) -> io::Result<Vec<u8>> {
+    loop {
+        let n = f(std::ptr::null_mut(), 0);
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut buf = vec![0u8; n as usize];
+        let m = f(buf.as_mut_ptr() as *mut libc::c_void, buf.len());
+        if m >= 0 {
+            buf.truncate(m as usize);
+            return Ok(buf);
+        }
+        let e = io::Error::last_os_error();
+        // grew between the calls
+        if e.raw_os_error() != Some(libc::ERANGE) {
+            return Err(e);
+        }
+    }
+}
+
+/// names of the extended attributes
+pub fn list(path: &Path) -> io::Result<Vec<Vec<u8>>> {
+    let p = cpath(path)?;
+    let names = read_sized(|buf, len| unsafe {
+        libc::llistxattr(p.as_ptr(), buf as *mut libc::c_char, len)
+    })?;
+    Ok(names
+        .split(|c| *c == 0)
+        .filter(|n| !n.is_empty())
+        .map(|n| n.to_vec())
+        .collect())
+}
+
+pub fn get(path: &Path, name: &[u8]) -> io::Result<Vec<u8>> {
+    let p = cpath(path)?;
+    let n = CString::new(name).map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+    read_sized(|buf, len| unsafe { libc::lgetxattr(p.as_ptr(), n.as_ptr(), buf, len) })
+}
+
+/// all extended attributes as (name, value), sorted by name.
+/// empty when the filesystem does not support them
+pub fn get_all(path: &Path) -> io::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+    let mut names = match list(path) {
+        Ok(n) => n,
+        Err(e) if e.raw_os_error() == Some(libc::ENOTSUP) => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    names.sort();
+    let mut ret = Vec::with_capacity(names.len());
+    for name in names {
+        match get(path, &name) {
+            Ok(v) => ret.push((name, v)),
+            // removed after listing
+            Err(e) if e.raw_os_error() == Some(libc::ENODATA) => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(ret)
+}
+
+pub fn set(path: &Path, name: &[u8], value: &[u8]) -> io::Result<()> {
+    let p = cpath(path)?;
+    let n = CString::new(name).map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+    let r = unsafe {
+        libc::lsetxattr(
+            p.as_ptr(),
+            n.as_ptr(),
+            value.as_ptr() as *const libc::c_void,
+            value.len(),
+            0,
+        )
+    };
+    if r < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// set the extended attributes of `src` to `dst`. attributes only in `dst` are kept
+pub fn copy(src: &Path, dst: &Path) -> io::Result<()> {
+    for (name, value) in get_all(src)? {
+        set(dst, &name, &value)?;
+    }
+    Ok(())
+}
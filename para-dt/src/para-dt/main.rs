@@ -1,5 +1,7 @@
 use clap::Parser;
 use libpara_dt::traverse;
+use libpara_dt::checksum;
+use libpara_dt::printer;
 use libpara_dt::error;
 use libpara_dt::options;
 
@@ -7,8 +9,17 @@ fn main() -> Result<(), error::E> {
     let mut t = traverse::Traverser {
         opt: options::Options::parse(),
     };
+    t.opt.src_paths.append(&mut t.opt.src_path);
+    t.opt.validate()?;
 
-    traverse::traverse(&mut t)?;
+    match t.opt.method {
+        // no traversal. the files listed in the manifest are read
+        options::Method::Checksum {
+            algorithm,
+            check: Some(ref manifest),
+        } => checksum::check(&t.opt, manifest, algorithm, printer::output(&t.opt)?)?,
+        _ => traverse::traverse(&mut t)?,
+    }
 
     Ok(())
 }
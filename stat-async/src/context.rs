@@ -6,8 +6,10 @@ use std::task::{RawWaker, RawWakerVTable};
 use crate::task::{Task,TaskOutput};
 
 pub struct Scheduler {
+    #[allow(dead_code)] // for the I/O of the tasks. not used yet
     ring: IoUring,
     actives: Vec<Task>,
+    #[allow(dead_code)] // tasks waiting for free submission entries. not used yet
     wait_for_ring_avail: Vec<Task>
 }
 
@@ -32,20 +34,26 @@ impl Scheduler {
     }
 }
 
+#[allow(dead_code)] // the waker of the tasks. not used until the scheduler polls them
 type SchedulerPtr = Rc<Scheduler>;
 
+#[allow(dead_code)]
 unsafe fn schedptr_clone (raw: *const ()) -> RawWaker {
     SchedulerPtr::increment_strong_count(raw as *const Scheduler);
     RawWaker::new(raw, &VTABLE)
 }
-unsafe fn schedptr_wake (raw: *const ()) {
+#[allow(dead_code)]
+unsafe fn schedptr_wake (_raw: *const ()) {
 }
-unsafe fn schedptr_wake_by_ref (raw: *const ()) {
+#[allow(dead_code)]
+unsafe fn schedptr_wake_by_ref (_raw: *const ()) {
 }
+#[allow(dead_code)]
 unsafe fn schedptr_drop (raw: *const ()) {
     SchedulerPtr::decrement_strong_count(raw as *const Scheduler)
 }
 
+#[allow(dead_code)]
 static VTABLE: RawWakerVTable = RawWakerVTable::new(
     schedptr_clone,
     schedptr_wake,
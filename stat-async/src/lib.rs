@@ -2,13 +2,11 @@
 pub mod error;
 pub mod context;
 pub mod task;
-
-use context::Scheduler;
-use task::Task;
+pub mod read;
 
 #[cfg(test)]
 mod tests {
-    use super::*;
+    use super::context::Scheduler;
 
     #[test]
     fn it_works() {
@@ -0,0 +1,166 @@
+//! Sequential reads of whole files through io_uring, with several blocks in flight.
+
+use io_uring::{opcode, types, IoUring};
+use std::collections::VecDeque;
+use std::io;
+use std::os::unix::io::RawFd;
+
+/// a block being read
+struct Slot {
+    buf: Vec<u8>,
+    offset: u64,
+    /// the result of the read, when it is completed
+    result: Option<i32>,
+}
+
+/// reads files of one thread. the buffers are reused
+pub struct Reader {
+    ring: IoUring,
+    slots: Vec<Slot>,
+}
+
+impl Reader {
+    /// `depth` blocks of `block_size` bytes are read ahead
+    pub fn new(depth: u32, block_size: usize) -> io::Result<Reader> {
+        let ring = IoUring::new(depth)?;
+        let slots = (0..depth)
+            .map(|_| Slot {
+                buf: vec![0; block_size],
+                offset: 0,
+                result: None,
+            })
+            .collect();
+        Ok(Reader { ring, slots })
+    }
+
+    fn submit(&mut self, i: usize, fd: RawFd, offset: u64) -> io::Result<()> {
+        let s = &mut self.slots[i];
+        s.offset = offset;
+        s.result = None;
+        let e = opcode::Read::new(types::Fd(fd), s.buf.as_mut_ptr(), s.buf.len() as u32)
+            .offset64(offset as i64)
+            .build()
+            .user_data(i as u64);
+        // the buffer is not touched until the completion is reaped. at most one entry per slot
+        // is queued, so the queue is not full
+        unsafe { self.ring.submission().push(&e) }
+            .map_err(|_| io::Error::other("submission queue is full"))
+    }
+
+    /// wait for a completion, and record the results
+    fn reap(&mut self) -> io::Result<()> {
+        loop {
+            match self.ring.submit_and_wait(1) {
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                r => {
+                    r?;
+                    break;
+                }
+            }
+        }
+        for c in self.ring.completion() {
+            self.slots[c.user_data() as usize].result = Some(c.result());
+        }
+        Ok(())
+    }
+
+    /// wait for the reads of `inflight`, so that their buffers are not written any more
+    fn drain(&mut self, inflight: &mut VecDeque<usize>) -> io::Result<()> {
+        while inflight.iter().any(|i| self.slots[*i].result.is_none()) {
+            self.reap()?;
+        }
+        inflight.clear();
+        Ok(())
+    }
+
+    /// read `fd` from the start to the end, calling `f` with the blocks in order
+    pub fn read_all(&mut self, fd: RawFd, mut f: impl FnMut(&[u8])) -> io::Result<()> {
+        let mut inflight = VecDeque::new();
+        let r = self.read_from(fd, 0, &mut inflight, &mut f);
+        // also after an error, since the kernel may still write the buffers
+        let d = self.drain(&mut inflight);
+        r.and(d)
+    }
+
+    fn read_from(
+        &mut self,
+        fd: RawFd,
+        mut offset: u64,
+        inflight: &mut VecDeque<usize>,
+        f: &mut impl FnMut(&[u8]),
+    ) -> io::Result<()> {
+        let block_size = self.slots[0].buf.len() as u64;
+        loop {
+            // `inflight` is in the order of the offsets
+            for i in 0..self.slots.len() {
+                self.submit(i, fd, offset + i as u64 * block_size)?;
+                inflight.push_back(i);
+            }
+            let mut next = offset + self.slots.len() as u64 * block_size;
+            loop {
+                let i = inflight[0];
+                let r = match self.slots[i].result {
+                    Some(r) => r,
+                    None => {
+                        self.reap()?;
+                        continue;
+                    }
+                };
+                if r < 0 {
+                    let e = io::Error::from_raw_os_error(-r);
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock
+                    ) {
+                        let offset = self.slots[i].offset;
+                        self.submit(i, fd, offset)?;
+                        continue;
+                    }
+                    return Err(e);
+                }
+                if r == 0 {
+                    return Ok(());
+                }
+                let n = r as usize;
+                f(&self.slots[i].buf[..n]);
+                inflight.pop_front();
+                if (n as u64) < block_size {
+                    // the end, or a short read. the blocks after it are read again
+                    offset = self.slots[i].offset + n as u64;
+                    self.drain(inflight)?;
+                    break;
+                }
+                self.submit(i, fd, next)?;
+                inflight.push_back(i);
+                next += block_size;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::os::unix::io::AsRawFd;
+
+    #[test]
+    fn read_all() {
+        let path = std::env::temp_dir().join(format!("stat-async-read-{}", std::process::id()));
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+        std::fs::File::create(&path).unwrap().write_all(&data).unwrap();
+        let f = std::fs::File::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let mut r = match Reader::new(4, 4096) {
+            Ok(r) => r,
+            // io_uring is not available
+            Err(_) => return,
+        };
+        for _ in 0..2 {
+            let mut read = Vec::new();
+            r.read_all(f.as_raw_fd(), |b| read.extend_from_slice(b)).unwrap();
+            assert_eq!(read, data);
+        }
+    }
+}
@@ -6,6 +6,7 @@ use crate::error;
 pub type TaskOutput = Result<(),error::E>;
 
 pub struct Task {
+    #[allow(dead_code)] // polled by the scheduler. not used yet
     f: Pin<Box<dyn Future<Output=TaskOutput>>>
 }
 